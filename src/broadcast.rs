@@ -0,0 +1,236 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::registry::{find_instance, load_registry};
+use crate::util::{find_sock_path, print_table, ExitValue};
+use clap::ArgMatches;
+use nix::libc;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{close, dup2, fork, pipe, ForkResult, Pid};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::{io, process};
+
+/// A server a command is sent to.
+pub struct Target {
+    /// The instance name, or the socket file when the server was given by its socket
+    pub name: String,
+    pub sock_file: PathBuf,
+}
+
+/// Finds the servers a command is sent to: every socket given with -s, every instance given with
+/// -n and every instance with a tag given with --tag. Without any of them, this is the single
+/// server every other command would find.
+pub fn find_targets(sub_m: &ArgMatches) -> Result<Vec<Target>, ExitValue> {
+    let mut targets = Vec::<Target>::new();
+
+    if let Some(socks) = sub_m.values_of("SOCK") {
+        for sock in socks {
+            targets.push(Target {
+                name: sock.to_string(),
+                sock_file: PathBuf::from(sock),
+            });
+        }
+    }
+
+    if let Some(names) = sub_m.values_of("INSTANCE") {
+        for name in names {
+            let instance = find_instance(name)?;
+            targets.push(Target {
+                sock_file: instance.sock_file(),
+                name: instance.name,
+            });
+        }
+    }
+
+    if let Some(tags) = sub_m.values_of("TAG") {
+        let registry = load_registry()?;
+        for tag in tags {
+            let tagged: Vec<_> = registry
+                .values()
+                .filter(|i| i.tags.iter().any(|t| t == tag))
+                .collect();
+            if tagged.is_empty() {
+                eprintln!("No registered instance is tagged {}", tag);
+                return Err(ExitValue::Code(1));
+            }
+            for instance in tagged {
+                targets.push(Target {
+                    name: instance.name.clone(),
+                    sock_file: instance.sock_file(),
+                });
+            }
+        }
+    }
+
+    // The same server may be given more than once, such as through two of its tags
+    let mut seen = Vec::new();
+    targets.retain(|t| {
+        if seen.contains(&t.sock_file) {
+            return false;
+        }
+        seen.push(t.sock_file.clone());
+        return true;
+    });
+
+    if targets.is_empty() {
+        let sock_file = find_sock_path(sub_m)?;
+        targets.push(Target {
+            name: sock_file.to_string_lossy().into_owned(),
+            sock_file,
+        });
+    }
+
+    if targets.len() > 1 && sub_m.is_present("TAIL") {
+        eprintln!("--tail can only be used with a single server");
+        return Err(ExitValue::Code(1));
+    }
+
+    return Ok(targets);
+}
+
+/// Runs `action` for every target, all at once unless `serial` is set, and prints which targets
+/// it succeeded for. Each target is handled in a process of its own, so what the action prints for
+/// a target ends up in that target's row instead of being mixed with the output for the others.
+/// `done` completes the summary line, like "stopped" in "2 of 3 servers stopped". Fails when the
+/// action failed for any of the targets.
+pub fn broadcast<F>(
+    targets: Vec<Target>,
+    serial: bool,
+    done: &str,
+    action: F,
+) -> Result<(), ExitValue>
+where
+    F: Fn(&Target) -> Result<(), ExitValue>,
+{
+    let results: Vec<(String, Outcome)> = if serial {
+        targets
+            .into_iter()
+            .map(|target| {
+                let outcome = spawn_action(&target, &action).finish();
+                (target.name, outcome)
+            })
+            .collect()
+    } else {
+        // Every process is forked before any output is read, so they all run at the same time
+        let running: Vec<_> = targets
+            .into_iter()
+            .map(|target| {
+                let action = spawn_action(&target, &action);
+                (target.name, action)
+            })
+            .collect();
+        running
+            .into_iter()
+            .map(|(name, action)| (name, action.finish()))
+            .collect()
+    };
+
+    let rows: Vec<Vec<&str>> = results
+        .iter()
+        .map(|(name, outcome)| {
+            let result = if outcome.ok { "ok" } else { "failed" };
+            vec![name.as_str(), result, outcome.output.as_str()]
+        })
+        .collect();
+    let succeeded = results.iter().filter(|(_, outcome)| outcome.ok).count();
+
+    println!();
+    print_table(&["SERVER", "RESULT", "OUTPUT"], &rows);
+    println!("{} of {} servers {}", succeeded, results.len(), done);
+
+    if succeeded < results.len() {
+        return Err(ExitValue::Code(1));
+    }
+    return Ok(());
+}
+
+/// Whether the action succeeded for a target, and what it printed, on a single line.
+struct Outcome {
+    ok: bool,
+    output: String,
+}
+
+/// The action for a single target, running in a forked process.
+enum RunningAction {
+    Forked { pid: Pid, output: File },
+    Failed(String),
+}
+
+impl RunningAction {
+    /// Waits for the action to finish, collecting everything it printed.
+    fn finish(self) -> Outcome {
+        let (pid, mut output) = match self {
+            RunningAction::Forked { pid, output } => (pid, output),
+            RunningAction::Failed(output) => return Outcome { ok: false, output },
+        };
+
+        let mut text = String::new();
+        let _ = output.read_to_string(&mut text);
+        let ok = match waitpid(pid, None) {
+            Ok(WaitStatus::Exited(_, code)) => code == 0,
+            _ => false,
+        };
+
+        let lines: Vec<&str> = text
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty())
+            .collect();
+        return Outcome {
+            ok,
+            output: lines.join(" "),
+        };
+    }
+}
+
+/// Forks a process which runs `action` for `target` with its stdout and stderr going to a pipe.
+fn spawn_action<F>(target: &Target, action: &F) -> RunningAction
+where
+    F: Fn(&Target) -> Result<(), ExitValue>,
+{
+    let (read, write) = match pipe() {
+        Ok(fds) => fds,
+        Err(e) => return RunningAction::Failed(format!("Failed to create a pipe: {}", e)),
+    };
+
+    match fork() {
+        Ok(ForkResult::Parent { child }) => {
+            let _ = close(write);
+            let output = unsafe { File::from_raw_fd(read) };
+            return RunningAction::Forked { pid: child, output };
+        }
+        Ok(ForkResult::Child) => {}
+        Err(e) => {
+            let _ = close(read);
+            let _ = close(write);
+            return RunningAction::Failed(format!("Fork failed: {}", e));
+        }
+    }
+
+    let _ = close(read);
+    let _ = dup2(write, libc::STDOUT_FILENO);
+    let _ = dup2(write, libc::STDERR_FILENO);
+    let _ = close(write);
+
+    let code = match action(target) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    };
+    let _ = io::stdout().flush();
+    process::exit(code);
+}
@@ -0,0 +1,348 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::identity::Identity;
+use crate::util::{ExitError, ExitValue};
+use nix::libc;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The cgroup v2 period cpu.max quotas are given in, in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+/// Limits for the cgroup v2 group paperd creates for a server, inside a subtree of the cgroup
+/// hierarchy which was delegated to paperd.
+#[derive(Clone)]
+pub struct CgroupConfig {
+    /// The delegated group the server's group is created in
+    pub parent: PathBuf,
+    pub memory_max: Option<u64>,
+    pub memory_high: Option<u64>,
+    /// The value for cpu.max, a quota and a period in microseconds
+    pub cpu_max: Option<String>,
+    pub pids_max: Option<u64>,
+}
+
+/// A group the server is placed in when it is started.
+pub struct Cgroup {
+    pub path: PathBuf,
+    procs: File,
+}
+
+impl CgroupConfig {
+    /// Creates the group for the server in the working directory, or reuses the one left behind
+    /// by an earlier run, and applies the limits to it. When the server is run as another user the
+    /// group is handed over to that user.
+    pub fn create(
+        &self,
+        working_dir: &Path,
+        identity: Option<&Identity>,
+    ) -> Result<Cgroup, ExitValue> {
+        if !self.parent.join("cgroup.procs").is_file() {
+            eprintln!(
+                "{} is not a cgroup. It has to be created and delegated to paperd before the \
+                 server can be started in it.",
+                self.parent.to_string_lossy()
+            );
+            return Err(ExitValue::Code(1));
+        }
+
+        self.enable_controllers()?;
+
+        let working_dir =
+            fs::canonicalize(working_dir).conv("Failed to get full path to working directory")?;
+        let path = self.parent.join(group_name(&working_dir));
+        if !path.is_dir() {
+            fs::create_dir(&path).conv(format!(
+                "Failed to create cgroup {}",
+                path.to_string_lossy()
+            ))?;
+        }
+
+        let limits = [
+            ("memory.max", self.memory_max.map(|m| m.to_string())),
+            ("memory.high", self.memory_high.map(|m| m.to_string())),
+            ("cpu.max", self.cpu_max.clone()),
+            ("pids.max", self.pids_max.map(|p| p.to_string())),
+        ];
+        for (file, value) in &limits {
+            if let Some(value) = value {
+                fs::write(path.join(file), value).conv(format!(
+                    "Failed to set {} of cgroup {}",
+                    file,
+                    path.to_string_lossy()
+                ))?;
+            }
+        }
+
+        if let Some(identity) = identity {
+            identity.give(&path)?;
+            identity.give(&path.join("cgroup.procs"))?;
+        }
+
+        let procs = OpenOptions::new()
+            .write(true)
+            .open(path.join("cgroup.procs"))
+            .conv(format!("Failed to open cgroup {}", path.to_string_lossy()))?;
+
+        return Ok(Cgroup { path, procs });
+    }
+
+    /// Controllers have to be enabled in the parent's cgroup.subtree_control before the limit
+    /// files show up in its children.
+    fn enable_controllers(&self) -> Result<(), ExitValue> {
+        let mut needed = Vec::new();
+        if self.memory_max.is_some() || self.memory_high.is_some() {
+            needed.push("memory");
+        }
+        if self.cpu_max.is_some() {
+            needed.push("cpu");
+        }
+        if self.pids_max.is_some() {
+            needed.push("pids");
+        }
+
+        let subtree_control = self.parent.join("cgroup.subtree_control");
+        let enabled = fs::read_to_string(&subtree_control).unwrap_or_default();
+        let missing: Vec<String> = needed
+            .iter()
+            .filter(|c| !enabled.split_whitespace().any(|e| e == **c))
+            .map(|c| format!("+{}", c))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(e) = fs::write(&subtree_control, missing.join(" ")) {
+            eprintln!(
+                "Failed to enable the {} controllers in {}: {}. The cgroup must not contain any \
+                 processes itself, and the controllers have to be enabled in its parents.",
+                needed.join(", "),
+                self.parent.to_string_lossy(),
+                e
+            );
+            return Err(ExitValue::Code(1));
+        }
+        return Ok(());
+    }
+}
+
+impl Cgroup {
+    /// Makes the process started by `command` move itself into this group before executing the
+    /// server, so the JVM never runs outside of it.
+    pub fn apply(&self, command: &mut Command) {
+        let fd = self.procs.as_raw_fd();
+        // Writing 0 to cgroup.procs moves the writing process
+        unsafe {
+            command.pre_exec(move || {
+                if libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                return Ok(());
+            });
+        }
+    }
+
+    /// The number of processes in this group the kernel has killed for going over memory.max.
+    pub fn oom_kills(&self) -> u64 {
+        let events = fs::read_to_string(self.path.join("memory.events")).unwrap_or_default();
+        return events
+            .lines()
+            .find_map(|line| {
+                let mut parts = line.split_whitespace();
+                match (parts.next(), parts.next()) {
+                    (Some("oom_kill"), Some(count)) => count.parse::<u64>().ok(),
+                    _ => None,
+                }
+            })
+            .unwrap_or(0);
+    }
+
+    /// Removes the group once the server has exited. This only works when there's nothing left
+    /// running in it, otherwise it is kept and reused by the next run.
+    pub fn remove(self) {
+        drop(self.procs);
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+/// Names the group after the working directory of the server, escaped like systemd does for
+/// paths, so every server gets its own group. Slashes become dashes and any other byte which isn't
+/// allowed becomes `\xNN`, so no two working directories share a group.
+fn group_name(working_dir: &Path) -> String {
+    let path = working_dir.as_os_str().as_bytes();
+    let mut start = 0;
+    let mut end = path.len();
+    while start < end && path[start] == b'/' {
+        start += 1;
+    }
+    while end > start && path[end - 1] == b'/' {
+        end -= 1;
+    }
+
+    let mut escaped = String::new();
+    for (i, b) in path[start..end].iter().enumerate() {
+        match *b {
+            b'/' => escaped.push('-'),
+            // A leading dot would make it a hidden file
+            b'.' if i == 0 => escaped.push_str("\\x2e"),
+            b if b.is_ascii_alphanumeric() || b == b'_' || b == b'.' || b == b':' => {
+                escaped.push(b as char)
+            }
+            b => escaped.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    if escaped.is_empty() {
+        escaped.push('-');
+    }
+    return format!("paperd-{}", escaped);
+}
+
+/// Parses a CPU limit given as a percentage of one CPU, like 150%, or in the format of cpu.max
+/// itself, a quota and a period in microseconds like `150000 100000`, or `max`.
+pub fn parse_cpu_max(text: &str) -> Option<String> {
+    let text = text.trim();
+    if text.ends_with('%') {
+        let percent = text[..text.len() - 1]
+            .parse::<u64>()
+            .ok()
+            .filter(|p| *p > 0)?;
+        return Some(format!("{} {}", percent * CPU_PERIOD / 100, CPU_PERIOD));
+    }
+
+    let mut parts = text.split_whitespace();
+    let quota = parts.next()?;
+    if quota != "max" {
+        quota.parse::<u64>().ok()?;
+    }
+    return match (parts.next(), parts.next()) {
+        (None, _) => Some(quota.to_string()),
+        (Some(period), None) => {
+            period.parse::<u64>().ok()?;
+            Some(format!("{} {}", quota, period))
+        }
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn escapes_group_names_losslessly() {
+        assert_eq!(
+            group_name(Path::new("/srv/mc/lobby")),
+            "paperd-srv-mc-lobby"
+        );
+        assert_eq!(group_name(Path::new("/srv/a-b")), "paperd-srv-a\\x2db");
+        assert_eq!(group_name(Path::new("/srv/a b")), "paperd-srv-a\\x20b");
+        assert_eq!(group_name(Path::new("/srv/a_b")), "paperd-srv-a_b");
+        assert_ne!(
+            group_name(Path::new("/srv/a/b")),
+            group_name(Path::new("/srv/a-b"))
+        );
+        assert_eq!(group_name(Path::new("/.mc/")), "paperd-\\x2emc");
+        assert_eq!(
+            group_name(Path::new("/srv/\u{e9}")),
+            "paperd-srv-\\xc3\\xa9"
+        );
+        assert_eq!(group_name(Path::new("/")), "paperd--");
+    }
+
+    #[test]
+    fn parses_cpu_max() {
+        assert_eq!(parse_cpu_max("150%"), Some("150000 100000".to_string()));
+        assert_eq!(parse_cpu_max("max"), Some("max".to_string()));
+        assert_eq!(
+            parse_cpu_max(" 50000 100000 "),
+            Some("50000 100000".to_string())
+        );
+        assert_eq!(parse_cpu_max("max 100000"), Some("max 100000".to_string()));
+        assert_eq!(parse_cpu_max("0%"), None);
+        assert_eq!(parse_cpu_max("fast"), None);
+        assert_eq!(parse_cpu_max("1 2 3"), None);
+    }
+
+    #[test]
+    fn creates_group_in_fake_cgroupfs() {
+        let root = env::temp_dir().join(format!("paperd-cgroup-test-{}", process::id()));
+        let parent = root.join("parent");
+        let working_dir = root.join("server dir");
+        fs::create_dir_all(&parent).unwrap();
+        fs::create_dir_all(&working_dir).unwrap();
+        fs::write(parent.join("cgroup.procs"), "").unwrap();
+        fs::write(parent.join("cgroup.subtree_control"), "cpu").unwrap();
+
+        // The kernel fills a new group with its files, which a plain directory doesn't do
+        let group = parent.join(group_name(&fs::canonicalize(&working_dir).unwrap()));
+        fs::create_dir(&group).unwrap();
+        fs::write(group.join("cgroup.procs"), "").unwrap();
+        fs::write(
+            group.join("memory.events"),
+            "low 0\nhigh 2\nmax 5\noom 1\noom_kill 3\n",
+        )
+        .unwrap();
+
+        let config = CgroupConfig {
+            parent: parent.clone(),
+            memory_max: Some(1 << 30),
+            memory_high: None,
+            cpu_max: parse_cpu_max("200%"),
+            pids_max: Some(512),
+        };
+        let cgroup = config.create(&working_dir, None);
+        let read = |name: &str| fs::read_to_string(group.join(name)).unwrap();
+        let result = cgroup.map(|cgroup| {
+            assert_eq!(cgroup.path, group);
+            assert_eq!(cgroup.oom_kills(), 3);
+            assert_eq!(read("memory.max"), "1073741824");
+            assert_eq!(read("cpu.max"), "200000 100000");
+            assert_eq!(read("pids.max"), "512");
+            assert!(!group.join("memory.high").exists());
+            assert_eq!(
+                fs::read_to_string(parent.join("cgroup.subtree_control")).unwrap(),
+                "+memory +pids"
+            );
+        });
+
+        let _ = fs::remove_dir_all(&root);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn refuses_parent_which_is_not_a_cgroup() {
+        let root = env::temp_dir().join(format!("paperd-cgroup-test-none-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let config = CgroupConfig {
+            parent: root.clone(),
+            memory_max: None,
+            memory_high: None,
+            cpu_max: None,
+            pids_max: None,
+        };
+        let result = config.create(&root, None);
+        let _ = fs::remove_dir_all(&root);
+        assert!(result.is_err());
+    }
+}
@@ -13,26 +13,49 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::presets::PRESET_NAMES;
 use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, Shell, SubCommand};
 use std::io;
 
 pub fn get_cmd_line_matches<'a>() -> ArgMatches<'a> {
     let start_text = run_after_text("start");
     let run_text = run_after_text("run");
-    return handle_cmd_line(start_text.as_str(), run_text.as_str()).get_matches();
+    let service_text = run_after_text("install-service");
+    let show_text = run_after_text("config show");
+    let validate_text = run_after_text("config validate");
+    return handle_cmd_line(
+        start_text.as_str(),
+        run_text.as_str(),
+        service_text.as_str(),
+        show_text.as_str(),
+        validate_text.as_str(),
+    )
+    .get_matches();
 }
 
 pub fn gen_completions(shell: &str) {
     let start_text = run_after_text("start");
     let run_text = run_after_text("run");
-    handle_cmd_line(start_text.as_str(), run_text.as_str()).gen_completions_to(
-        "paperd",
-        shell.parse::<Shell>().unwrap(),
-        &mut io::stdout(),
-    );
+    let service_text = run_after_text("install-service");
+    let show_text = run_after_text("config show");
+    let validate_text = run_after_text("config validate");
+    handle_cmd_line(
+        start_text.as_str(),
+        run_text.as_str(),
+        service_text.as_str(),
+        show_text.as_str(),
+        validate_text.as_str(),
+    )
+    .gen_completions_to("paperd", shell.parse::<Shell>().unwrap(), &mut io::stdout());
 }
 
-fn handle_cmd_line<'a, 'b>(start_after: &'b str, run_after: &'b str) -> App<'a, 'b> {
+fn handle_cmd_line<'a, 'b>(
+    start_after: &'b str,
+    run_after: &'b str,
+    service_after: &'b str,
+    show_after: &'b str,
+    validate_after: &'b str,
+) -> App<'a, 'b> {
     let sock_arg = Arg::<'a, 'b>::with_name("SOCK")
         .help(
             "Custom socket file to send commands to a running server. If not set, the \
@@ -42,6 +65,16 @@ fn handle_cmd_line<'a, 'b>(start_after: &'b str, run_after: &'b str) -> App<'a,
         .short("s")
         .long("sock")
         .takes_value(true);
+    let instance_arg = Arg::<'a, 'b>::with_name("INSTANCE")
+        .help(
+            "The name of a registered instance to send commands to, instead of a socket file. See \
+             the list command for how to register instances.",
+        )
+        .short("n")
+        .long("instance")
+        .value_name("NAME")
+        .takes_value(true)
+        .conflicts_with("SOCK");
 
     let license_text = r"ISSUES:
     Please submit any bugs or issues with paperd to the paperd issue tracker:
@@ -65,13 +98,33 @@ LICENSE:
             SubCommand::with_name("status")
                 .about("Get the status of the currently running server.")
                 .arg(&sock_arg)
+                .arg(&instance_arg)
+                .arg(
+                    Arg::with_name("ALL")
+                        .help(
+                            "Show a line with the state, TPS, players, memory, version and uptime \
+                             of every registered instance instead, asking all of them at once.",
+                        )
+                        .short("a")
+                        .long("all")
+                        .conflicts_with_all(&["SOCK", "INSTANCE"]),
+                )
                 .display_order(1)
                 .after_help(license_text),
         )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about(
+                    "List the registered instances with their state, PID, uptime and whether \
+                     their protocol version is compatible with this paperd.",
+                )
+                .after_help(LIST_HELP)
+                .display_order(1),
+        )
         .subcommand(
             SubCommand::with_name("send")
                 .about("Send a command to the running MC server.")
-                .arg(&sock_arg)
+                .targets(&sock_arg)
                 .arg(tail_arg(
                     "Tail the server log after sending the command to the \
                      server, useful for viewing the response. Press C-c to quit.",
@@ -90,11 +143,12 @@ LICENSE:
                 )
                 .display_order(1),
         )
-        .console(&sock_arg)
+        .console(&sock_arg, &instance_arg)
         .subcommand(
             SubCommand::with_name("log")
                 .about("Print recent log messages from the running MC server.")
                 .arg(&sock_arg)
+                .arg(&instance_arg)
                 .arg(
                     Arg::with_name("LINES")
                         .help("The number of log messages to print.")
@@ -106,12 +160,47 @@ LICENSE:
                     "Tail the server log rather than just printing recent \
                      messages. Press C-c.",
                 ))
+                .arg(
+                    Arg::with_name("STDOUT")
+                        .help(
+                            "Read the captured stdout and stderr of the server process from \
+                             paperd-stdout.log instead of the server log. This includes output \
+                             from before the server's logger started, such as JVM errors.",
+                        )
+                        .long("stdout"),
+                )
+                .display_order(1),
+        )
+        .subcommand(
+            SubCommand::with_name("events")
+                .about(
+                    "Print the lifecycle events paperd recorded for the server, such as \
+                     restarts, crashes and signals.",
+                )
+                .arg(&sock_arg)
+                .arg(&instance_arg)
+                .arg(
+                    Arg::with_name("SINCE")
+                        .help(
+                            "Only print events which happened after this point. Either a \
+                             duration like 30m or 2h counting back from now, or a UTC date like \
+                             2020-06-01 or 2020-06-01T03:00:00.",
+                        )
+                        .long("since")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("JSON")
+                        .help("Print the events as JSON lines, as they are stored.")
+                        .long("json"),
+                )
                 .display_order(1),
         )
         .subcommand(
             SubCommand::with_name("timings")
                 .about("If timings is enabled, generate a report and return the URL.")
                 .arg(&sock_arg)
+                .arg(&instance_arg)
                 .display_order(1),
         )
         .subcommand(
@@ -122,6 +211,22 @@ LICENSE:
                      quit (will NOT stop the server).",
                 ))
                 .java_run(start_after)
+                .run_as()
+                .resources()
+                .arg(skip_preflight_arg())
+                .arg(
+                    Arg::with_name("FOREGROUND")
+                        .help(
+                            "Supervise the server without forking into the background, for \
+                             running paperd under a service manager such as systemd. Restarts and \
+                             all other start options still apply. When NOTIFY_SOCKET is set paperd \
+                             reports readiness, status and watchdog pings to systemd, so it can be \
+                             used with Type=notify services.",
+                        )
+                        .short("F")
+                        .long("foreground")
+                        .conflicts_with("TAIL"),
+                )
                 .arg(
                     Arg::with_name("KEEP_ALIVE")
                         .help(
@@ -132,12 +237,139 @@ LICENSE:
                         .short("k")
                         .long("keep-alive"),
                 )
+                .arg(
+                    Arg::with_name("RESTART_DELAY")
+                        .help(
+                            "How long to wait before restarting the server after a crash when \
+                             using --keep-alive. The delay doubles with each further crash inside \
+                             the crash window. The default is 1s.",
+                        )
+                        .long("restart-delay")
+                        .value_name("DURATION")
+                        .takes_value(true)
+                        .requires("KEEP_ALIVE"),
+                )
+                .arg(
+                    Arg::with_name("MAX_RESTART_DELAY")
+                        .help(
+                            "The longest paperd will ever wait before restarting a crashed \
+                             server when using --keep-alive. The default is 5m.",
+                        )
+                        .long("max-restart-delay")
+                        .value_name("DURATION")
+                        .takes_value(true)
+                        .requires("KEEP_ALIVE"),
+                )
+                .arg(
+                    Arg::with_name("MAX_CRASHES")
+                        .help(
+                            "Give up restarting the server when using --keep-alive once it has \
+                             crashed this many times inside the crash window. Set to 0 to always \
+                             restart the server. The default is 5.",
+                        )
+                        .long("max-crashes")
+                        .value_name("COUNT")
+                        .takes_value(true)
+                        .requires("KEEP_ALIVE"),
+                )
+                .arg(
+                    Arg::with_name("CRASH_WINDOW")
+                        .help(
+                            "The window of time in which crashes are counted towards \
+                             --max-crashes and the restart delay. The default is 10m.",
+                        )
+                        .long("crash-window")
+                        .value_name("DURATION")
+                        .takes_value(true)
+                        .requires("KEEP_ALIVE"),
+                )
+                .arg(
+                    Arg::with_name("WATCHDOG_INTERVAL")
+                        .help(
+                            "Ping the server over its socket at this interval when using \
+                             --keep-alive. If the server stops answering, paperd takes a thread \
+                             dump (written to paperd-stdout.log) and restarts it. The watchdog \
+                             stands down once the server is asked to stop or restart, and is \
+                             disabled unless this is set.",
+                        )
+                        .long("watchdog-interval")
+                        .value_name("DURATION")
+                        .takes_value(true)
+                        .requires("KEEP_ALIVE"),
+                )
+                .arg(
+                    Arg::with_name("WATCHDOG_FAILURES")
+                        .help(
+                            "The number of pings in a row the server has to miss before the \
+                             watchdog restarts it. The default is 3.",
+                        )
+                        .long("watchdog-failures")
+                        .value_name("COUNT")
+                        .takes_value(true)
+                        .requires("WATCHDOG_INTERVAL"),
+                )
+                .arg(
+                    Arg::with_name("GRACEFUL_STOP")
+                        .help(
+                            "When paperd receives SIGTERM or SIGINT, ask the server to stop \
+                             through its socket like the stop command does, instead of passing \
+                             the signal on to the JVM. If the server hasn't exited after the stop \
+                             timeout it is sent SIGTERM, and then SIGKILL.",
+                        )
+                        .long("graceful-stop"),
+                )
+                .arg(
+                    Arg::with_name("STOP_TIMEOUT")
+                        .help(
+                            "How long to wait for the server to stop when using --graceful-stop \
+                             before sending it signals. The default is 30s.",
+                        )
+                        .long("stop-timeout")
+                        .value_name("DURATION")
+                        .takes_value(true)
+                        .requires("GRACEFUL_STOP"),
+                )
+                .arg(
+                    Arg::with_name("ROLLBACK_WINDOW")
+                        .help(
+                            "If the server crashes within this long after paperd swapped in a \
+                             jar staged with the upgrade command, the previous jar is put back. \
+                             The default is 5m.",
+                        )
+                        .long("rollback-window")
+                        .value_name("DURATION")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("STDOUT_LOG_SIZE")
+                        .help(
+                            "The size the captured stdout and stderr of the server may reach in \
+                             paperd-stdout.log before the file is rotated, such as 512k or 10M. \
+                             The default is 10M.",
+                        )
+                        .long("stdout-log-size")
+                        .value_name("SIZE")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("STDOUT_LOG_COUNT")
+                        .help(
+                            "The number of rotated paperd-stdout.log files to keep. The default \
+                             is 5.",
+                        )
+                        .long("stdout-log-count")
+                        .value_name("COUNT")
+                        .takes_value(true),
+                )
                 .display_order(2),
         )
         .subcommand(
             SubCommand::with_name("run")
                 .about("Start the MC server in the foreground.")
                 .java_run(run_after)
+                .run_as()
+                .resources()
+                .arg(skip_preflight_arg())
                 .display_order(2),
         )
         .subcommand(
@@ -146,7 +378,7 @@ LICENSE:
                     "Stop the MC server gracefully. This is functionally \
                      equivalent to sending the 'stop' command to the server.",
                 )
-                .arg(&sock_arg)
+                .targets(&sock_arg)
                 .arg(
                     Arg::with_name("FORCE")
                         .help(
@@ -182,13 +414,203 @@ LICENSE:
                      original jar. The paperd instance will not be changed if it has been updated, \
                      however, as it does not restart.",
                 )
-                .arg(&sock_arg)
+                .targets(&sock_arg)
                 .arg(tail_arg(
                     "Tail the server log after asking the server to restart. Press \
                      C-c to quit.",
                 ))
                 .display_order(3),
         )
+        .subcommand(
+            SubCommand::with_name("upgrade")
+                .about(
+                    "Stage a new server jar. paperd swaps it in place of the current jar the next \
+                     time it starts or restarts the server, keeping the current jar as \
+                     paperd-rollback.jar. If the server crashes within the rollback window after \
+                     the swap, paperd automatically puts the previous jar back.",
+                )
+                .arg(&sock_arg)
+                .arg(&instance_arg)
+                .arg(
+                    Arg::with_name("JAR")
+                        .help("The new server jar.")
+                        .long("jar")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("RESTART")
+                        .help("Ask the server to restart right away, so the new jar is used.")
+                        .short("r")
+                        .long("restart"),
+                )
+                .display_order(3),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about(
+                    "Set up a new server directory with the server jar and a config file for \
+                     paperd, ready to be started.",
+                )
+                .arg(
+                    Arg::with_name("DIR")
+                        .help("The directory to set up the server in. It is created if needed.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("JAR")
+                        .help("The server jar, it is copied into the directory as paperclip.jar.")
+                        .long("jar")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("LINK")
+                        .help("Link to the jar instead of copying it.")
+                        .long("link"),
+                )
+                .arg(
+                    Arg::with_name("ACCEPT_EULA")
+                        .help(
+                            "Accept the Minecraft EULA \
+                             (https://account.mojang.com/documents/minecraft_eula) by writing \
+                             eula=true to eula.txt. The start command runs the server in the \
+                             foreground until the EULA is accepted.",
+                        )
+                        .long("accept-eula"),
+                )
+                .arg(
+                    Arg::with_name("PORT")
+                        .help("The port the server listens on, set as server-port in server.properties.")
+                        .long("port")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("MOTD")
+                        .help("The message of the day, set as motd in server.properties.")
+                        .long("motd")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("INSTANCE")
+                        .help(
+                            "Register the server under this name, so other commands can find it \
+                             with -n. See the list command for more about registered instances.",
+                        )
+                        .short("n")
+                        .long("instance")
+                        .value_name("NAME")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("FORCE")
+                        .help(
+                            "Set up the server even if the directory already contains one, \
+                             overwriting its jar and config file.",
+                        )
+                        .long("force"),
+                )
+                .display_order(4),
+        )
+        .subcommand(
+            SubCommand::with_name("install-service")
+                .about(
+                    "Generate a service definition which runs the MC server with paperd, using \
+                     the same arguments as the start command. By default a systemd unit is \
+                     written to /etc/systemd/system.",
+                )
+                .java_run(service_after)
+                .arg(
+                    Arg::with_name("KEEP_ALIVE")
+                        .help("Pass --keep-alive to the start command in the service.")
+                        .short("k")
+                        .long("keep-alive"),
+                )
+                .arg(
+                    Arg::with_name("NAME")
+                        .help(
+                            "The name of the service. The default is the name of the instance \
+                             given with -n, or paper.",
+                        )
+                        .long("name")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("USER")
+                        .help(
+                            "The user to run the server as. The default is the owner of the \
+                             server's working directory.",
+                        )
+                        .short("u")
+                        .long("user")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("INIT")
+                        .help(
+                            "The init system to generate the service for. systemd units and \
+                             OpenRC scripts supervise paperd in the foreground, SysV init scripts \
+                             start it as a daemon.",
+                        )
+                        .long("init")
+                        .takes_value(true)
+                        .possible_values(&["systemd", "openrc", "sysvinit"])
+                        .default_value("systemd"),
+                )
+                .arg(
+                    Arg::with_name("PRINT")
+                        .help("Print the service definition instead of writing it to a file.")
+                        .long("print"),
+                )
+                .arg(
+                    Arg::with_name("FORCE")
+                        .help("Overwrite the service definition if it already exists.")
+                        .long("force"),
+                )
+                .display_order(4),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about(
+                    "Inspect the settings the start command would use, without starting the \
+                     server.",
+                )
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about(
+                            "Print the values of the config file after applying the files it \
+                             extends and the chosen profile, along with the file each value came \
+                             from. With --effective, resolve the java executable, jar, working \
+                             directory, JVM arguments and server arguments exactly like the start \
+                             command does, and print where each of them came from.",
+                        )
+                        .java_run(show_after)
+                        .arg(
+                            Arg::with_name("EFFECTIVE")
+                                .help(
+                                    "Combine the command line, config file, environment and \
+                                     defaults like the start command does.",
+                                )
+                                .long("effective"),
+                        )
+                        .arg(
+                            Arg::with_name("JSON")
+                                .help("Print the result as JSON.")
+                                .long("json"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("validate")
+                        .about(
+                            "Resolve the settings like the start command does and check that the \
+                             JVM, jar and working directory are usable. Exits with a non-zero \
+                             exit code when they are not.",
+                        )
+                        .java_run(validate_after),
+                )
+                .display_order(4),
+        )
         .subcommand(
             SubCommand::with_name("completions")
                 .about("Generate completion scripts for your shell")
@@ -206,7 +628,10 @@ LICENSE:
 
 trait PaperArg<'a, 'b> {
     fn java_run(self, after_text: &'b str) -> Self;
-    fn console(self, arg: &Arg<'a, 'b>) -> Self;
+    fn run_as(self) -> Self;
+    fn resources(self) -> Self;
+    fn targets(self, sock_arg: &Arg<'a, 'b>) -> Self;
+    fn console(self, sock_arg: &Arg<'a, 'b>, instance_arg: &Arg<'a, 'b>) -> Self;
 }
 
 impl<'a, 'b> PaperArg<'a, 'b> for App<'a, 'b> {
@@ -216,9 +641,11 @@ impl<'a, 'b> PaperArg<'a, 'b> for App<'a, 'b> {
                 Arg::with_name("JVM")
                     .help(
                         "The java binary to use to execute the paperclip jar. By default \
-                         paperd will search the PATH. If there is no java binary on the PATH, \
-                         paperd will use the JAVA_HOME environment variable instead. If neither of \
-                         these finds a JVM, this argument must be supplied.",
+                         paperd searches the PATH, then JAVA_HOME. When the Java found there is \
+                         older than the Java version the jar requires, paperd picks the oldest \
+                         installed JDK which can run it instead, from the javaHomes config key, \
+                         JAVA_HOME, the PATH, /usr/lib/jvm and SDKMAN. If none of these finds a \
+                         JVM, this argument must be supplied.",
                     )
                     .long("jvm")
                     .takes_value(true),
@@ -245,14 +672,43 @@ impl<'a, 'b> PaperArg<'a, 'b> for App<'a, 'b> {
                     .help(
                         "Use a default set of recommended JVM arguments (Aikar's flags) \
                          with the specified amount of memory. The format should be something \
-                         like 500m or 10G. It's recommended to provide as much memory as possible \
-                         up to 10G. You may not provide custom arguments if defaults are used.",
+                         like 500m or 10G, any of the units k, m, g and t are accepted. It may also \
+                         be a percentage like 75%, of the memory limit of the container paperd \
+                         runs in, or of the machine's memory otherwise. It's recommended to \
+                         provide as much memory as possible up to 10G. You may not provide custom \
+                         arguments if defaults are used.",
                     )
                     .short("d")
                     .long("default-args")
                     .value_name("MEMORY")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("PRESET")
+                    .help(
+                        "The set of JVM flags to use with --default-args. aikar-g1 is Aikar's G1 \
+                         flags, zgc, generational-zgc and shenandoah use those garbage \
+                         collectors, and minimal only sets the heap size. Flags the JVM doesn't \
+                         support are left out, and presets it can't run at all fall back to a \
+                         supported one. The default is aikar-g1.",
+                    )
+                    .long("preset")
+                    .takes_value(true)
+                    .possible_values(PRESET_NAMES)
+                    .conflicts_with("CUSTOM_ARGS"),
+            )
+            .arg(
+                Arg::with_name("HEAP_HEADROOM")
+                    .help(
+                        "How much memory to leave free for the JVM outside of the heap when \
+                         sizing the heap from a percentage or automatically. Sizes given with \
+                         --default-args which leave less than this free under a container's \
+                         memory limit cause a warning. The default is 10% of the memory limit, \
+                         but at least 256m.",
+                    )
+                    .long("heap-headroom")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("CUSTOM_ARGS")
                     .help(
@@ -283,16 +739,38 @@ impl<'a, 'b> PaperArg<'a, 'b> for App<'a, 'b> {
             .arg(
                 Arg::with_name("CONFIG_FILE")
                     .help(
-                        "Define a JSON configuration file which specifies all other arguments. \
+                        "Define a configuration file which specifies all other arguments. \
                          This allows defining complex or large startup commands permanently for \
                          using them again for each server startup. The JSON configuration file can \
                          define more configuration past what is possible with just command line \
-                         arguments. See documentation for the JSON configuration file below in the \
+                         arguments. See documentation for the configuration file below in the \
                          CONFIG FILE section.",
                     )
                     .long("config-file")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("PROFILE")
+                    .help(
+                        "Apply the named profile from the configuration file on top of its other \
+                         values. See the CONFIG FILE section below.",
+                    )
+                    .long("profile")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("INSTANCE")
+                    .help(
+                        "The name of a registered instance. Its working directory and config file \
+                         are used unless --working-dir or --config-file are given, and the jar \
+                         defaults to paperclip.jar in its working directory. See the list command \
+                         for how to register instances.",
+                    )
+                    .short("n")
+                    .long("instance")
+                    .value_name("NAME")
+                    .takes_value(true),
+            )
             .group(
                 ArgGroup::with_name("JVM_ARGS")
                     .arg("DEFAULT_ARGS")
@@ -301,18 +779,164 @@ impl<'a, 'b> PaperArg<'a, 'b> for App<'a, 'b> {
             .after_help(after_text);
     }
 
+    fn run_as(self) -> Self {
+        return self
+            .arg(
+                Arg::with_name("RUN_USER")
+                    .help(
+                        "The user to run the server as. paperd has to be started as root to \
+                         use this, it switches to the user before starting the server, so the \
+                         server, hooks and files paperd writes belong to that user.",
+                    )
+                    .long("user")
+                    .value_name("USER")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("RUN_GROUP")
+                    .help(
+                        "The group to run the server as. The default is the primary group of \
+                         the user.",
+                    )
+                    .long("group")
+                    .value_name("GROUP")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("RUN_GROUPS")
+                    .help(
+                        "Supplementary groups to run the server with, in addition to the groups \
+                         the user is a member of. May be specified multiple times, or as a comma \
+                         separated list.",
+                    )
+                    .long("groups")
+                    .value_name("GROUPS")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .use_delimiter(true),
+            )
+            .arg(
+                Arg::with_name("ALLOW_ROOT")
+                    .help(
+                        "Allow the server to run as root. paperd refuses to by default, as \
+                         anyone who gains control of the server would control the whole \
+                         machine.",
+                    )
+                    .long("allow-root"),
+            );
+    }
+
+    fn resources(self) -> Self {
+        return self
+            .arg(
+                Arg::with_name("LIMIT_NOFILE")
+                    .help(
+                        "The maximum number of files the server may have open (RLIMIT_NOFILE). \
+                         Raising it above paperd's own hard limit requires starting paperd as \
+                         root.",
+                    )
+                    .long("limit-nofile")
+                    .value_name("COUNT")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("LIMIT_CORE")
+                    .help(
+                        "The largest core dump the server may write (RLIMIT_CORE), such as 0, 2G \
+                         or unlimited.",
+                    )
+                    .long("limit-core")
+                    .value_name("SIZE")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("NICE")
+                    .help(
+                        "The nice level to run the server with, from -20 (most favorable \
+                         scheduling) to 19 (least favorable). Negative levels require starting \
+                         paperd as root.",
+                    )
+                    .long("nice")
+                    .value_name("LEVEL")
+                    .takes_value(true)
+                    .allow_hyphen_values(true),
+            )
+            .arg(
+                Arg::with_name("IO_CLASS")
+                    .help(
+                        "The I/O scheduling class of the server: idle, best-effort or realtime. \
+                         best-effort and realtime may be followed by a priority within the class \
+                         from 0 (highest) to 7, like best-effort:2. realtime requires starting \
+                         paperd as root.",
+                    )
+                    .long("io-class")
+                    .value_name("CLASS")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("CPU_AFFINITY")
+                    .help("The CPUs the server may run on, such as 0-3 or 0,2,4-5.")
+                    .long("cpu-affinity")
+                    .value_name("CPUS")
+                    .takes_value(true),
+            );
+    }
+
+    fn targets(self, sock_arg: &Arg<'a, 'b>) -> Self {
+        // Sockets and instance names may be mixed here, so this can't be the instance arg of the
+        // single-server commands, which conflicts with --sock
+        return self
+            .arg(sock_arg.clone().multiple(true).number_of_values(1))
+            .arg(
+                Arg::with_name("INSTANCE")
+                    .help(
+                        "The name of a registered instance to send the command to, instead of a \
+                         socket file. See the list command for how to register instances.",
+                    )
+                    .short("n")
+                    .long("instance")
+                    .value_name("NAME")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("TAG")
+                    .help(
+                        "Send the command to every registered instance with this tag. -s, -n \
+                         and --tag may all be given several times to send the command to \
+                         several servers at once, followed by a report of which of them it \
+                         succeeded for, along with anything printed for each of them.",
+                    )
+                    .long("tag")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("SERIAL")
+                    .help(
+                        "When sending the command to several servers, send it to one server \
+                         after another instead of to all of them at once.",
+                    )
+                    .long("serial"),
+            );
+    }
+
     #[cfg(feature = "console")]
-    fn console(self, arg: &Arg<'a, 'b>) -> Self {
+    fn console(self, sock_arg: &Arg<'a, 'b>, instance_arg: &Arg<'a, 'b>) -> Self {
         return self.subcommand(
             SubCommand::with_name("console")
                 .about("Attach to the console of the running MC server.")
-                .arg(arg)
+                .arg(sock_arg)
+                .arg(instance_arg)
                 .display_order(1),
         );
     }
 
     #[cfg(not(feature = "console"))]
-    fn console(self, _: &Arg<'a, 'b>) -> Self {
+    fn console(self, _: &Arg<'a, 'b>, _: &Arg<'a, 'b>) -> Self {
         return self;
     }
 }
@@ -321,6 +945,17 @@ fn tail_arg(message: &str) -> Arg {
     return Arg::with_name("TAIL").help(message).short("t").long("tail");
 }
 
+fn skip_preflight_arg<'a, 'b>() -> Arg<'a, 'b> {
+    return Arg::with_name("SKIP_PREFLIGHT")
+        .help(
+            "Start the server without the pre-flight checks. Before starting, paperd checks the \
+             ports in server.properties (server-port, and query.port and rcon.port when enabled) \
+             are free, that there is at least 1G of free disk space in the working directory, and \
+             that the heap fits in the memory which is available.",
+        )
+        .long("skip-preflight");
+}
+
 fn run_after_text(command_text: &str) -> String {
     return format!(
         r#"EXAMPLES:
@@ -335,21 +970,103 @@ fn run_after_text(command_text: &str) -> String {
         $ paperd {cmd} -- -Xmx5G -Xms5G
 
 CONFIG FILE:
-    You may pass options to this command using a JSON configuration file instead of command line
+    You may pass options to this command using a configuration file instead of command line
     arguments using the --config-file argument. When using this argument the config file values
     have lower precedence than the other command line arguments, so any other arguments specified
-    will effectively override any configuration values present in the file. The config file must be
-    a valid JSON file with the following keys. All keys are optional.
+    will effectively override any configuration values present in the file. The config file may be
+    written in TOML (.toml), YAML (.yaml or .yml) or JSON (any other extension), with the following
+    keys. All keys are optional.
+
+    * jvm          | This is equivalent to the --jvm argument.
+    * jarFile      | This is equivalent to the --jar argument.
+    * workingDir   | This is equivalent to the -w or --working-dir argument.
+    * serverArgs   | This is equivalent to the -s or --server-arg argument.
+    * jvmArgs      | This is equivalent to the CUSTOM_ARGS argument.
+    * preset       | This is equivalent to the --preset argument.
+    * heapHeadroom | This is equivalent to the --heap-headroom argument.
+    * javaHomes    | A list of JDKs to choose from when jvm is not set and the java on the PATH
+                     or in JAVA_HOME is too old for the jar. Each entry is the home directory of
+                     a JDK or the path to a java executable.
+    * user         | This is equivalent to the --user argument.
+    * group        | This is equivalent to the --group argument.
+    * groups       | This is equivalent to the --groups argument, as a list.
+    * limitNofile  | This is equivalent to the --limit-nofile argument.
+    * limitCore    | This is equivalent to the --limit-core argument.
+    * nice         | This is equivalent to the --nice argument.
+    * ioClass      | This is equivalent to the --io-class argument.
+    * cpuAffinity  | This is equivalent to the --cpu-affinity argument.
+
+    The following keys only apply to the start command when --keep-alive is used:
+
+    * restartDelay     | This is equivalent to the --restart-delay argument.
+    * maxRestartDelay  | This is equivalent to the --max-restart-delay argument.
+    * maxCrashes       | This is equivalent to the --max-crashes argument.
+    * crashWindow      | This is equivalent to the --crash-window argument.
+    * watchdogInterval | This is equivalent to the --watchdog-interval argument.
+    * watchdogFailures | This is equivalent to the --watchdog-failures argument.
+
+    The following keys only apply to the start command:
+
+    * stdoutLogSize  | This is equivalent to the --stdout-log-size argument.
+    * stdoutLogCount | This is equivalent to the --stdout-log-count argument.
+    * gracefulStop   | This is equivalent to the --graceful-stop argument.
+    * stopTimeout    | This is equivalent to the --stop-timeout argument.
+    * rollbackWindow | This is equivalent to the --rollback-window argument.
+
+    The following keys place the server in a cgroup v2 group of its own. Each server gets a group in
+    the delegated cgroup named after its working directory, which is removed after the server stops:
 
-    * jvm        | This is equivalent to the --jvm argument.
-    * jarFile    | This is equivalent to the --jar argument.
-    * workingDir | This is equivalent to the -w or --working-dir argument.
-    * serverArgs | This is equivalent to the -s or --server-arg argument.
-    * jvmArgs    | This is equivalent to the CUSTOM_ARGS argument.
+    * cgroup     | The path of a cgroup delegated to paperd, such as /sys/fs/cgroup/minecraft. It
+                   must not contain any processes itself.
+    * memoryMax  | memory.max of the group, like 6G. The kernel kills the server when it uses more,
+                   which paperd reports as an out of memory kill. The automatic heap size is kept
+                   below it.
+    * memoryHigh | memory.high of the group, where the kernel starts to reclaim memory.
+    * cpuMax     | cpu.max of the group, as a percentage of one CPU like 150%.
+    * pidsMax    | pids.max of the group, the number of threads the server may have.
 
     The serverArgs and jvmArgs fields are lists of arguments, where each entry in the list is one
     argument to be passed to either the server or the JVM respectively. This includes any whitespace
-    which may appear in the argument. All other fields are JSON strings.
+    which may appear in the argument. javaHomes and groups are lists as well. The maxCrashes,
+    watchdogFailures, stdoutLogCount, limitNofile, nice and pidsMax fields are JSON numbers and
+    gracefulStop is a JSON boolean. All other fields are JSON strings.
+
+    The following keys are lists of shell commands to run at points in the server's lifecycle. Each
+    command is run with `sh -c` in the server's working directory, one after another:
+
+    * preStart   | Run before the server is started, including every restart. The server is not
+                   started if one of these commands fails.
+    * postStart  | Run after the server process has been started.
+    * postStop   | Run every time the server process exits.
+    * preRestart | Run after postStop when the server is about to be restarted.
+
+    Hook commands get the following environment variables: PAPERD_HOOK (the name of the hook),
+    PAPERD_INSTANCE_DIR (the server's working directory), PAPERD_RESTART_COUNT, PAPERD_SUPERVISOR_PID,
+    PAPERD_PID (the server's PID, once it has been started) and PAPERD_EXIT_CODE (the server's last
+    exit code, once it has exited). Shell variables in hook commands are expanded by the shell when
+    the hook runs, not when the config file is read.
+
+    The following keys control the environment the server is started with:
+
+    * env      | A map of environment variables to set for the server.
+    * envFile  | A list of files to read environment variables from, in dotenv format. Each line is
+                 NAME=value, and values may be quoted. Variables in env win over these.
+    * clearEnv | A list of variables to keep from paperd's own environment. When this is set, no
+                 other variables are passed on to the server, so an empty list only passes on the
+                 variables from env and envFile.
+
+    Shell variables such as $HOME or ${{SERVER_NAME}} in all other string values are replaced with
+    their values from paperd's environment.
+
+    The extends key names another config file, or a list of them, to use as a base. Paths are
+    relative to the file which extends them. The values of the extending file are layered on top of
+    the values in its base files, where later files in the list win over earlier ones.
+
+    The profiles key holds named sets of values, which are layered on top of all other values in the
+    file when chosen with --profile. A profile may name other profiles to use as its base with its
+    own extends key.
+
+    Any other key is an error, in every file and every profile, whether or not the profile is used.
 
     Example JSON file:
     {{
@@ -357,11 +1074,42 @@ CONFIG FILE:
         "workingDir": "/minecraft/servers/paper",
         "jvmArgs": ["-Xmx5G", "-Xms5G"],
         "serverArgs": ["--port", "22222"]
-    }}"#,
+    }}
+
+    Example TOML file:
+        extends = "../base.toml"
+        workingDir = "/minecraft/servers/paper"
+
+        [profiles.big]
+        jvmArgs = ["-Xmx10G", "-Xms10G"]"#,
         cmd = command_text
     );
 }
 
+pub static LIST_HELP: &str = r#"REGISTRY:
+    Instances are registered by name in /etc/paperd/instances.toml for the whole system, and in
+    ~/.config/paperd/instances.toml (or $XDG_CONFIG_HOME/paperd/instances.toml) for the current
+    user. Instances in the user's registry replace ones of the same name in the system's. Each
+    instance is a table with the following keys:
+
+    * workingDir | The working directory of the server. Required.
+    * configFile | The config file to start the server with, see --help of the start command.
+    * tags       | A list of tags, to send, stop or restart all instances with a tag at once using
+                   --tag.
+
+    Relative paths are relative to the registry file, and shell variables such as $HOME are
+    replaced with their values. Every command accepts -n with the name of an instance to find its
+    server, instead of looking in the current directory.
+
+    Example:
+        [survival]
+        workingDir = "/minecraft/servers/survival"
+        configFile = "/minecraft/servers/survival/paperd.toml"
+        tags = ["network"]
+
+        [creative]
+        workingDir = "/minecraft/servers/creative""#;
+
 // This excellent description was taken from rustup
 // https://github.com/rust-lang/rustup.rs/blob/256488923d3fb2637b7d706002b3e6d2db917590/src/cli/help.rs#L156
 pub static COMPLETIONS_HELP: &str = r"DISCUSSION:
@@ -0,0 +1,602 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::java::{java_version, required_java_version};
+use crate::protocol::check_jar_protocol;
+use crate::registry::selected_instance;
+use crate::runner::{setup_java_env, JavaEnv, Source};
+use crate::util::{find_program, ExitValue};
+use clap::ArgMatches;
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::fs::canonicalize;
+use std::path::{Path, PathBuf};
+
+const EXTENDS_KEY: &'static str = "extends";
+const PROFILES_KEY: &'static str = "profiles";
+
+/// Hooks are run through a shell, which expands variables itself when the hook runs.
+const UNEXPANDED_KEYS: &[&str] = &["preStart", "postStart", "preRestart", "postStop"];
+
+pub fn config(sub_m: &ArgMatches) -> Result<(), ExitValue> {
+    return match sub_m.subcommand() {
+        ("show", Some(sub_m)) => show(sub_m),
+        ("validate", Some(sub_m)) => validate(sub_m),
+        _ => Ok(()),
+    };
+}
+
+fn show(sub_m: &ArgMatches) -> Result<(), ExitValue> {
+    if sub_m.is_present("EFFECTIVE") {
+        let env = setup_java_env(sub_m)?;
+        if sub_m.is_present("JSON") {
+            print_json(&env_json(&env));
+        } else {
+            print_env(&env);
+        }
+        return Ok(());
+    }
+
+    let instance_config = selected_instance(sub_m)?.and_then(|i| i.config_file);
+    let path = match sub_m.value_of("CONFIG_FILE") {
+        Some(p) => p.to_string(),
+        None => match instance_config {
+            Some(f) => f.to_string_lossy().into_owned(),
+            None => {
+                eprintln!(
+                    "No config file given. Use --effective to show the settings resolved from the \
+                     command line alone."
+                );
+                return Err(ExitValue::Code(1));
+            }
+        },
+    };
+    let loaded = load_config(&path, sub_m.value_of("PROFILE"))?;
+
+    if sub_m.is_present("JSON") {
+        print_json(&Value::Object(loaded.values));
+        return Ok(());
+    }
+    for (key, value) in &loaded.values {
+        let origin = loaded.origins.get(key).map_or("", |o| o.as_str());
+        println!("{} = {}  [{}]", key, value, origin);
+    }
+    return Ok(());
+}
+
+/// Resolves the settings the same way the start command does and checks that the server can
+/// actually be started with them.
+fn validate(sub_m: &ArgMatches) -> Result<(), ExitValue> {
+    let env = setup_java_env(sub_m)?;
+
+    let mut valid = true;
+
+    let java = &env.java_file;
+    let java_found = if java.components().count() > 1 {
+        java.is_file()
+    } else {
+        // A bare name is looked up on the PATH when the server is started
+        find_program(&[("PATH", &java.to_string_lossy())]).is_some()
+    };
+    if !java_found {
+        eprintln!("Could not find JVM executable {}", java.to_string_lossy());
+        valid = false;
+    } else if let Some(required) = required_java_version(&env.jar_file) {
+        match java_version(java) {
+            Some(version) if version < required => {
+                eprintln!(
+                    "{} requires Java {} or newer, but {} is Java {}",
+                    env.jar_file.to_string_lossy(),
+                    required,
+                    java.to_string_lossy(),
+                    version
+                );
+                valid = false;
+            }
+            _ => {}
+        }
+    }
+
+    if !env.working_dir.is_dir() {
+        eprintln!(
+            "Working directory {} does not exist",
+            env.working_dir.to_string_lossy()
+        );
+        valid = false;
+    }
+
+    if check_jar_protocol(&env.jar_file).is_err() {
+        valid = false;
+    }
+
+    if !valid {
+        return Err(ExitValue::Code(1));
+    }
+    println!("Configuration is valid.");
+    return Ok(());
+}
+
+fn print_env(env: &JavaEnv) {
+    let sources = &env.sources;
+    let print = |name: &str, value: &str, source: &Source| {
+        println!("{:<12} {}", name, value);
+        println!("{:<12} from {}", "", sources.describe(source));
+    };
+
+    print("Java:", &env.java_file.to_string_lossy(), &sources.java);
+    print("Jar:", &env.jar_file.to_string_lossy(), &sources.jar);
+    print(
+        "Working dir:",
+        &env.working_dir.to_string_lossy(),
+        &sources.working_dir,
+    );
+    print("JVM args:", &env.args.join(" "), &sources.jvm_args);
+    print(
+        "Server args:",
+        &env.cmd_args.join(" "),
+        &sources.server_args,
+    );
+}
+
+fn env_json(env: &JavaEnv) -> Value {
+    let sources = &env.sources;
+    return json!({
+        "java": {
+            "value": env.java_file,
+            "source": sources.describe(&sources.java),
+        },
+        "jarFile": {
+            "value": env.jar_file,
+            "source": sources.describe(&sources.jar),
+        },
+        "workingDir": {
+            "value": env.working_dir,
+            "source": sources.describe(&sources.working_dir),
+        },
+        "jvmArgs": {
+            "value": env.args,
+            "source": sources.describe(&sources.jvm_args),
+        },
+        "serverArgs": {
+            "value": env.cmd_args,
+            "source": sources.describe(&sources.server_args),
+        },
+    });
+}
+
+fn print_json(value: &Value) {
+    if let Ok(text) = serde_json::to_string_pretty(value) {
+        println!("{}", text);
+    }
+}
+
+pub struct LoadedConfig {
+    pub config: RunnerConfig,
+    /// The final values of all keys, after layering and shell expansion
+    pub values: Map<String, Value>,
+    /// Which file or profile each key's final value came from
+    pub origins: BTreeMap<String, String>,
+}
+
+/// Reads the config file at `path`, layered on top of the files it extends, with the named profile
+/// applied on top of that. The format is picked by the file extension: `.toml`, `.yaml` or `.yml`,
+/// anything else is read as JSON.
+pub fn load_config(path: &str, profile: Option<&str>) -> Result<LoadedConfig, ExitValue> {
+    let (mut values, mut origins) = load_file(Path::new(path), &mut Vec::new())?;
+
+    let profiles = match values.remove(PROFILES_KEY) {
+        Some(Value::Object(profiles)) => profiles,
+        _ => Map::new(),
+    };
+    origins.remove(PROFILES_KEY);
+    // Every profile is checked, not only the one in use, so a typo is caught before it's needed
+    let mut known = true;
+    for (name, profile) in &profiles {
+        if let Value::Object(profile) = profile {
+            known &= check_keys(profile, &format!("profile {}", name), &[EXTENDS_KEY]);
+        }
+    }
+    if !known {
+        return Err(ExitValue::Code(1));
+    }
+
+    if let Some(name) = profile {
+        let profile = resolve_profile(&profiles, name, &mut Vec::new())?;
+        for key in profile.keys() {
+            origins.insert(key.clone(), format!("profile {}", name));
+        }
+        merge(&mut values, profile);
+    }
+
+    for (key, value) in values.iter_mut() {
+        if !UNEXPANDED_KEYS.contains(&key.as_str()) {
+            expand(value)?;
+        }
+    }
+
+    let config = match serde_json::from_value(Value::Object(values.clone())) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Failed to parse config file {}: {}", path, e);
+            return Err(ExitValue::Code(1));
+        }
+    };
+
+    return Ok(LoadedConfig {
+        config,
+        values,
+        origins,
+    });
+}
+
+/// The values set in a config file, and the file each key was last set in.
+type FileValues = (Map<String, Value>, BTreeMap<String, String>);
+
+/// Reads a single config file and the files listed in its `extends` key. Later files in the list
+/// are layered on top of earlier ones, and the extending file on top of all of them. Also returns
+/// which file each key was last set in.
+fn load_file(path: &Path, seen: &mut Vec<PathBuf>) -> Result<FileValues, ExitValue> {
+    let path_text = path.to_string_lossy();
+    if !path.exists() {
+        eprintln!("No file found at {}", path_text);
+        return Err(ExitValue::Code(1));
+    }
+
+    let canonical = canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if seen.contains(&canonical) {
+        eprintln!("Config file {} extends itself", path_text);
+        return Err(ExitValue::Code(1));
+    }
+
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Failed to open config file {}: {}", path_text, e);
+            return Err(ExitValue::Code(1));
+        }
+    };
+
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    let parsed = match extension.as_deref() {
+        Some("toml") => toml::from_str::<Value>(&text).map_err(|e| e.to_string()),
+        Some("yaml") | Some("yml") => {
+            serde_yaml::from_str::<Value>(&text).map_err(|e| e.to_string())
+        }
+        _ => serde_json::from_str::<Value>(&text).map_err(|e| e.to_string()),
+    };
+    let mut config = match parsed {
+        Ok(Value::Object(map)) => map,
+        Ok(_) => {
+            eprintln!(
+                "Failed to parse config file {}: expected a map of keys to values",
+                path_text
+            );
+            return Err(ExitValue::Code(1));
+        }
+        Err(e) => {
+            eprintln!("Failed to parse config file {}: {}", path_text, e);
+            return Err(ExitValue::Code(1));
+        }
+    };
+
+    if !check_keys(&config, &path_text, &[EXTENDS_KEY, PROFILES_KEY]) {
+        return Err(ExitValue::Code(1));
+    }
+
+    let bases = match config.remove(EXTENDS_KEY) {
+        Some(value) => string_list(value, EXTENDS_KEY, &path_text)?,
+        None => Vec::new(),
+    };
+
+    // Files to extend are relative to the file extending them
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    seen.push(canonical);
+    let mut result = Map::new();
+    let mut origins = BTreeMap::new();
+    for base in bases {
+        let base = expand_text(&base)?;
+        let (values, base_origins) = load_file(&dir.join(base), seen)?;
+        merge(&mut result, values);
+        origins.extend(base_origins);
+    }
+    seen.pop();
+
+    for key in config.keys() {
+        origins.insert(key.clone(), path_text.to_string());
+    }
+    merge(&mut result, config);
+    return Ok((result, origins));
+}
+
+/// Finds a profile by name. A profile may itself name another profile to extend, which is then
+/// layered under it.
+fn resolve_profile(
+    profiles: &Map<String, Value>,
+    name: &str,
+    seen: &mut Vec<String>,
+) -> Result<Map<String, Value>, ExitValue> {
+    let mut profile = match profiles.get(name) {
+        Some(Value::Object(p)) => p.clone(),
+        Some(_) => {
+            eprintln!("Profile {} must be a map of keys to values", name);
+            return Err(ExitValue::Code(1));
+        }
+        None => {
+            let names: Vec<&str> = profiles.keys().map(|k| k.as_str()).collect();
+            if names.is_empty() {
+                eprintln!(
+                    "Profile {} not found, the config file has no profiles",
+                    name
+                );
+            } else {
+                eprintln!(
+                    "Profile {} not found, available profiles: {}",
+                    name,
+                    names.join(", ")
+                );
+            }
+            return Err(ExitValue::Code(1));
+        }
+    };
+
+    if seen.iter().any(|s| s == name) {
+        eprintln!("Profile {} extends itself", name);
+        return Err(ExitValue::Code(1));
+    }
+
+    let bases = match profile.remove(EXTENDS_KEY) {
+        Some(value) => string_list(value, EXTENDS_KEY, name)?,
+        None => return Ok(profile),
+    };
+
+    seen.push(name.to_string());
+    let mut result = Map::new();
+    for base in bases {
+        merge(&mut result, resolve_profile(profiles, &base, seen)?);
+    }
+    seen.pop();
+
+    merge(&mut result, profile);
+    return Ok(result);
+}
+
+/// Checks that every key in `values` is a setting paperd knows, besides the `extra` keys, printing
+/// the ones which aren't. `source` names the file or profile the keys were read from.
+fn check_keys(values: &Map<String, Value>, source: &str, extra: &[&str]) -> bool {
+    let mut known = true;
+    for key in values.keys() {
+        if extra.contains(&key.as_str()) {
+            continue;
+        }
+        // Every setting is optional, so a key set to null on its own only fails to parse when it
+        // isn't a setting
+        let mut probe = Map::new();
+        probe.insert(key.clone(), Value::Null);
+        if serde_json::from_value::<RunnerConfig>(Value::Object(probe)).is_err() {
+            eprintln!("Unknown key {} in {}", key, source);
+            known = false;
+        }
+    }
+    return known;
+}
+
+fn string_list(value: Value, key: &str, source: &str) -> Result<Vec<String>, ExitValue> {
+    let values = match value {
+        Value::String(s) => return Ok(vec![s]),
+        Value::Array(values) => values,
+        _ => Vec::new(),
+    };
+
+    let mut result = Vec::new();
+    for value in values {
+        match value {
+            Value::String(s) => result.push(s),
+            _ => {
+                eprintln!(
+                    "Invalid {} in {}: expected a string or a list of strings",
+                    key, source
+                );
+                return Err(ExitValue::Code(1));
+            }
+        }
+    }
+    if result.is_empty() {
+        eprintln!(
+            "Invalid {} in {}: expected a string or a list of strings",
+            key, source
+        );
+        return Err(ExitValue::Code(1));
+    }
+    return Ok(result);
+}
+
+/// Layers `over` on top of `base`. Nested maps are merged key by key, any other value in `over`
+/// replaces the one in `base`, including lists.
+fn merge(base: &mut Map<String, Value>, over: Map<String, Value>) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(base_map)), Value::Object(over_map)) => merge(base_map, over_map),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Replaces shell variables in every string inside `value`.
+fn expand(value: &mut Value) -> Result<(), ExitValue> {
+    match value {
+        Value::String(s) => *s = expand_text(s)?,
+        Value::Array(values) => {
+            for v in values {
+                expand(v)?;
+            }
+        }
+        Value::Object(map) => {
+            for (_, v) in map.iter_mut() {
+                expand(v)?;
+            }
+        }
+        _ => {}
+    }
+    return Ok(());
+}
+
+pub fn expand_text(text: &str) -> Result<String, ExitValue> {
+    return match shellexpand::env_with_context(text, shell_context) {
+        Ok(s) => Ok(s.into_owned()),
+        Err(e) => {
+            eprintln!("Failed to expand {} in config file: {}", text, e);
+            Err(ExitValue::Code(1))
+        }
+    };
+}
+
+fn shell_context(s: &str) -> Result<Option<Cow<'static, str>>, env::VarError> {
+    match env::var(s) {
+        Ok(value) => Ok(Some(value.into())),
+        Err(env::VarError::NotPresent) => Ok(Some("".into())),
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunnerConfig {
+    #[serde(rename = "jvm")]
+    pub jvm: Option<String>,
+    #[serde(rename = "javaHomes")]
+    pub java_homes: Option<Vec<String>>,
+    #[serde(rename = "jarFile")]
+    pub jar_file: Option<String>,
+    #[serde(rename = "workingDir")]
+    pub working_dir: Option<String>,
+    #[serde(rename = "jvmArgs")]
+    pub jvm_args: Option<Vec<String>>,
+    #[serde(rename = "serverArgs")]
+    pub server_args: Option<Vec<String>>,
+    #[serde(rename = "restartDelay")]
+    pub restart_delay: Option<String>,
+    #[serde(rename = "maxRestartDelay")]
+    pub max_restart_delay: Option<String>,
+    #[serde(rename = "maxCrashes")]
+    pub max_crashes: Option<u32>,
+    #[serde(rename = "crashWindow")]
+    pub crash_window: Option<String>,
+    #[serde(rename = "stdoutLogSize")]
+    pub stdout_log_size: Option<String>,
+    #[serde(rename = "stdoutLogCount")]
+    pub stdout_log_count: Option<u32>,
+    #[serde(rename = "watchdogInterval")]
+    pub watchdog_interval: Option<String>,
+    #[serde(rename = "watchdogFailures")]
+    pub watchdog_failures: Option<u32>,
+    #[serde(rename = "gracefulStop")]
+    pub graceful_stop: Option<bool>,
+    #[serde(rename = "stopTimeout")]
+    pub stop_timeout: Option<String>,
+    #[serde(rename = "user")]
+    pub user: Option<String>,
+    #[serde(rename = "group")]
+    pub group: Option<String>,
+    #[serde(rename = "groups")]
+    pub groups: Option<Vec<String>>,
+    #[serde(rename = "limitNofile")]
+    pub limit_nofile: Option<u64>,
+    #[serde(rename = "limitCore")]
+    pub limit_core: Option<String>,
+    #[serde(rename = "nice")]
+    pub nice: Option<i32>,
+    #[serde(rename = "ioClass")]
+    pub io_class: Option<String>,
+    #[serde(rename = "cpuAffinity")]
+    pub cpu_affinity: Option<String>,
+    #[serde(rename = "cgroup")]
+    pub cgroup: Option<String>,
+    #[serde(rename = "memoryMax")]
+    pub memory_max: Option<String>,
+    #[serde(rename = "memoryHigh")]
+    pub memory_high: Option<String>,
+    #[serde(rename = "cpuMax")]
+    pub cpu_max: Option<String>,
+    #[serde(rename = "pidsMax")]
+    pub pids_max: Option<u64>,
+    #[serde(rename = "rollbackWindow")]
+    pub rollback_window: Option<String>,
+    #[serde(rename = "preset")]
+    pub preset: Option<String>,
+    #[serde(rename = "heapHeadroom")]
+    pub heap_headroom: Option<String>,
+    #[serde(rename = "env")]
+    pub env: Option<BTreeMap<String, String>>,
+    #[serde(rename = "envFile")]
+    pub env_file: Option<Vec<String>>,
+    #[serde(rename = "clearEnv")]
+    pub clear_env: Option<Vec<String>>,
+    #[serde(rename = "preStart")]
+    pub pre_start: Option<Vec<String>>,
+    #[serde(rename = "postStart")]
+    pub post_start: Option<Vec<String>>,
+    #[serde(rename = "preRestart")]
+    pub pre_restart: Option<Vec<String>>,
+    #[serde(rename = "postStop")]
+    pub post_stop: Option<Vec<String>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn write_config(dir: &Path, name: &str, text: &str) -> String {
+        let path = dir.join(name);
+        fs::write(&path, text).unwrap();
+        return path.to_string_lossy().into_owned();
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let dir = env::temp_dir().join(format!("paperd-config-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let valid = write_config(
+            &dir,
+            "valid.json",
+            r#"{"jvmArgs": ["-Xmx1G"], "profiles": {"dev": {"preStart": ["true"]}}}"#,
+        );
+        assert!(load_config(&valid, Some("dev")).is_ok());
+
+        let typo = write_config(&dir, "typo.json", r#"{"jvmArg": ["-Xmx1G"]}"#);
+        assert!(load_config(&typo, None).is_err());
+
+        // A typo in a file being extended, or in a profile, counts even when it isn't used
+        let extends = write_config(&dir, "extends.json", r#"{"extends": "typo.json"}"#);
+        assert!(load_config(&extends, None).is_err());
+        let profile = write_config(
+            &dir,
+            "profile.json",
+            r#"{"profiles": {"dev": {"prestart": ["true"]}, "prod": {"nice": 5}}}"#,
+        );
+        assert!(load_config(&profile, None).is_err());
+        assert!(load_config(&profile, Some("prod")).is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -16,13 +16,12 @@
 pub mod ansi;
 
 use crate::console::ansi::{AnsiCode, MessageElement, StyledMessage};
-use crate::protocol::check_protocol;
+use crate::pid::{read_pid_file, PidStatus};
+use crate::protocol::{check_protocol, CAPABILITY_CONSOLE, CAPABILITY_STATUS};
 use crate::send::send_command;
 use crate::status::{StatusMessage, StatusMessageResponse};
 use crate::util;
-use crate::util::{
-    get_pid, get_sock, get_sock_from_file, get_sock_from_file_direct, ExitError, ExitValue,
-};
+use crate::util::{get_pid, get_sock, get_sock_from_file, get_sock_from_file_direct, ExitValue};
 use clap::ArgMatches;
 use crossbeam_channel::Sender;
 use ncurses::{
@@ -46,7 +45,7 @@ use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread::sleep;
 use std::time::Duration;
 use std::vec::Vec;
-use std::{fs, process, str, thread};
+use std::{process, str, thread};
 
 macro_rules! ctrl {
     ($letter:expr) => {
@@ -84,7 +83,7 @@ const COLOR_BRIGHT_WHITE: i16 = 15;
 #[cfg(feature = "console")]
 pub fn console(sub_m: &ArgMatches) -> Result<(), ExitValue> {
     let (sock, sock_file) = get_sock(sub_m)?;
-    check_protocol(&sock)?;
+    check_protocol(&sock, &[CAPABILITY_CONSOLE, CAPABILITY_STATUS])?;
 
     let (pid_file, pid) = get_pid(&sock_file)?;
 
@@ -184,9 +183,13 @@ impl<'a> Term<'a> {
     }
 
     fn start_stop_listener_thread(&self, stop: Arc<AtomicBool>) -> Result<(), ExitValue> {
-        let pid_text = fs::read_to_string(&self.pid_file).conv("Failed to read PID file")?;
-        let pid_int = pid_text.parse::<i32>().conv("Failed to parse PID file")?;
-        let pid = Pid::from_raw(pid_int);
+        let pid = match read_pid_file(self.pid_file)? {
+            PidStatus::Running(pid) => pid,
+            _ => {
+                eprintln!("Server is no longer running");
+                return Err(ExitValue::Code(1));
+            }
+        };
         thread::spawn(move || {
             while !stop.load(Ordering::SeqCst) {
                 if let Err(_) = kill(pid, None) {
@@ -14,10 +14,9 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use crate::util::ExitValue;
+use nix::fcntl::{open, OFlag};
 use nix::sys::stat::{umask, Mode};
-use nix::unistd::{close, fork, setsid, ForkResult};
-use std::io::{stderr, stdin, stdout};
-use std::os::unix::io::AsRawFd;
+use nix::unistd::{close, dup2, fork, setsid, ForkResult};
 
 pub enum Status {
     CONTINUE,
@@ -55,14 +54,23 @@ pub fn run_daemon() -> Result<Status, ExitValue> {
 
     umask(Mode::from_bits(0o022).unwrap());
 
-    // Close stdin, stdout, stderr; we won't be using them from here on
-    close_fd(stdin());
-    close_fd(stdout());
-    close_fd(stderr());
+    // Point stdin, stdout and stderr at /dev/null; we won't be using the terminal from here on.
+    // They are not simply closed, as the next files we open would then take their place and
+    // anything written to stdout or stderr would end up in them.
+    match open("/dev/null", OFlag::O_RDWR, Mode::empty()) {
+        Ok(null) => {
+            for fd in 0..=2 {
+                let _ = dup2(null, fd);
+            }
+            if null > 2 {
+                let _ = close(null);
+            }
+        }
+        Err(_) => {
+            eprintln!("Failed to open /dev/null");
+            return Err(ExitValue::Code(1));
+        }
+    }
 
     return Ok(Status::CONTINUE);
 }
-
-fn close_fd<T: AsRawFd>(fd: T) {
-    let _ = close(fd.as_raw_fd());
-}
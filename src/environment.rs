@@ -0,0 +1,226 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::expand_text;
+use crate::util::{ExitError, ExitValue};
+use std::env;
+use std::fs;
+use std::process::Command;
+
+/// The environment the server is started with.
+#[derive(Clone, Default)]
+pub struct ChildEnv {
+    /// When set, only these variables are passed on from paperd's own environment
+    pub keep: Option<Vec<String>>,
+    /// Variables to set, later entries win over earlier ones
+    pub vars: Vec<(String, String)>,
+}
+
+impl ChildEnv {
+    pub fn apply(&self, command: &mut Command) {
+        if let Some(keep) = &self.keep {
+            command.env_clear();
+            for name in keep {
+                if let Some(value) = env::var_os(name) {
+                    command.env(name, value);
+                }
+            }
+        }
+        for (name, value) in &self.vars {
+            command.env(name, value);
+        }
+    }
+}
+
+/// Reads variables from a file in dotenv format: `NAME=value` lines, optionally prefixed with
+/// `export`. Values in double quotes may contain escapes like `\n`, values in single quotes are
+/// taken literally. Shell variables are expanded in all values except single quoted ones, `\$`
+/// in double quotes is a literal `$`.
+pub fn read_env_file(path: &str) -> Result<Vec<(String, String)>, ExitValue> {
+    let text = fs::read_to_string(path).conv(format!("Failed to read env file {}", path))?;
+
+    let mut vars = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = if line.starts_with("export ") {
+            line["export ".len()..].trim_start()
+        } else {
+            line
+        };
+
+        let parsed = line
+            .find('=')
+            .map(|i| (line[..i].trim(), line[i + 1..].trim()))
+            .filter(|(name, _)| is_valid_name(name))
+            .and_then(|(name, value)| parse_value(value).map(|v| (name, v)));
+        let (name, (value, literal)) = match parsed {
+            Some(p) => p,
+            None => {
+                eprintln!("Invalid line {} in env file {}", index + 1, path);
+                return Err(ExitValue::Code(1));
+            }
+        };
+
+        let value = if literal { value } else { expand_text(&value)? };
+        vars.push((name.to_string(), value));
+    }
+
+    return Ok(vars);
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    return match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    };
+}
+
+/// Returns the value and whether it must be taken literally.
+fn parse_value(value: &str) -> Option<(String, bool)> {
+    if value.starts_with('\'') {
+        let end = value[1..].find('\'')? + 1;
+        return only_comment(&value[end + 1..]).map(|_| (value[1..end].to_string(), true));
+    }
+
+    if value.starts_with('"') {
+        let mut result = String::new();
+        let mut chars = value[1..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return only_comment(&value[i + 2..]).map(|_| (result, false)),
+                '\\' => match chars.next()?.1 {
+                    'n' => result.push('\n'),
+                    't' => result.push('\t'),
+                    'r' => result.push('\r'),
+                    // Still has to be taken literally when variables are expanded afterwards
+                    '$' => result.push_str("$$"),
+                    c => result.push(c),
+                },
+                c => result.push(c),
+            }
+        }
+        // No closing quote
+        return None;
+    }
+
+    // Unquoted values end at a comment
+    let value = match value.find(" #") {
+        Some(i) => value[..i].trim_end(),
+        None => value,
+    };
+    return Some((value.to_string(), false));
+}
+
+fn only_comment(rest: &str) -> Option<()> {
+    let rest = rest.trim_start();
+    return if rest.is_empty() || rest.starts_with('#') {
+        Some(())
+    } else {
+        None
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn value(text: &str) -> Option<String> {
+        return parse_value(text).map(|(value, _)| value);
+    }
+
+    #[test]
+    fn parses_unquoted_values() {
+        assert_eq!(parse_value("value"), Some(("value".to_string(), false)));
+        assert_eq!(value("a b # comment"), Some("a b".to_string()));
+        assert_eq!(value("a#b"), Some("a#b".to_string()));
+        assert_eq!(value(""), Some("".to_string()));
+    }
+
+    #[test]
+    fn parses_double_quoted_values() {
+        assert_eq!(value(r#""a b""#), Some("a b".to_string()));
+        assert_eq!(value(r#""a # b" # comment"#), Some("a # b".to_string()));
+        assert_eq!(
+            value(r#""line\nnext\ttab""#),
+            Some("line\nnext\ttab".to_string())
+        );
+        assert_eq!(
+            value(r#""say \"hi\" \\ bye""#),
+            Some(r#"say "hi" \ bye"#.to_string())
+        );
+        assert_eq!(value(r#""s3cr\$HOME""#), Some("s3cr$$HOME".to_string()));
+    }
+
+    #[test]
+    fn takes_single_quoted_values_literally() {
+        assert_eq!(
+            parse_value(r#"'$HOME \n "x"' # comment"#),
+            Some((r#"$HOME \n "x""#.to_string(), true))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert_eq!(value(r#""unterminated"#), None);
+        assert_eq!(value("'unterminated"), None);
+        assert_eq!(value(r#""trailing\"#), None);
+        assert_eq!(value(r#""a" b"#), None);
+        assert_eq!(value("'a' b"), None);
+    }
+
+    #[test]
+    fn reads_env_files() {
+        let path = env::temp_dir().join(format!("paperd-env-test-{}", process::id()));
+        fs::write(
+            &path,
+            "# comment\n\
+             \n\
+             export PLAIN=plain value # comment\n\
+             QUOTED=\"s3cr\\$HOME\"\n\
+             LITERAL='$HOME'\n\
+             EXPANDED=\"$PAPERD_ENV_TEST_UNSET/x\"\n",
+        )
+        .unwrap();
+        let vars = read_env_file(&path.to_string_lossy());
+        let _ = fs::remove_file(&path);
+
+        let vars = vars.unwrap_or_else(|_| panic!("failed to read env file"));
+        assert_eq!(
+            vars,
+            vec![
+                ("PLAIN".to_string(), "plain value".to_string()),
+                ("QUOTED".to_string(), "s3cr$HOME".to_string()),
+                ("LITERAL".to_string(), "$HOME".to_string()),
+                ("EXPANDED".to_string(), "/x".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        let path = env::temp_dir().join(format!("paperd-env-invalid-test-{}", process::id()));
+        fs::write(&path, "OK=1\n1BAD=2\n").unwrap();
+        let result = read_env_file(&path.to_string_lossy());
+        let _ = fs::remove_file(&path);
+        assert!(result.is_err());
+    }
+}
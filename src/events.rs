@@ -0,0 +1,359 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::util::{find_sock_path, parse_duration, ExitError, ExitValue};
+use clap::ArgMatches;
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, process};
+
+pub const EVENTS_FILE_NAME: &'static str = "paperd-events.jsonl";
+
+pub fn events(sub_m: &ArgMatches) -> Result<(), ExitValue> {
+    let events_file = match find_sock_path(sub_m)?
+        .parent()
+        .map(|p| p.join(EVENTS_FILE_NAME))
+    {
+        Some(f) => f,
+        None => {
+            eprintln!("Failed to find event journal {}", EVENTS_FILE_NAME);
+            return Err(ExitValue::Code(1));
+        }
+    };
+    if !events_file.is_file() {
+        eprintln!(
+            "No event journal found at {}",
+            events_file.to_string_lossy()
+        );
+        return Err(ExitValue::Code(1));
+    }
+
+    let since = match sub_m.value_of("SINCE") {
+        Some(text) => match parse_since(text) {
+            Some(t) => t,
+            None => {
+                eprintln!(
+                    "Invalid value for --since: {}. Should be a duration like 30m or 2h, or a \
+                     UTC date like 2020-06-01 or 2020-06-01T03:00:00.",
+                    text
+                );
+                return Err(ExitValue::Code(1));
+            }
+        },
+        None => 0,
+    };
+    let json = sub_m.is_present("JSON");
+
+    let file = fs::File::open(&events_file).conv("Failed to open event journal")?;
+    for line in BufReader::new(file).lines() {
+        let line = line.conv("Failed to read event journal")?;
+        // Skip anything we can't make sense of, such as a line cut short by a crash
+        let record = match serde_json::from_str::<Value>(&line) {
+            Ok(Value::Object(map)) => map,
+            _ => continue,
+        };
+
+        let timestamp = record
+            .get("timestamp")
+            .and_then(|t| t.as_u64())
+            .unwrap_or(0);
+        if timestamp < since {
+            continue;
+        }
+
+        if json {
+            println!("{}", line);
+            continue;
+        }
+
+        let event = record.get("event").and_then(|e| e.as_str()).unwrap_or("?");
+        let mut out = format!("{}  {:<10}", format_timestamp(timestamp), event);
+        for (key, value) in &record {
+            if key == "timestamp" || key == "event" {
+                continue;
+            }
+            match value {
+                Value::String(s) => out.push_str(&format!(" {}={}", key, s)),
+                Value::Array(values) => {
+                    let values: Vec<String> = values
+                        .iter()
+                        .map(|v| match v {
+                            Value::String(s) => s.clone(),
+                            v => v.to_string(),
+                        })
+                        .collect();
+                    out.push_str(&format!(" {}=[{}]", key, values.join(" ")));
+                }
+                Value::Null => {}
+                v => out.push_str(&format!(" {}={}", key, v)),
+            }
+        }
+        println!("{}", out);
+    }
+
+    return Ok(());
+}
+
+/// Lifecycle events recorded by the supervisor.
+#[derive(Serialize)]
+#[serde(tag = "event")]
+pub enum Event<'a> {
+    #[serde(rename = "spawn")]
+    Spawn {
+        #[serde(rename = "pid")]
+        pid: u32,
+        #[serde(rename = "argv")]
+        argv: Vec<String>,
+    },
+    #[serde(rename = "exit")]
+    Exit {
+        #[serde(rename = "pid")]
+        pid: u32,
+        #[serde(rename = "code")]
+        code: Option<i32>,
+        #[serde(rename = "signal")]
+        signal: Option<i32>,
+    },
+    #[serde(rename = "oomKill")]
+    OomKill {
+        #[serde(rename = "pid")]
+        pid: u32,
+        #[serde(rename = "cgroup")]
+        cgroup: &'a str,
+    },
+    #[serde(rename = "restart")]
+    Restart {
+        #[serde(rename = "reason")]
+        reason: &'a str,
+        #[serde(rename = "delayMs")]
+        delay_ms: u64,
+    },
+    #[serde(rename = "keepAlive")]
+    KeepAlive {
+        #[serde(rename = "decision")]
+        decision: &'a str,
+        #[serde(rename = "exitCode")]
+        exit_code: i32,
+        #[serde(rename = "recentCrashes")]
+        recent_crashes: usize,
+    },
+    #[serde(rename = "stop")]
+    Stop {
+        #[serde(rename = "method")]
+        method: &'a str,
+        #[serde(rename = "pid")]
+        pid: u32,
+    },
+    #[serde(rename = "stalePid")]
+    StalePid {
+        #[serde(rename = "pid")]
+        pid: i32,
+    },
+    #[serde(rename = "signal")]
+    Signal {
+        #[serde(rename = "signal")]
+        signal: &'a str,
+        #[serde(rename = "pid")]
+        pid: u32,
+    },
+    #[serde(rename = "hook")]
+    Hook {
+        #[serde(rename = "hook")]
+        hook: &'a str,
+        #[serde(rename = "command")]
+        command: &'a str,
+        #[serde(rename = "exitCode")]
+        exit_code: Option<i32>,
+    },
+    #[serde(rename = "upgrade")]
+    Upgrade {
+        #[serde(rename = "action")]
+        action: &'a str,
+        #[serde(rename = "reason")]
+        reason: Option<&'a str>,
+    },
+}
+
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    #[serde(rename = "timestamp")]
+    timestamp: u64,
+    #[serde(rename = "supervisorPid")]
+    supervisor_pid: u32,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+/// Appends events as JSON lines to `paperd-events.jsonl` in the server's working directory.
+/// Failing to write an event never interrupts the supervisor, the journal is only informational.
+#[derive(Clone)]
+pub struct EventJournal {
+    path: PathBuf,
+}
+
+impl EventJournal {
+    pub fn new<P: AsRef<Path>>(working_dir: P) -> Self {
+        return EventJournal {
+            path: working_dir.as_ref().join(EVENTS_FILE_NAME),
+        };
+    }
+
+    pub fn record(&self, event: Event) {
+        let record = EventRecord {
+            timestamp: now_millis(),
+            supervisor_pid: process::id(),
+            event,
+        };
+        let mut line = match serde_json::to_string(&record) {
+            Ok(l) => l,
+            Err(_) => return,
+        };
+        line.push('\n');
+
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path);
+        if let Ok(mut file) = file {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+}
+
+/// Parses either a duration relative to now, or a UTC date with an optional time, into a unix
+/// timestamp in milliseconds.
+fn parse_since(text: &str) -> Option<u64> {
+    if let Some(duration) = parse_duration(text) {
+        return Some(now_millis().saturating_sub(duration.as_millis() as u64));
+    }
+
+    let (date, time) = match text.find('T') {
+        Some(i) => (&text[..i], &text[i + 1..]),
+        None => (text, "00:00:00"),
+    };
+
+    let date: Vec<&str> = date.split('-').collect();
+    let time: Vec<&str> = time.trim_end_matches('Z').split(':').collect();
+    if date.len() != 3 || time.len() != 3 {
+        return None;
+    }
+
+    let year = date[0].parse::<i64>().ok()?;
+    let month = date[1].parse::<i64>().ok()?;
+    let day = date[2].parse::<i64>().ok()?;
+    let hour = time[0].parse::<i64>().ok()?;
+    let minute = time[1].parse::<i64>().ok()?;
+    let second = time[2].parse::<i64>().ok()?;
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    if secs < 0 {
+        return None;
+    }
+    return Some(secs as u64 * 1000);
+}
+
+fn format_timestamp(millis: u64) -> String {
+    let secs = (millis / 1000) as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let rem = secs.rem_euclid(86400);
+    return format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    );
+}
+
+// Conversions between days since the unix epoch and the proleptic Gregorian calendar, see
+// http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    return era * 146097 + doe - 719468;
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    return (year, month, day);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_relative_times() {
+        let now = now_millis();
+        let since = parse_since("7d").unwrap();
+        let expected = now - 7 * 86400 * 1000;
+        assert!(since >= expected && since <= expected + 1000);
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_since("1970-01-01"), Some(0));
+        assert_eq!(parse_since("2020-01-01"), Some(1_577_836_800_000));
+        assert_eq!(parse_since("2020-01-01T12:30:15Z"), Some(1_577_881_815_000));
+        assert_eq!(parse_since("2020-02-29T00:00:00"), Some(1_582_934_400_000));
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        assert_eq!(parse_since("2020-13-01"), None);
+        assert_eq!(parse_since("2020-01-32"), None);
+        assert_eq!(parse_since("2020-01-01T24:00:00"), None);
+        assert_eq!(parse_since("2020-01"), None);
+        assert_eq!(parse_since("1969-12-31"), None);
+        assert_eq!(parse_since("yesterday"), None);
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(format_timestamp(1_577_881_815_000), "2020-01-01 12:30:15");
+    }
+}
@@ -0,0 +1,155 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::events::{Event, EventJournal};
+use crate::output::{capture, RotatingFile};
+use std::path::Path;
+use std::process;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy)]
+pub enum Hook {
+    PreStart,
+    PostStart,
+    PreRestart,
+    PostStop,
+}
+
+impl Hook {
+    pub fn name(self) -> &'static str {
+        return match self {
+            Hook::PreStart => "preStart",
+            Hook::PostStart => "postStart",
+            Hook::PreRestart => "preRestart",
+            Hook::PostStop => "postStop",
+        };
+    }
+}
+
+/// What a hook is told about the server through its environment.
+#[derive(Default)]
+pub struct HookContext {
+    /// Number of times the server has been restarted by this paperd process
+    pub restart_count: u32,
+    /// Exit code of the server's last run, if it has exited before
+    pub exit_code: Option<i32>,
+    /// PID of the server's JVM, if there is or was one for this event
+    pub pid: Option<u32>,
+}
+
+/// Commands to run at points in the server's lifecycle. Every command is run with `sh -c` in the
+/// server's working directory, one after another.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    pub pre_start: Vec<String>,
+    pub post_start: Vec<String>,
+    pub pre_restart: Vec<String>,
+    pub post_stop: Vec<String>,
+}
+
+impl Hooks {
+    fn commands(&self, hook: Hook) -> &[String] {
+        return match hook {
+            Hook::PreStart => &self.pre_start,
+            Hook::PostStart => &self.post_start,
+            Hook::PreRestart => &self.pre_restart,
+            Hook::PostStop => &self.post_stop,
+        };
+    }
+
+    /// Runs the commands for `hook`, stopping at the first one which fails. Returns whether all of
+    /// them succeeded. When `log` is set, the output of the commands is written to the stdout log
+    /// the server writes to, rather than paperd's own stdout and stderr, which are closed when
+    /// running as a daemon.
+    pub fn run(
+        &self,
+        hook: Hook,
+        working_dir: &Path,
+        context: &HookContext,
+        events: &EventJournal,
+        log: Option<&Arc<Mutex<RotatingFile>>>,
+    ) -> bool {
+        for cmd in self.commands(hook) {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(cmd)
+                .current_dir(working_dir)
+                .env("PAPERD_HOOK", hook.name())
+                .env("PAPERD_INSTANCE_DIR", working_dir)
+                .env("PAPERD_SUPERVISOR_PID", process::id().to_string())
+                .env("PAPERD_RESTART_COUNT", context.restart_count.to_string())
+                .env_remove("PAPERD_EXIT_CODE")
+                .env_remove("PAPERD_PID")
+                .env_remove("NOTIFY_SOCKET")
+                .env_remove("WATCHDOG_USEC")
+                .env_remove("WATCHDOG_PID")
+                .stdin(Stdio::null());
+            if let Some(code) = context.exit_code {
+                command.env("PAPERD_EXIT_CODE", code.to_string());
+            }
+            if let Some(pid) = context.pid {
+                command.env("PAPERD_PID", pid.to_string());
+            }
+
+            if log.is_some() {
+                command.stdout(Stdio::piped()).stderr(Stdio::piped());
+            }
+
+            let status = match command.spawn() {
+                Ok(mut child) => {
+                    let mut readers = Vec::new();
+                    if let Some(log) = log {
+                        if let Some(stdout) = child.stdout.take() {
+                            readers.push(capture(stdout, log.clone()));
+                        }
+                        if let Some(stderr) = child.stderr.take() {
+                            readers.push(capture(stderr, log.clone()));
+                        }
+                    }
+                    let status = child.wait();
+                    for reader in readers {
+                        let _ = reader.join();
+                    }
+                    status.ok()
+                }
+                Err(err) => {
+                    eprintln!("Failed to run {} hook `{}`: {}", hook.name(), cmd, err);
+                    None
+                }
+            };
+
+            let exit_code = status.and_then(|s| s.code());
+            events.record(Event::Hook {
+                hook: hook.name(),
+                command: cmd,
+                exit_code,
+            });
+
+            if exit_code != Some(0) {
+                eprintln!(
+                    "{} hook `{}` failed with exit code {}",
+                    hook.name(),
+                    cmd,
+                    exit_code.map_or("none".to_string(), |c| c.to_string())
+                );
+                return false;
+            }
+        }
+
+        return true;
+    }
+}
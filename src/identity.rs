@@ -0,0 +1,171 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::util::ExitValue;
+use nix::unistd::{chown, geteuid, getgrouplist, setgid, setgroups, setuid, Gid, Group, Uid, User};
+use std::env;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+/// The user and groups the server is run as, when paperd is started as root.
+#[derive(Clone)]
+pub struct Identity {
+    pub user: String,
+    pub uid: Uid,
+    pub gid: Gid,
+    pub groups: Vec<Gid>,
+    home: PathBuf,
+}
+
+impl Identity {
+    /// Looks up the given user and groups. The group defaults to the user's primary group, and the
+    /// supplementary groups are the user's groups from the group database plus `groups`.
+    pub fn resolve(
+        user: Option<&str>,
+        group: Option<&str>,
+        groups: &[String],
+    ) -> Result<Option<Identity>, ExitValue> {
+        let user = match user {
+            Some(u) => find_user(u)?,
+            None => {
+                if group.is_some() || !groups.is_empty() {
+                    eprintln!("Groups to run the server as can only be given together with a user");
+                    return Err(ExitValue::Code(1));
+                }
+                return Ok(None);
+            }
+        };
+
+        let gid = match group {
+            Some(g) => find_group(g)?,
+            None => user.gid,
+        };
+
+        let name = CString::new(user.name.as_str()).unwrap_or_default();
+        let mut all_groups = match getgrouplist(&name, gid) {
+            Ok(g) => g,
+            Err(_) => vec![gid],
+        };
+        for group in groups {
+            let gid = find_group(group)?;
+            if !all_groups.contains(&gid) {
+                all_groups.push(gid);
+            }
+        }
+
+        return Ok(Some(Identity {
+            user: user.name,
+            uid: user.uid,
+            gid,
+            groups: all_groups,
+            home: user.dir,
+        }));
+    }
+
+    /// Checks that paperd is able to switch to this identity, which requires running as root
+    /// unless paperd already runs as the user.
+    pub fn check_switch(&self) -> Result<(), ExitValue> {
+        let euid = geteuid();
+        if !euid.is_root() && euid != self.uid {
+            eprintln!(
+                "paperd must be started as root to run the server as {}",
+                self.user
+            );
+            return Err(ExitValue::Code(1));
+        }
+        return Ok(());
+    }
+
+    /// Permanently switches this process to the user and groups. Everything paperd starts from
+    /// here on, including the server and hooks, runs as this user.
+    pub fn switch(&self) -> Result<(), ExitValue> {
+        if geteuid() == self.uid {
+            return Ok(());
+        }
+
+        // The groups have to be changed first, the user is no longer allowed to after setuid
+        let result = setgroups(&self.groups)
+            .and_then(|_| setgid(self.gid))
+            .and_then(|_| setuid(self.uid));
+        if let Err(e) = result {
+            eprintln!("Failed to switch to user {}: {}", self.user, e);
+            return Err(ExitValue::Code(1));
+        }
+
+        env::set_var("HOME", &self.home);
+        env::set_var("USER", &self.user);
+        env::set_var("LOGNAME", &self.user);
+        return Ok(());
+    }
+
+    /// Hands a file paperd created, or one left behind by an earlier run, over to the user.
+    pub fn give(&self, path: &Path) -> Result<(), ExitValue> {
+        if !path.exists() {
+            return Ok(());
+        }
+        if let Err(e) = chown(path, Some(self.uid), Some(self.gid)) {
+            eprintln!(
+                "Failed to change the owner of {} to {}: {}",
+                path.to_string_lossy(),
+                self.user,
+                e
+            );
+            return Err(ExitValue::Code(1));
+        }
+        return Ok(());
+    }
+}
+
+/// Refuses to run the server as root, which gives anyone who gets control of the server through
+/// a plugin or exploit control of the whole machine.
+pub fn check_not_root(identity: Option<&Identity>, allow_root: bool) -> Result<(), ExitValue> {
+    let uid = identity.map_or_else(geteuid, |i| i.uid);
+    if uid.is_root() && !allow_root {
+        eprintln!(
+            "Refusing to run the server as root. Choose a user to run it as with --user, or pass \
+             --allow-root if you really want to run it as root."
+        );
+        return Err(ExitValue::Code(1));
+    }
+    return Ok(());
+}
+
+fn find_user(name: &str) -> Result<User, ExitValue> {
+    let user = match name.parse::<u32>() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) => User::from_name(name),
+    };
+    return match user {
+        Ok(Some(user)) => Ok(user),
+        _ => {
+            eprintln!("Could not find user {}", name);
+            Err(ExitValue::Code(1))
+        }
+    };
+}
+
+fn find_group(name: &str) -> Result<Gid, ExitValue> {
+    let group = match name.parse::<u32>() {
+        Ok(gid) => Group::from_gid(Gid::from_raw(gid)),
+        Err(_) => Group::from_name(name),
+    };
+    return match group {
+        Ok(Some(group)) => Ok(group.gid),
+        _ => {
+            eprintln!("Could not find group {}", name);
+            Err(ExitValue::Code(1))
+        }
+    };
+}
@@ -0,0 +1,202 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::properties::{set_properties, PROPERTIES_FILE_NAME};
+use crate::protocol::check_jar_protocol;
+use crate::registry::{load_registry, register};
+use crate::runner::EULA_FILE_NAME;
+use crate::util::{ExitError, ExitValue};
+use clap::ArgMatches;
+use std::fs;
+use std::fs::canonicalize;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE_NAME: &'static str = "paperd.toml";
+const JAR_FILE_NAME: &'static str = "paperclip.jar";
+
+/// Files which mean there's a server in the directory already.
+const SERVER_FILES: &[&str] = &[
+    JAR_FILE_NAME,
+    CONFIG_FILE_NAME,
+    EULA_FILE_NAME,
+    PROPERTIES_FILE_NAME,
+    "world",
+];
+
+pub fn init(sub_m: &ArgMatches) -> Result<(), ExitValue> {
+    let dir = PathBuf::from(sub_m.value_of("DIR").unwrap());
+    let jar = PathBuf::from(sub_m.value_of("JAR").unwrap());
+    let force = sub_m.is_present("FORCE");
+
+    if !jar.is_file() {
+        eprintln!("Could not find jar {}", jar.to_string_lossy());
+        return Err(ExitValue::Code(1));
+    }
+    check_jar_protocol(&jar)?;
+
+    let port = match sub_m.value_of("PORT") {
+        Some(text) => match text.parse::<u16>() {
+            Ok(p) if p > 0 => Some(p.to_string()),
+            _ => {
+                eprintln!("Invalid port: {}", text);
+                return Err(ExitValue::Code(1));
+            }
+        },
+        None => None,
+    };
+
+    let name = sub_m.value_of("INSTANCE");
+    if let Some(name) = name {
+        if load_registry()?.contains_key(name) {
+            eprintln!(
+                "An instance named {} is already registered, choose another name",
+                name
+            );
+            return Err(ExitValue::Code(1));
+        }
+    }
+
+    let existing: Vec<&str> = SERVER_FILES
+        .iter()
+        .filter(|f| dir.join(f).exists())
+        .copied()
+        .collect();
+    if !existing.is_empty() && !force {
+        eprintln!(
+            "{} already contains a server ({}), use --force to overwrite it",
+            dir.to_string_lossy(),
+            existing.join(", ")
+        );
+        return Err(ExitValue::Code(1));
+    }
+
+    fs::create_dir_all(&dir).conv(format!(
+        "Failed to create directory {}",
+        dir.to_string_lossy()
+    ))?;
+    let dir = canonicalize(&dir).conv("Failed to get full path to the server directory")?;
+
+    let jar_text = place_jar(&jar, &dir, sub_m.is_present("LINK"))?;
+    println!("Created server in {}", dir.to_string_lossy());
+    println!("  {}", jar_text);
+
+    let config_file = dir.join(CONFIG_FILE_NAME);
+    fs::write(&config_file, starter_config(&dir))
+        .conv(format!("Failed to write {}", config_file.to_string_lossy()))?;
+    println!("  {}", CONFIG_FILE_NAME);
+
+    let accept_eula = sub_m.is_present("ACCEPT_EULA");
+    if accept_eula {
+        let eula = "# Accepted with paperd init \
+                    (https://account.mojang.com/documents/minecraft_eula)\neula=true\n";
+        fs::write(dir.join(EULA_FILE_NAME), eula).conv("Failed to write EULA file")?;
+        println!("  {}", EULA_FILE_NAME);
+    }
+
+    let mut properties = Vec::new();
+    if let Some(port) = &port {
+        properties.push(("server-port", port.as_str()));
+    }
+    if let Some(motd) = sub_m.value_of("MOTD") {
+        properties.push(("motd", motd));
+    }
+    if !properties.is_empty() {
+        set_properties(&dir, &properties)
+            .conv(format!("Failed to write {}", PROPERTIES_FILE_NAME))?;
+        println!("  {}", PROPERTIES_FILE_NAME);
+    }
+
+    if let Some(name) = name {
+        let registry = register(name, &dir, Some(&config_file))?;
+        println!(
+            "Registered the server as {} in {}",
+            name,
+            registry.to_string_lossy()
+        );
+    }
+
+    println!();
+    if !accept_eula && !dir.join(EULA_FILE_NAME).exists() {
+        println!(
+            "The Minecraft EULA (https://account.mojang.com/documents/minecraft_eula) has not been \
+             accepted yet. Until eula=true is set in {}, the server can only be run in the \
+             foreground.",
+            EULA_FILE_NAME
+        );
+    }
+    match name {
+        Some(name) => println!("Start the server with: paperd start -n {}", name),
+        None => println!(
+            "Start the server with: paperd start --config-file {}",
+            config_file.to_string_lossy()
+        ),
+    }
+
+    return Ok(());
+}
+
+/// Copies or links the jar into the server directory, returning what was done.
+fn place_jar(jar: &Path, dir: &Path, link: bool) -> Result<String, ExitValue> {
+    let jar = canonicalize(jar).conv("Failed to get full path to jar")?;
+    let target = dir.join(JAR_FILE_NAME);
+    if canonicalize(&target).ok() == Some(jar.clone()) {
+        return Ok(format!("{} (already in place)", JAR_FILE_NAME));
+    }
+
+    // A link to a jar which is gone doesn't exist, but still has to be removed
+    if fs::symlink_metadata(&target).is_ok() {
+        fs::remove_file(&target).conv(format!("Failed to replace {}", JAR_FILE_NAME))?;
+    }
+    if link {
+        symlink(&jar, &target).conv(format!("Failed to link {}", JAR_FILE_NAME))?;
+        return Ok(format!(
+            "{} (linked to {})",
+            JAR_FILE_NAME,
+            jar.to_string_lossy()
+        ));
+    }
+
+    fs::copy(&jar, &target).conv(format!("Failed to copy {}", JAR_FILE_NAME))?;
+    return Ok(format!(
+        "{} (copied from {})",
+        JAR_FILE_NAME,
+        jar.to_string_lossy()
+    ));
+}
+
+fn starter_config(dir: &Path) -> String {
+    let quote = |path: PathBuf| toml::Value::String(path.to_string_lossy().into_owned());
+    return format!(
+        r#"# paperd config for this server. Start it with paperd start --config-file, see
+# paperd start --help for all keys.
+
+workingDir = {}
+jarFile = {}
+
+# JVM arguments to run the server with. paperd picks a heap size and its own flags without them.
+# jvmArgs = ["-Xms4G", "-Xmx4G"]
+
+# Arguments for the server itself.
+# serverArgs = ["--port", "25566"]
+
+# Commands to run before the server starts and after it stops.
+# preStart = ["./backup.sh"]
+# postStop = []
+"#,
+        quote(dir.to_path_buf()),
+        quote(dir.join(JAR_FILE_NAME))
+    );
+}
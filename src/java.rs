@@ -0,0 +1,353 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::util::find_program;
+use std::cmp::max;
+use std::env;
+use std::fs;
+use std::fs::{canonicalize, File};
+use std::io::{Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use zip::ZipArchive;
+
+/// Class file major versions started at 45 with Java 1.1, so Java 8 classes are version 52.
+const CLASS_VERSION_OFFSET: u32 = 44;
+
+/// Places where JDKs are commonly installed, each subdirectory is a JDK.
+const JDK_DIRS: &[&str] = &["/usr/lib/jvm", "/usr/lib64/jvm", "/usr/java"];
+
+#[derive(Clone)]
+pub struct JavaInstall {
+    pub java: PathBuf,
+    pub version: u32,
+}
+
+/// Runs `java -version` and returns the feature version of the JVM, such as 8 for 1.8.0_292 or 17
+/// for 17.0.2.
+pub fn java_version(java: &Path) -> Option<u32> {
+    let output = Command::new(java)
+        .arg("-version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .ok()?;
+
+    // The version is written to stderr, like: openjdk version "17.0.2" 2022-01-18
+    let text = String::from_utf8_lossy(&output.stderr);
+    return text.lines().find_map(|line| {
+        let start = line.find('"')? + 1;
+        let end = start + line[start..].find('"')?;
+        parse_version(&line[start..end])
+    });
+}
+
+fn parse_version(version: &str) -> Option<u32> {
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    let first = parts.next()?.parse::<u32>().ok()?;
+    // Before Java 9 versions looked like 1.8.0_292
+    if first == 1 {
+        return parts.next()?.parse::<u32>().ok();
+    }
+    return Some(first);
+}
+
+/// Finds the Java version needed to run the jar, from the class file version of the main class
+/// named in its manifest, or from the Build-Jdk-Spec the jar was built with when the main class
+/// can't be read. Paperclip jars which bundle the server jar are looked into as well, as the server
+/// may need a newer Java than paperclip itself.
+pub fn required_java_version(jar: &Path) -> Option<u32> {
+    let mut archive = ZipArchive::new(File::open(jar).ok()?).ok()?;
+    let mut required = jar_java_version(&mut archive);
+
+    if let Some(bundled) = bundled_jar(&mut archive) {
+        if let Ok(mut bundled) = ZipArchive::new(Cursor::new(bundled)) {
+            if let Some(version) = jar_java_version(&mut bundled) {
+                required = Some(required.map_or(version, |r| max(r, version)));
+            }
+        }
+    }
+
+    return required;
+}
+
+fn jar_java_version<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Option<u32> {
+    let manifest = read_entry(archive, "META-INF/MANIFEST.MF")?;
+    let manifest = String::from_utf8_lossy(&manifest);
+    return main_class_version(archive, &manifest).or_else(|| {
+        manifest_attribute(&manifest, "Build-Jdk-Spec").and_then(|v| parse_version(&v))
+    });
+}
+
+fn main_class_version<R: Read + Seek>(archive: &mut ZipArchive<R>, manifest: &str) -> Option<u32> {
+    let main_class = manifest_attribute(manifest, "Main-Class")?;
+
+    let class = read_entry(archive, &format!("{}.class", main_class.replace('.', "/")))?;
+    if class.len() < 8 || class[..4] != [0xCA, 0xFE, 0xBA, 0xBE] {
+        return None;
+    }
+    let major = u16::from_be_bytes([class[6], class[7]]) as u32;
+    return major.checked_sub(CLASS_VERSION_OFFSET);
+}
+
+/// Newer paperclip jars list the server jar they contain in META-INF/versions.list, as lines of
+/// `hash<TAB>id<TAB>path`, with the path relative to META-INF/versions.
+fn bundled_jar<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Option<Vec<u8>> {
+    let list = read_entry(archive, "META-INF/versions.list")?;
+    let list = String::from_utf8_lossy(&list);
+    let path = list.lines().next()?.split('\t').nth(2)?.to_string();
+    return read_entry(archive, &format!("META-INF/versions/{}", path));
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Option<Vec<u8>> {
+    let mut entry = archive.by_name(name).ok()?;
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data).ok()?;
+    return Some(data);
+}
+
+fn manifest_attribute(manifest: &str, name: &str) -> Option<String> {
+    let mut value: Option<String> = None;
+    for line in manifest.lines() {
+        // Long values are continued on the next line, after a single space
+        if line.starts_with(' ') {
+            if let Some(v) = value.as_mut() {
+                v.push_str(&line[1..]);
+            }
+            continue;
+        }
+        if value.is_some() {
+            break;
+        }
+        if let Some(i) = line.find(": ") {
+            if line[..i].eq_ignore_ascii_case(name) {
+                value = Some(line[i + 2..].to_string());
+            }
+        }
+    }
+    return value.map(|v| v.trim().to_string());
+}
+
+/// Lists the Java installations paperd knows about: the given JDKs first, then JAVA_HOME and the
+/// java on the PATH, then JDKs in the usual system directories and in SDKMAN.
+pub fn find_installs(homes: &[String]) -> Vec<JavaInstall> {
+    let mut candidates: Vec<PathBuf> = homes.iter().map(|h| java_in_home(Path::new(h))).collect();
+
+    if let Some(home) = env::var_os("JAVA_HOME") {
+        candidates.push(java_in_home(Path::new(&home)));
+    }
+    if let Some(java) = find_program(&[("PATH", "java")]) {
+        candidates.push(java);
+    }
+
+    let mut dirs: Vec<PathBuf> = JDK_DIRS.iter().map(PathBuf::from).collect();
+    match env::var_os("SDKMAN_DIR") {
+        Some(sdkman) => dirs.push(Path::new(&sdkman).join("candidates/java")),
+        None => {
+            if let Some(home) = env::var_os("HOME") {
+                dirs.push(Path::new(&home).join(".sdkman/candidates/java"));
+            }
+        }
+    }
+    for dir in dirs {
+        if let Ok(entries) = fs::read_dir(dir) {
+            let mut homes: Vec<PathBuf> =
+                entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+            homes.sort();
+            candidates.extend(homes.iter().map(|h| java_in_home(h)));
+        }
+    }
+
+    let mut seen = Vec::new();
+    let mut installs = Vec::new();
+    for java in candidates {
+        let canonical = match canonicalize(&java) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        if !canonical.is_file() || seen.contains(&canonical) {
+            continue;
+        }
+        seen.push(canonical);
+
+        if let Some(install) = java_install(&java) {
+            installs.push(install);
+        }
+    }
+
+    return installs;
+}
+
+/// Finds the Java version of the java executable at `java`.
+pub fn java_install(java: &Path) -> Option<JavaInstall> {
+    let canonical = canonicalize(java).ok()?;
+    let version = release_version(&canonical).or_else(|| java_version(java))?;
+    return Some(JavaInstall {
+        java: java.to_path_buf(),
+        version,
+    });
+}
+
+/// Picks the JVM to run code for the required Java version with. `default`, the java on the PATH
+/// or in JAVA_HOME, is kept whenever it is new enough, as that's the Java the user chose. Only
+/// when it isn't are the installations from `installs` searched, and all of them are returned
+/// when none of them fits either.
+pub fn prefer_java<F>(
+    default: Option<JavaInstall>,
+    required: u32,
+    installs: F,
+) -> Result<JavaInstall, Vec<JavaInstall>>
+where
+    F: FnOnce() -> Vec<JavaInstall>,
+{
+    if let Some(default) = default.filter(|d| d.version >= required) {
+        return Ok(default);
+    }
+
+    let installs = installs();
+    return match select_java(&installs, required) {
+        Some(install) => Ok(install.clone()),
+        None => Err(installs),
+    };
+}
+
+/// Picks the oldest installation which can run code for the required Java version, so old servers
+/// keep running on the Java they were made for.
+pub fn select_java(installs: &[JavaInstall], required: u32) -> Option<&JavaInstall> {
+    return installs
+        .iter()
+        .filter(|i| i.version >= required)
+        .min_by_key(|i| i.version);
+}
+
+/// Accepts either the home directory of a JDK or the path to a java executable.
+fn java_in_home(home: &Path) -> PathBuf {
+    if home.is_file() {
+        return home.to_path_buf();
+    }
+    return home.join("bin/java");
+}
+
+/// JDKs describe themselves in a `release` file in their home directory, which is a lot quicker
+/// to read than starting the JVM.
+fn release_version(java: &Path) -> Option<u32> {
+    let home = java.parent()?.parent()?;
+    let release = fs::read_to_string(home.join("release")).ok()?;
+    let line = release.lines().find(|l| l.starts_with("JAVA_VERSION="))?;
+    return parse_version(line["JAVA_VERSION=".len()..].trim_matches('"'));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    fn jar(entries: &[(&str, &[u8])]) -> ZipArchive<Cursor<Vec<u8>>> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        return ZipArchive::new(writer.finish().unwrap()).unwrap();
+    }
+
+    fn class(major: u8) -> Vec<u8> {
+        return vec![0xCA, 0xFE, 0xBA, 0xBE, 0, 0, 0, major];
+    }
+
+    fn install(java: &str, version: u32) -> JavaInstall {
+        return JavaInstall {
+            java: PathBuf::from(java),
+            version,
+        };
+    }
+
+    #[test]
+    fn prefers_default_java_when_new_enough() {
+        let default = install("/usr/bin/java", 21);
+        let chosen = prefer_java(Some(default), 17, || panic!("searched for installations"));
+        assert_eq!(
+            chosen.ok().map(|i| i.java),
+            Some(PathBuf::from("/usr/bin/java"))
+        );
+
+        let installs = || {
+            vec![
+                install("/jdk/21/bin/java", 21),
+                install("/jdk/17/bin/java", 17),
+            ]
+        };
+        let chosen = prefer_java(Some(install("/usr/bin/java", 11)), 17, installs);
+        assert_eq!(
+            chosen.ok().map(|i| i.java),
+            Some(PathBuf::from("/jdk/17/bin/java"))
+        );
+
+        let chosen = prefer_java(None, 17, installs);
+        assert_eq!(
+            chosen.ok().map(|i| i.java),
+            Some(PathBuf::from("/jdk/17/bin/java"))
+        );
+
+        let chosen = prefer_java(Some(install("/usr/bin/java", 11)), 25, installs);
+        assert_eq!(chosen.err().map(|i| i.len()), Some(2));
+    }
+
+    #[test]
+    fn parses_java_versions() {
+        assert_eq!(parse_version("1.8.0_292"), Some(8));
+        assert_eq!(parse_version("17.0.2"), Some(17));
+        assert_eq!(parse_version("21"), Some(21));
+        assert_eq!(parse_version("openjdk"), None);
+    }
+
+    #[test]
+    fn reads_continued_manifest_attributes() {
+        let manifest =
+            "Manifest-Version: 1.0\r\nMain-Class: io.papermc.paper\r\n .PaperBootstrap\r\n";
+        assert_eq!(
+            manifest_attribute(manifest, "main-class"),
+            Some("io.papermc.paper.PaperBootstrap".to_string())
+        );
+        assert_eq!(manifest_attribute(manifest, "Build-Jdk-Spec"), None);
+    }
+
+    #[test]
+    fn finds_version_from_main_class() {
+        let mut archive = jar(&[
+            (
+                "META-INF/MANIFEST.MF",
+                b"Main-Class: a.Main\nBuild-Jdk-Spec: 21\n",
+            ),
+            ("a/Main.class", &class(61)),
+        ]);
+        assert_eq!(jar_java_version(&mut archive), Some(17));
+    }
+
+    #[test]
+    fn falls_back_to_build_jdk_spec() {
+        let mut archive = jar(&[(
+            "META-INF/MANIFEST.MF",
+            b"Main-Class: a.Missing\nBuild-Jdk-Spec: 1.8\n",
+        )]);
+        assert_eq!(jar_java_version(&mut archive), Some(8));
+
+        let mut archive = jar(&[("META-INF/MANIFEST.MF", b"Main-Class: a.Missing\n")]);
+        assert_eq!(jar_java_version(&mut archive), None);
+    }
+}
@@ -0,0 +1,182 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_RESTART_DELAY: Duration = Duration::from_secs(1);
+pub const DEFAULT_MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+pub const DEFAULT_MAX_CRASHES: u32 = 5;
+pub const DEFAULT_CRASH_WINDOW: Duration = Duration::from_secs(600);
+
+#[derive(Clone)]
+pub struct RestartPolicy {
+    /// Delay before the first restart after a crash, doubled for every further crash in the window
+    pub restart_delay: Duration,
+    pub max_restart_delay: Duration,
+    /// Number of crashes within `crash_window` after which paperd gives up, 0 means no limit
+    pub max_crashes: u32,
+    pub crash_window: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        return RestartPolicy {
+            restart_delay: DEFAULT_RESTART_DELAY,
+            max_restart_delay: DEFAULT_MAX_RESTART_DELAY,
+            max_crashes: DEFAULT_MAX_CRASHES,
+            crash_window: DEFAULT_CRASH_WINDOW,
+        };
+    }
+}
+
+pub enum Decision {
+    Restart(Duration),
+    GiveUp(usize),
+}
+
+/// Keeps track of recent crashes to decide how long to wait before the next restart, and whether
+/// the server should be restarted at all.
+pub struct CrashTracker {
+    policy: RestartPolicy,
+    crashes: VecDeque<Instant>,
+}
+
+impl CrashTracker {
+    pub fn new(policy: RestartPolicy) -> Self {
+        return CrashTracker {
+            policy,
+            crashes: VecDeque::new(),
+        };
+    }
+
+    pub fn record_crash(&mut self) -> Decision {
+        return self.record_crash_at(Instant::now());
+    }
+
+    fn record_crash_at(&mut self, now: Instant) -> Decision {
+        self.crashes.push_back(now);
+
+        // Forget about crashes which happened before the current window, this also resets the
+        // backoff once the server has been stable for a while
+        while let Some(first) = self.crashes.front() {
+            if now.duration_since(*first) > self.policy.crash_window {
+                self.crashes.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let count = self.crashes.len();
+        if self.policy.max_crashes != 0 && count >= self.policy.max_crashes as usize {
+            return Decision::GiveUp(count);
+        }
+
+        // Cap the shift so huge crash counts can't overflow the multiplier
+        let factor = 1u32 << min(count - 1, 16) as u32;
+        let delay = self
+            .policy
+            .restart_delay
+            .checked_mul(factor)
+            .unwrap_or(self.policy.max_restart_delay);
+
+        return Decision::Restart(min(delay, self.policy.max_restart_delay));
+    }
+
+    pub fn recent_crashes(&self) -> usize {
+        return self.crashes.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_crashes: u32) -> RestartPolicy {
+        return RestartPolicy {
+            restart_delay: Duration::from_secs(1),
+            max_restart_delay: Duration::from_secs(10),
+            max_crashes,
+            crash_window: Duration::from_secs(60),
+        };
+    }
+
+    fn delay(decision: Decision) -> Duration {
+        return match decision {
+            Decision::Restart(delay) => delay,
+            Decision::GiveUp(count) => panic!("gave up after {} crashes", count),
+        };
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut tracker = CrashTracker::new(policy(0));
+        let start = Instant::now();
+        let delays: Vec<u64> = (0..6)
+            .map(|i| delay(tracker.record_crash_at(start + Duration::from_secs(i))).as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+    }
+
+    #[test]
+    fn gives_up_at_max_crashes() {
+        let mut tracker = CrashTracker::new(policy(3));
+        let start = Instant::now();
+        delay(tracker.record_crash_at(start));
+        delay(tracker.record_crash_at(start + Duration::from_secs(1)));
+        match tracker.record_crash_at(start + Duration::from_secs(2)) {
+            Decision::GiveUp(count) => assert_eq!(count, 3),
+            Decision::Restart(_) => panic!("restarted after the third crash"),
+        }
+    }
+
+    #[test]
+    fn zero_max_crashes_never_gives_up() {
+        let mut tracker = CrashTracker::new(policy(0));
+        let start = Instant::now();
+        for i in 0..100 {
+            delay(tracker.record_crash_at(start + Duration::from_millis(i)));
+        }
+        assert_eq!(tracker.recent_crashes(), 100);
+    }
+
+    #[test]
+    fn crashes_outside_the_window_are_forgotten() {
+        let mut tracker = CrashTracker::new(policy(3));
+        let start = Instant::now();
+        delay(tracker.record_crash_at(start));
+        delay(tracker.record_crash_at(start + Duration::from_secs(1)));
+
+        // Both earlier crashes are more than a window ago, so this counts as the first again
+        let later = start + Duration::from_secs(120);
+        assert_eq!(
+            delay(tracker.record_crash_at(later)),
+            Duration::from_secs(1)
+        );
+        assert_eq!(tracker.recent_crashes(), 1);
+    }
+
+    #[test]
+    fn huge_crash_counts_stay_at_the_cap() {
+        let mut tracker = CrashTracker::new(policy(0));
+        let start = Instant::now();
+        let mut last = Duration::from_secs(0);
+        for i in 0..40 {
+            last = delay(tracker.record_crash_at(start + Duration::from_millis(i)));
+        }
+        assert_eq!(last, Duration::from_secs(10));
+    }
+}
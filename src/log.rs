@@ -13,14 +13,21 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::util::{find_program, find_sock_file, ExitError, ExitValue};
+use crate::runner::STDOUT_LOG_FILE_NAME;
+use crate::util::{find_program, find_sock_file, find_sock_path, ExitError, ExitValue};
 use clap::ArgMatches;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 pub fn log(sub_m: &ArgMatches) -> Result<(), ExitValue> {
-    let sock_file = find_sock_file(sub_m)?;
-    let log_file = find_log_file(&sock_file)?;
+    let log_file = if sub_m.is_present("STDOUT") {
+        // The server may have died before it ever created the socket, which is exactly when this
+        // file is most useful, so don't require the socket to exist
+        find_stdout_log_file(find_sock_path(sub_m)?)?
+    } else {
+        let sock_file = find_sock_file(sub_m)?;
+        find_log_file(&sock_file)?
+    };
 
     let follow = sub_m.is_present("TAIL");
     let lines = match sub_m.value_of("LINES") {
@@ -47,6 +54,17 @@ pub fn find_log_file<P: AsRef<Path>>(sock_file: P) -> Result<PathBuf, ExitValue>
     };
 }
 
+pub fn find_stdout_log_file<P: AsRef<Path>>(sock_file: P) -> Result<PathBuf, ExitValue> {
+    let sock_file = sock_file.as_ref();
+    return match sock_file.parent().map(|p| p.join(STDOUT_LOG_FILE_NAME)) {
+        Some(f) => Ok(f),
+        None => {
+            eprintln!("Failed to find log file in {}", STDOUT_LOG_FILE_NAME);
+            Err(ExitValue::Code(1))
+        }
+    };
+}
+
 pub fn tail<P: AsRef<Path>>(path: P, lines: i32, follow: bool) -> Result<(), ExitValue> {
     let path = path.as_ref();
     if !path.is_file() {
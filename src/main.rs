@@ -26,31 +26,59 @@ extern crate signal_hook;
 extern crate sys_info;
 extern crate zip;
 
+mod broadcast;
+mod cgroup;
 mod cmd;
+mod config;
 #[cfg(feature = "console")]
 mod console;
 mod daemon;
+mod environment;
+mod events;
+mod hooks;
+mod identity;
+mod init;
+mod java;
+mod keep_alive;
 mod log;
+mod memory;
 mod messages;
 mod messaging;
+mod output;
+mod pid;
+mod preflight;
+mod presets;
+mod properties;
 mod protocol;
+mod registry;
+mod resources;
 mod restart;
 mod runner;
 mod send;
+mod service;
 mod status;
 mod stop;
+mod systemd;
 mod timings;
+mod upgrade;
 mod util;
+mod watchdog;
 
+use crate::config::config;
 #[cfg(feature = "console")]
 use crate::console::console;
+use crate::events::events;
+use crate::init::init;
 use crate::log::log;
+use crate::registry::list;
 use crate::restart::restart;
 use crate::runner::{run_cmd, start};
 use crate::send::send;
+use crate::service::install_service;
 use crate::status::status;
 use crate::stop::stop;
 use crate::timings::timings;
+use crate::upgrade::upgrade;
 use crate::util::ExitValue;
 use std::process::exit;
 
@@ -63,13 +91,19 @@ fn run() -> i32 {
 
     let ret: Result<(), ExitValue> = match matches.subcommand() {
         ("status", Some(sub_m)) => status(sub_m),
+        ("list", Some(sub_m)) => list(sub_m),
         ("send", Some(sub_m)) => send(sub_m),
         ("log", Some(sub_m)) => log(sub_m),
+        ("events", Some(sub_m)) => events(sub_m),
         ("start", Some(sub_m)) => start(sub_m),
         ("run", Some(sub_m)) => run_cmd(sub_m),
         ("stop", Some(sub_m)) => stop(sub_m),
         ("restart", Some(sub_m)) => restart(sub_m),
+        ("upgrade", Some(sub_m)) => upgrade(sub_m),
         ("timings", Some(sub_m)) => timings(sub_m),
+        ("install-service", Some(sub_m)) => install_service(sub_m),
+        ("init", Some(sub_m)) => init(sub_m),
+        ("config", Some(sub_m)) => config(sub_m),
         #[cfg(feature = "console")]
         ("console", Some(sub_m)) => console(sub_m),
         ("completions", Some(sub_m)) => {
@@ -0,0 +1,273 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::util::{parse_size, ExitValue};
+use std::cmp::{max, min};
+use std::fs;
+use std::path::{Path, PathBuf};
+use sys_info::mem_info;
+
+pub const MB: u64 = 1024 * 1024;
+
+/// The most memory paperd will pick for the heap on its own, when no size is given.
+const AUTO_HEAP_CAP: u64 = 10 * 1024 * MB;
+/// Heaps smaller than this are rejected by the JVM.
+pub const MIN_HEAP: u64 = 2 * MB;
+
+const MIN_HEADROOM: u64 = 256 * MB;
+const HEADROOM_PERCENT: u64 = 10;
+
+/// cgroup v1 reports this (rounded down to the page size) when there is no limit.
+const V1_UNLIMITED: u64 = 1 << 62;
+
+pub enum HeapArg {
+    /// An amount of memory in the format the JVM accepts, and the number of bytes it stands for
+    Size(String, u64),
+    /// A percentage of the memory available to the server
+    Percent(u64),
+}
+
+/// Parses the value of --default-args, either a heap size like `512m` or `4G`, or a percentage like
+/// `75%`.
+pub fn parse_heap_arg(text: &str) -> Option<HeapArg> {
+    if text.ends_with('%') {
+        let percent = text[..text.len() - 1].parse::<u64>().ok()?;
+        if percent == 0 || percent > 100 {
+            return None;
+        }
+        return Some(HeapArg::Percent(percent));
+    }
+    return parse_heap_size(text).map(|bytes| HeapArg::Size(text.to_string(), bytes));
+}
+
+/// Parses heap sizes exactly like the JVM's -Xmx does: a number of bytes, optionally followed by one
+/// of the units k, m, g or t in either case.
+pub fn parse_heap_size(text: &str) -> Option<u64> {
+    if text.trim() != text {
+        return None;
+    }
+    return parse_size(text);
+}
+
+/// How much memory the server may use: the memory limit of paperd's cgroup, if there is one, and
+/// otherwise the total memory of the machine.
+pub struct MemoryInfo {
+    pub cgroup_limit: Option<u64>,
+    pub host_total: Option<u64>,
+    pub host_available: Option<u64>,
+}
+
+impl MemoryInfo {
+    pub fn detect() -> Self {
+        let info = mem_info().ok();
+        return MemoryInfo {
+            cgroup_limit: cgroup_memory_limit(),
+            // sys_info reports kilobytes
+            host_total: info.as_ref().map(|i| i.total * 1024),
+            // info.avail should always be greater than free, but it seems there may be a bug
+            // for macOS. Assuming most users are using linux this doesn't really affect much
+            host_available: info.as_ref().map(|i| max(i.avail, i.free) * 1024),
+        };
+    }
+
+    /// Lowers the memory limit to `limit`, for a server placed in a cgroup with a limit of its own.
+    pub fn cap(&mut self, limit: u64) {
+        self.cgroup_limit = Some(self.cgroup_limit.map_or(limit, |l| min(l, limit)));
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        return match (self.cgroup_limit, self.host_total) {
+            (Some(limit), Some(total)) => Some(min(limit, total)),
+            (limit, total) => limit.or(total),
+        };
+    }
+
+    /// Memory kept free for the JVM's own use outside of the heap, such as metaspace, thread
+    /// stacks and direct buffers.
+    pub fn default_headroom(&self) -> u64 {
+        return self.limit().map_or(MIN_HEADROOM, |limit| {
+            max(MIN_HEADROOM, limit / 100 * HEADROOM_PERCENT)
+        });
+    }
+
+    /// The largest heap which still leaves `headroom` free under the memory limit.
+    pub fn max_heap(&self, headroom: u64) -> Option<u64> {
+        return self.limit().map(|limit| limit.saturating_sub(headroom));
+    }
+
+    /// Half of the memory currently available, within the memory limit, capped at 10G.
+    pub fn auto_heap(&self, headroom: u64) -> Option<u64> {
+        let available = match (self.host_available, self.cgroup_limit) {
+            (Some(available), Some(limit)) => min(available, limit),
+            (available, limit) => available.or(limit)?,
+        };
+        let mut heap = min(available / 2, AUTO_HEAP_CAP);
+        if let Some(max_heap) = self.max_heap(headroom) {
+            heap = min(heap, max_heap);
+        }
+        return Some(heap);
+    }
+}
+
+/// Turns a percentage into a heap size in the format the JVM accepts.
+pub fn percent_heap(
+    memory: &MemoryInfo,
+    percent: u64,
+    headroom: u64,
+) -> Result<(String, u64), ExitValue> {
+    let limit = match memory.limit() {
+        Some(l) => l,
+        None => {
+            eprintln!(
+                "Failed to find out how much memory is available, specify the heap size as an \
+                 amount of memory instead of a percentage."
+            );
+            return Err(ExitValue::Code(1));
+        }
+    };
+
+    let mut heap = limit / 100 * percent;
+    if let Some(max_heap) = memory.max_heap(headroom) {
+        heap = min(heap, max_heap);
+    }
+    if heap < MIN_HEAP {
+        eprintln!(
+            "{}% of {} MB leaves no room for the heap after reserving {} MB outside of it",
+            percent,
+            limit / MB,
+            headroom / MB
+        );
+        return Err(ExitValue::Code(1));
+    }
+
+    return Ok(format_heap(heap));
+}
+
+/// Formats a number of bytes as whole megabytes, the JVM rounds heap sizes anyways.
+pub fn format_heap(bytes: u64) -> (String, u64) {
+    let mb = bytes / MB;
+    return (format!("{}m", mb), mb * MB);
+}
+
+/// Finds the memory limit of paperd's cgroup, which is the smallest limit set on it or any of its
+/// parents. Both cgroup v1 and v2 are supported, as well as hybrid setups using both.
+pub fn cgroup_memory_limit() -> Option<u64> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+
+    let mut result: Option<u64> = None;
+    for line in cgroups.lines() {
+        let mut parts = line.splitn(3, ':');
+        let (id, controllers, path) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(id), Some(controllers), Some(path)) => (id, controllers, path),
+            _ => continue,
+        };
+
+        let limit = if id == "0" && controllers.is_empty() {
+            let root = if Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+                PathBuf::from("/sys/fs/cgroup")
+            } else {
+                PathBuf::from("/sys/fs/cgroup/unified")
+            };
+            smallest_limit(&root, path, "memory.max")
+        } else if controllers.split(',').any(|c| c == "memory") {
+            smallest_limit(
+                Path::new("/sys/fs/cgroup/memory"),
+                path,
+                "memory.limit_in_bytes",
+            )
+        } else {
+            None
+        };
+
+        if let Some(limit) = limit {
+            result = Some(result.map_or(limit, |r| min(r, limit)));
+        }
+    }
+
+    return result;
+}
+
+/// Reads the limit file of the cgroup at `path` and all of its parents. Inside a container the
+/// path is often relative to a cgroup mounted as the root, so this ends at the root either way.
+fn smallest_limit(root: &Path, path: &str, file: &str) -> Option<u64> {
+    let mut result: Option<u64> = None;
+    for dir in Path::new(path).ancestors() {
+        let limit_file = root.join(dir.strip_prefix("/").unwrap_or(dir)).join(file);
+        let limit = match fs::read_to_string(limit_file) {
+            Ok(text) => text.trim().parse::<u64>().ok(),
+            Err(_) => None,
+        };
+        if let Some(limit) = limit.filter(|l| *l < V1_UNLIMITED) {
+            result = Some(result.map_or(limit, |r| min(r, limit)));
+        }
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(text: &str) -> Option<u64> {
+        return match parse_heap_arg(text)? {
+            HeapArg::Size(_, bytes) => Some(bytes),
+            HeapArg::Percent(_) => None,
+        };
+    }
+
+    fn percent(text: &str) -> Option<u64> {
+        return match parse_heap_arg(text)? {
+            HeapArg::Percent(percent) => Some(percent),
+            HeapArg::Size(_, _) => None,
+        };
+    }
+
+    #[test]
+    fn parses_heap_sizes() {
+        assert_eq!(size("512m"), Some(512 * MB));
+        assert_eq!(size("4G"), Some(4096 * MB));
+        assert_eq!(size("2097152"), Some(2 * MB));
+    }
+
+    #[test]
+    fn parses_heap_percentages() {
+        assert_eq!(percent("75%"), Some(75));
+        assert_eq!(percent("100%"), Some(100));
+        assert_eq!(percent("0%"), None);
+        assert_eq!(percent("101%"), None);
+        assert_eq!(percent("%"), None);
+    }
+
+    #[test]
+    fn rejects_sizes_the_jvm_rejects() {
+        assert!(parse_heap_arg(" 4G").is_none());
+        assert!(parse_heap_arg("4G ").is_none());
+        assert!(parse_heap_arg("4GB").is_none());
+        assert!(parse_heap_arg("4.5G").is_none());
+    }
+
+    #[test]
+    fn caps_the_memory_limit() {
+        let mut memory = MemoryInfo {
+            cgroup_limit: None,
+            host_total: Some(8192 * MB),
+            host_available: Some(4096 * MB),
+        };
+        memory.cap(2048 * MB);
+        assert_eq!(memory.limit(), Some(2048 * MB));
+        memory.cap(4096 * MB);
+        assert_eq!(memory.limit(), Some(2048 * MB));
+    }
+}
@@ -0,0 +1,152 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use nix::libc;
+use nix::unistd::dup2;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::{fs, io, thread};
+
+pub const DEFAULT_STDOUT_LOG_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_STDOUT_LOG_COUNT: u32 = 5;
+
+#[derive(Clone)]
+pub struct StdoutLogConfig {
+    pub path: PathBuf,
+    /// Size in bytes after which the log file is rotated
+    pub max_size: u64,
+    /// Number of rotated files to keep next to the current one
+    pub retention: u32,
+}
+
+/// A log file which is rotated once it grows past a configured size. Rotated files are renamed
+/// with an increasing numeric suffix, `paperd-stdout.log.1` being the most recent.
+pub struct RotatingFile {
+    config: StdoutLogConfig,
+    file: File,
+    size: u64,
+    /// Whether stdout and stderr point at the current file, and have to follow it when rotating
+    stdio: bool,
+}
+
+impl RotatingFile {
+    pub fn open(config: StdoutLogConfig) -> io::Result<Self> {
+        let file = open_append(&config.path)?;
+        let size = file.metadata()?.len();
+        return Ok(RotatingFile {
+            config,
+            file,
+            size,
+            stdio: false,
+        });
+    }
+
+    /// Points stdout and stderr of this process at the log file, so paperd's own messages end up
+    /// next to the output of the server. They are moved along to the new file on every rotation.
+    pub fn redirect_stdio(&mut self) -> io::Result<()> {
+        self.stdio = true;
+        return self.dup_stdio();
+    }
+
+    fn dup_stdio(&self) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        for target in &[libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            dup2(fd, *target).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        }
+        return Ok(());
+    }
+
+    pub fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        return Ok(());
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.config.path;
+        let retention = self.config.retention;
+
+        if retention == 0 {
+            self.file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            self.size = 0;
+            if self.stdio {
+                self.dup_stdio()?;
+            }
+            return Ok(());
+        }
+
+        let _ = fs::remove_file(rotated_path(path, retention));
+        for i in (1..retention).rev() {
+            let from = rotated_path(path, i);
+            if from.exists() {
+                fs::rename(&from, rotated_path(path, i + 1))?;
+            }
+        }
+        fs::rename(path, rotated_path(path, 1))?;
+
+        self.file = open_append(path)?;
+        self.size = 0;
+        if self.stdio {
+            self.dup_stdio()?;
+        }
+        return Ok(());
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    return OpenOptions::new().append(true).create(true).open(path);
+}
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", index));
+    return PathBuf::from(name);
+}
+
+/// Copies everything read from `input` into the shared log file, one line at a time so output
+/// from stdout and stderr doesn't get mixed up within a single line.
+pub fn capture<R: Read + Send + 'static>(
+    input: R,
+    log: Arc<Mutex<RotatingFile>>,
+) -> JoinHandle<()> {
+    return thread::spawn(move || {
+        let mut reader = BufReader::new(input);
+        let mut line = Vec::<u8>::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if let Ok(mut file) = log.lock() {
+                        // There's nowhere left to report a failure to, so just drop the line
+                        let _ = file.write_line(&line);
+                    }
+                }
+            }
+        }
+    });
+}
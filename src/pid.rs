@@ -0,0 +1,323 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::events::{Event, EventJournal};
+use crate::runner::{PID_FILE_NAME, SOCK_FILE_NAME};
+use crate::util::{ExitError, ExitValue};
+use nix::errno::Errno;
+use nix::errno::Errno::{EACCES, EAGAIN, ESRCH};
+use nix::libc;
+use nix::sys::signal::kill;
+use nix::unistd::{sysconf, Pid, SysconfVar};
+use nix::Error;
+use std::env;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::ErrorKind;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const LOCK_FILE_NAME: &'static str = "paperd.lock";
+
+/// An exclusive lock on a server's working directory, held by the supervisor for as long as it
+/// runs. The kernel releases it when the supervisor exits, however it exits, so unlike the PID
+/// file it can never be left behind.
+///
+/// It is an open file description lock rather than a `flock(2)` lock. Both belong to the open
+/// file, so the daemon keeps the lock it inherits when forking, but `flock(2)` has no way to test
+/// for a lock other than taking it. Commands like `status` which only check whether a supervisor is
+/// running would then hold the lock for a moment, and a supervisor starting at that moment would
+/// be turned away. `F_OFD_GETLK` tests for the lock without taking it.
+pub struct InstanceLock {
+    _file: File,
+    working_dir: PathBuf,
+}
+
+impl InstanceLock {
+    pub fn acquire(working_dir: &Path) -> Result<InstanceLock, ExitValue> {
+        let path = working_dir.join(LOCK_FILE_NAME);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .conv(format!(
+                "Failed to open lock file {}",
+                path.to_string_lossy()
+            ))?;
+
+        let mut lock = whole_file_lock(libc::F_WRLCK);
+        match ofd_lock(&file, libc::F_OFD_SETLK, &mut lock) {
+            Ok(_) => {
+                return Ok(InstanceLock {
+                    _file: file,
+                    working_dir: working_dir.to_path_buf(),
+                })
+            }
+            Err(Error::Sys(EAGAIN)) | Err(Error::Sys(EACCES)) => {
+                eprintln!(
+                    "Another paperd is already running the server in {}, will not continue",
+                    working_dir.to_string_lossy()
+                );
+            }
+            Err(e) => {
+                eprintln!("Failed to lock {}: {}", path.to_string_lossy(), e);
+            }
+        }
+        return Err(ExitValue::Code(1));
+    }
+
+    /// Like [`clean_stale`](fn.clean_stale.html), but holding the lock already proves no other
+    /// supervisor is running in the working directory.
+    pub fn clean_stale(&self) -> Result<bool, ExitValue> {
+        return remove_stale(&self.working_dir);
+    }
+}
+
+/// Checks whether a supervisor holds the lock on `working_dir`. The lock is what counts, not the
+/// PID file, which may have been deleted or overwritten while the supervisor is running. The lock
+/// is only tested, never taken, so a supervisor starting at the same time isn't turned away.
+pub fn is_locked(working_dir: &Path) -> bool {
+    let file = match File::open(working_dir.join(LOCK_FILE_NAME)) {
+        Ok(f) => f,
+        Err(_) => return false,
+    };
+    // The kernel replaces the lock with the one which would conflict with it, if there is one
+    let mut lock = whole_file_lock(libc::F_WRLCK);
+    return match ofd_lock(&file, libc::F_OFD_GETLK, &mut lock) {
+        Ok(_) => lock.l_type != libc::F_UNLCK as libc::c_short,
+        Err(_) => false,
+    };
+}
+
+fn whole_file_lock(lock_type: libc::c_int) -> libc::flock {
+    let mut lock: libc::flock = unsafe { mem::zeroed() };
+    lock.l_type = lock_type as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    // A length of 0 reaches to the end of the file, however long it gets
+    lock.l_start = 0;
+    lock.l_len = 0;
+    return lock;
+}
+
+/// nix doesn't pass the open file description lock commands on to fcntl, so this does.
+fn ofd_lock(file: &File, command: libc::c_int, lock: &mut libc::flock) -> nix::Result<()> {
+    let result = unsafe { libc::fcntl(file.as_raw_fd(), command, lock as *mut libc::flock) };
+    return Errno::result(result).map(drop);
+}
+
+/// What the PID file in a server's working directory says about the supervisor.
+pub enum PidStatus {
+    /// There's no PID file
+    Missing,
+    /// The supervisor with this PID is running
+    Running(Pid),
+    /// The PID file was left behind by a supervisor which isn't running anymore. The PID may have
+    /// been reused by an unrelated process since.
+    Stale(Pid),
+}
+
+/// Writes the PID file for the supervisor with `pid`. Besides the PID it holds the start time of
+/// the process, so the PID file can't be mistaken for a live one after the PID was reused.
+pub fn write_pid_file(path: &Path, pid: u32) -> io::Result<()> {
+    let mut text = pid.to_string();
+    if let Some(start_time) = start_time(pid as i32) {
+        text.push('\n');
+        text.push_str(&start_time.to_string());
+    }
+    return fs::write(path, text);
+}
+
+pub fn read_pid_file(path: &Path) -> Result<PidStatus, ExitValue> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(PidStatus::Missing),
+        Err(e) => {
+            eprintln!("Failed to read PID file: {}", e);
+            return Err(ExitValue::Code(1));
+        }
+    };
+
+    let mut lines = text.lines();
+    let pid = match lines.next().and_then(|l| l.trim().parse::<i32>().ok()) {
+        Some(p) => Pid::from_raw(p),
+        None => {
+            eprintln!("Failed to parse PID file {}", path.to_string_lossy());
+            return Err(ExitValue::Code(1));
+        }
+    };
+    // PID files written by older versions only hold the PID
+    let start = lines.next().and_then(|l| l.trim().parse::<u64>().ok());
+
+    return if is_supervisor(pid, start) {
+        Ok(PidStatus::Running(pid))
+    } else {
+        Ok(PidStatus::Stale(pid))
+    };
+}
+
+/// Removes the PID file and socket of a supervisor which is no longer running from
+/// `working_dir`, as well as a socket no server is listening on anymore. Returns whether anything
+/// was removed. Nothing is removed while a supervisor holds the lock on `working_dir`.
+pub fn clean_stale(working_dir: &Path) -> Result<bool, ExitValue> {
+    if is_locked(working_dir) {
+        return Ok(false);
+    }
+    return remove_stale(working_dir);
+}
+
+fn remove_stale(working_dir: &Path) -> Result<bool, ExitValue> {
+    let pid_file = working_dir.join(PID_FILE_NAME);
+    let sock_file = working_dir.join(SOCK_FILE_NAME);
+
+    let mut cleaned = false;
+    match read_pid_file(&pid_file) {
+        Ok(PidStatus::Running(_)) => return Ok(false),
+        Ok(PidStatus::Stale(pid)) => {
+            fs::remove_file(&pid_file).conv("Failed to delete stale PID file")?;
+            EventJournal::new(working_dir).record(Event::StalePid { pid: pid.as_raw() });
+            cleaned = true;
+        }
+        Ok(PidStatus::Missing) => {}
+        // An unreadable PID file can't tell us anything, so don't touch the socket either
+        Err(_) => return Ok(false),
+    }
+
+    // The server may still be running without its supervisor, or without paperd at all when it
+    // was started with the run command, so only remove the socket when nothing answers on it
+    if sock_file.exists() {
+        if let Err(e) = UnixStream::connect(&sock_file) {
+            if e.kind() == ErrorKind::ConnectionRefused || e.kind() == ErrorKind::NotFound {
+                let _ = fs::remove_file(&sock_file);
+                cleaned = true;
+            }
+        }
+    }
+
+    return Ok(cleaned);
+}
+
+/// Checks that `pid` is still the paperd process which wrote the PID file, not a process which
+/// got the same PID later, such as after a reboot.
+fn is_supervisor(pid: Pid, start: Option<u64>) -> bool {
+    // EPERM means the process exists, but belongs to another user
+    if let Err(Error::Sys(ESRCH)) = kill(pid, None) {
+        return false;
+    }
+
+    let proc_dir = Path::new("/proc").join(pid.as_raw().to_string());
+    if !proc_dir.is_dir() {
+        // Without procfs there's nothing else to check
+        return true;
+    }
+
+    if let Some(start) = start {
+        if start_time(pid.as_raw()) != Some(start) {
+            return false;
+        }
+    }
+
+    let cmdline = match fs::read(proc_dir.join("cmdline")) {
+        Ok(c) => c,
+        Err(_) => return true,
+    };
+    let arg0 =
+        String::from_utf8_lossy(cmdline.split(|b| *b == 0).next().unwrap_or(&[])).into_owned();
+    return is_paperd(&arg0);
+}
+
+/// Compares the program name of another process to the names this paperd was started with.
+fn is_paperd(arg0: &str) -> bool {
+    let name = match Path::new(arg0).file_name() {
+        Some(n) => n.to_os_string(),
+        None => return false,
+    };
+
+    let mut own_names = Vec::new();
+    if let Some(own_arg0) = env::args_os().next() {
+        own_names.push(PathBuf::from(own_arg0));
+    }
+    if let Ok(exe) = env::current_exe() {
+        own_names.push(exe);
+    }
+    return own_names
+        .iter()
+        .any(|own| own.file_name() == Some(name.as_os_str()));
+}
+
+/// How long the process with `pid` has been running.
+pub fn uptime(pid: Pid) -> Option<Duration> {
+    let start = start_time(pid.as_raw())?;
+    let ticks = sysconf(SysconfVar::CLK_TCK).ok()??;
+    let boot_uptime = fs::read_to_string("/proc/uptime").ok()?;
+    let boot_uptime = boot_uptime.split_whitespace().next()?.parse::<f64>().ok()?;
+    let started = start as f64 / ticks as f64;
+    return Some(Duration::from_secs_f64((boot_uptime - started).max(0.0)));
+}
+
+/// The time the process started after boot, in clock ticks, from /proc/<pid>/stat.
+fn start_time(pid: i32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name in the second field may contain spaces and parentheses, the fields after
+    // it start after the last parenthesis. starttime is the 22nd field.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    return rest.split_whitespace().nth(19)?.parse::<u64>().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn tests_lock_without_taking_it() {
+        let dir = env::temp_dir().join(format!("paperd-lock-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert!(!is_locked(&dir));
+
+        let lock = InstanceLock::acquire(&dir);
+        assert!(lock.is_ok());
+        assert!(is_locked(&dir));
+        drop(lock);
+        assert!(!is_locked(&dir));
+
+        // Testing for the lock mustn't get in the way of a supervisor taking it
+        let lock = InstanceLock::acquire(&dir);
+        assert!(lock.is_ok());
+        drop(lock);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn second_acquire_fails_while_held() {
+        let dir = env::temp_dir().join(format!("paperd-lock-held-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let first = InstanceLock::acquire(&dir);
+        assert!(first.is_ok());
+        // A lock taken through another open of the lock file conflicts, even within one process
+        assert!(InstanceLock::acquire(&dir).is_err());
+
+        drop(first);
+        assert!(InstanceLock::acquire(&dir).is_ok());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
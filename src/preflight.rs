@@ -0,0 +1,292 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::identity::Identity;
+use crate::memory::{parse_heap_size, MemoryInfo, MB};
+use crate::properties::{read_properties, PROPERTIES_FILE_NAME};
+use crate::registry::{instance_state, load_registry};
+use crate::util::ExitValue;
+use nix::sys::statvfs::statvfs;
+use nix::unistd::geteuid;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+
+/// Less free disk space than this and the server may fail to save its worlds.
+const MIN_FREE_SPACE: u64 = 1024 * MB;
+
+const DEFAULT_SERVER_PORT: u16 = 25565;
+const DEFAULT_RCON_PORT: u16 = 25575;
+const UNPRIVILEGED_PORT_START_FILE: &'static str = "/proc/sys/net/ipv4/ip_unprivileged_port_start";
+const DEFAULT_UNPRIVILEGED_PORT_START: u16 = 1024;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Transport {
+    Tcp,
+    Udp,
+}
+
+/// A port the server listens on, and the property in server.properties it is set with.
+struct ServerPort {
+    property: &'static str,
+    port: u16,
+    transport: Transport,
+}
+
+/// Checks the problems which would make the server fail right after starting, before paperd goes
+/// to the trouble of starting it: ports which are taken, a full disk, and a heap which doesn't fit
+/// in memory. Every problem found is reported, not only the first.
+pub fn preflight(
+    working_dir: &Path,
+    jvm_args: &[String],
+    identity: Option<&Identity>,
+    memory_max: Option<u64>,
+) -> Result<(), ExitValue> {
+    let mut problems = Vec::new();
+    check_ports(working_dir, identity, &mut problems);
+    check_disk_space(working_dir, &mut problems);
+    check_heap(jvm_args, memory_max, &mut problems);
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("Pre-flight check failed: {}", problem);
+    }
+    eprintln!("Fix the problems above, or use --skip-preflight to start the server anyway.");
+    return Err(ExitValue::Code(1));
+}
+
+fn check_ports(working_dir: &Path, identity: Option<&Identity>, problems: &mut Vec<String>) {
+    // Before the server's first start there is no server.properties, and it uses the defaults
+    let properties = read_properties(working_dir).unwrap_or_default();
+    let ports = match server_ports(&properties) {
+        Ok(p) => p,
+        Err(problem) => {
+            problems.push(problem);
+            return;
+        }
+    };
+
+    let host = properties
+        .get("server-ip")
+        .map(|ip| ip.trim())
+        .filter(|ip| !ip.is_empty());
+    let addr = match (host.unwrap_or("0.0.0.0"), 0)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+    {
+        Some(a) => a,
+        None => {
+            problems.push(format!(
+                "server-ip {} in {} can't be resolved. Set it to an address of this machine, or \
+                 leave it empty to listen on all of them.",
+                host.unwrap_or_default(),
+                PROPERTIES_FILE_NAME
+            ));
+            return;
+        }
+    };
+
+    // The server binds its ports as the user it runs as, which may not be allowed to bind ports
+    // paperd can
+    let is_root = identity.map_or_else(|| geteuid().is_root(), |i| i.uid.is_root());
+    let unprivileged_start = fs::read_to_string(UNPRIVILEGED_PORT_START_FILE)
+        .ok()
+        .and_then(|text| text.trim().parse::<u16>().ok())
+        .unwrap_or(DEFAULT_UNPRIVILEGED_PORT_START);
+
+    for (i, port) in ports.iter().enumerate() {
+        if let Some(other) = ports[..i]
+            .iter()
+            .find(|p| p.port == port.port && p.transport == port.transport)
+        {
+            problems.push(format!(
+                "{} and {} in {} are both {}. Give them different ports.",
+                other.property, port.property, PROPERTIES_FILE_NAME, port.port
+            ));
+            continue;
+        }
+        if !is_root && port.port < unprivileged_start {
+            problems.push(format!(
+                "Port {} ({} in {}) can only be used by root. Choose a port of {} or higher.",
+                port.port, port.property, PROPERTIES_FILE_NAME, unprivileged_start
+            ));
+            continue;
+        }
+
+        let addr = SocketAddr::new(addr.ip(), port.port);
+        let result = match port.transport {
+            Transport::Tcp => TcpListener::bind(addr).map(|_| ()),
+            Transport::Udp => UdpSocket::bind(addr).map(|_| ()),
+        };
+        match result {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::AddrInUse => {
+                let owner = match port_owner(working_dir, port) {
+                    Some(name) => format!(" by the registered instance {}", name),
+                    None => String::new(),
+                };
+                problems.push(format!(
+                    "Port {} ({} in {}) is already in use{}. Stop whatever is using it, or choose \
+                     another port.",
+                    port.port, port.property, PROPERTIES_FILE_NAME, owner
+                ));
+            }
+            Err(ref e) if e.kind() == ErrorKind::AddrNotAvailable => {
+                problems.push(format!(
+                    "server-ip {} in {} is not an address of this machine. Set it to one of its \
+                     addresses, or leave it empty to listen on all of them.",
+                    host.unwrap_or_default(),
+                    PROPERTIES_FILE_NAME
+                ));
+                return;
+            }
+            Err(e) => problems.push(format!(
+                "Failed to bind port {} ({} in {}): {}",
+                port.port, port.property, PROPERTIES_FILE_NAME, e
+            )),
+        }
+    }
+}
+
+/// The ports the server listens on with the given properties: the game port, and the query and
+/// RCON ports when those are enabled.
+fn server_ports(properties: &BTreeMap<String, String>) -> Result<Vec<ServerPort>, String> {
+    let server_port = port_property(properties, "server-port", DEFAULT_SERVER_PORT)?;
+    let mut ports = vec![ServerPort {
+        property: "server-port",
+        port: server_port,
+        transport: Transport::Tcp,
+    }];
+    if is_enabled(properties, "enable-query") {
+        ports.push(ServerPort {
+            property: "query.port",
+            port: port_property(properties, "query.port", server_port)?,
+            transport: Transport::Udp,
+        });
+    }
+    if is_enabled(properties, "enable-rcon") {
+        ports.push(ServerPort {
+            property: "rcon.port",
+            port: port_property(properties, "rcon.port", DEFAULT_RCON_PORT)?,
+            transport: Transport::Tcp,
+        });
+    }
+    return Ok(ports);
+}
+
+fn port_property(
+    properties: &BTreeMap<String, String>,
+    key: &str,
+    default: u16,
+) -> Result<u16, String> {
+    return match properties.get(key).map(|v| v.trim()) {
+        None | Some("") => Ok(default),
+        Some(text) => match text.parse::<u16>() {
+            Ok(port) if port > 0 => Ok(port),
+            _ => Err(format!(
+                "{} in {} is not a valid port: {}. Use a number from 1 to 65535.",
+                key, PROPERTIES_FILE_NAME, text
+            )),
+        },
+    };
+}
+
+fn is_enabled(properties: &BTreeMap<String, String>, key: &str) -> bool {
+    return properties.get(key).map_or(false, |v| v.trim() == "true");
+}
+
+/// The running registered instance, other than the one in `working_dir`, which is set up to use
+/// `port`.
+fn port_owner(working_dir: &Path, port: &ServerPort) -> Option<String> {
+    let working_dir = fs::canonicalize(working_dir).ok();
+    return load_registry()
+        .ok()?
+        .into_iter()
+        .map(|(_, instance)| instance)
+        .find(|instance| {
+            fs::canonicalize(&instance.working_dir).ok() != working_dir
+                && instance_state(instance, false).0 == "running"
+                && read_properties(&instance.working_dir)
+                    .and_then(|p| server_ports(&p).ok())
+                    .map_or(false, |ports| {
+                        ports
+                            .iter()
+                            .any(|p| p.port == port.port && p.transport == port.transport)
+                    })
+        })
+        .map(|instance| instance.name);
+}
+
+fn check_disk_space(working_dir: &Path, problems: &mut Vec<String>) {
+    let stat = match statvfs(working_dir) {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let free = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+    if free < MIN_FREE_SPACE {
+        problems.push(format!(
+            "Only {} MB of disk space is free for {}, the server needs at least {} MB to save its \
+             worlds and write its logs. Free up some space on the disk.",
+            free / MB,
+            working_dir.to_string_lossy(),
+            MIN_FREE_SPACE / MB
+        ));
+    }
+}
+
+fn check_heap(jvm_args: &[String], memory_max: Option<u64>, problems: &mut Vec<String>) {
+    // The JVM uses the last -Xmx it is given
+    let (text, heap) = match jvm_args
+        .iter()
+        .rev()
+        .find(|arg| arg.starts_with("-Xmx"))
+        .and_then(|arg| parse_heap_size(&arg[4..]).map(|bytes| (&arg[4..], bytes)))
+    {
+        Some(h) => h,
+        None => return,
+    };
+
+    let mut memory = MemoryInfo::detect();
+    if let Some(memory_max) = memory_max {
+        memory.cap(memory_max);
+    }
+
+    if let Some(limit) = memory.limit() {
+        if heap > limit {
+            problems.push(format!(
+                "The heap of {} is larger than the {} MB of memory the server may use. Give it a \
+                 smaller heap, such as with --default-args.",
+                text,
+                limit / MB
+            ));
+            return;
+        }
+    }
+    if let Some(available) = memory.host_available {
+        if heap > available {
+            problems.push(format!(
+                "The heap of {} is larger than the {} MB of memory which is available right now. \
+                 Stop other programs to free up memory, or give the server a smaller heap.",
+                text,
+                available / MB
+            ));
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+pub const DEFAULT_PRESET: &'static str = "aikar-g1";
+
+pub const PRESET_NAMES: &[&str] = &[
+    "aikar-g1",
+    "zgc",
+    "generational-zgc",
+    "shenandoah",
+    "minimal",
+];
+
+/// A JVM flag, only passed to Java versions up to and including `max`.
+struct Flag {
+    arg: &'static str,
+    max: u32,
+}
+
+const fn flag(arg: &'static str) -> Flag {
+    return Flag { arg, max: u32::MAX };
+}
+
+const fn flag_until(arg: &'static str, max: u32) -> Flag {
+    return Flag { arg, max };
+}
+
+struct Preset {
+    name: &'static str,
+    /// The oldest Java version which supports the preset's garbage collector
+    min_java: u32,
+    /// The preset to use instead on older Java versions
+    fallback: Option<&'static str>,
+    flags: &'static [Flag],
+}
+
+const PRESETS: &[Preset] = &[
+    // https://aikar.co/2018/07/02/tuning-the-jvm-g1gc-garbage-collector-flags-for-minecraft/
+    Preset {
+        name: "aikar-g1",
+        min_java: 0,
+        fallback: None,
+        flags: &[
+            flag("-XX:+UseG1GC"),
+            flag("-XX:+UnlockExperimentalVMOptions"),
+            flag("-XX:MaxGCPauseMillis=100"),
+            flag("-XX:+DisableExplicitGC"),
+            flag("-XX:TargetSurvivorRatio=90"),
+            flag("-XX:G1NewSizePercent=50"),
+            flag("-XX:G1MaxNewSizePercent=80"),
+            flag("-XX:G1MixedGCLiveThresholdPercent=35"),
+            flag("-XX:+AlwaysPreTouch"),
+            flag("-XX:+ParallelRefProcEnabled"),
+            flag("-Dusing.aikars.flags=mcflags.emc.gs"),
+        ],
+    },
+    // ZGC was experimental until Java 15
+    Preset {
+        name: "zgc",
+        min_java: 11,
+        fallback: Some("aikar-g1"),
+        flags: &[
+            flag_until("-XX:+UnlockExperimentalVMOptions", 14),
+            flag("-XX:+UseZGC"),
+            flag("-XX:+DisableExplicitGC"),
+            flag("-XX:+AlwaysPreTouch"),
+        ],
+    },
+    // Generational mode became the only mode in Java 24, which removed the flag
+    Preset {
+        name: "generational-zgc",
+        min_java: 21,
+        fallback: Some("zgc"),
+        flags: &[
+            flag("-XX:+UseZGC"),
+            flag_until("-XX:+ZGenerational", 23),
+            flag("-XX:+DisableExplicitGC"),
+            flag("-XX:+AlwaysPreTouch"),
+        ],
+    },
+    // Shenandoah was experimental until Java 15
+    Preset {
+        name: "shenandoah",
+        min_java: 12,
+        fallback: Some("aikar-g1"),
+        flags: &[
+            flag_until("-XX:+UnlockExperimentalVMOptions", 14),
+            flag("-XX:+UseShenandoahGC"),
+            flag("-XX:+DisableExplicitGC"),
+            flag("-XX:+AlwaysPreTouch"),
+        ],
+    },
+    Preset {
+        name: "minimal",
+        min_java: 0,
+        fallback: None,
+        flags: &[],
+    },
+];
+
+/// Returns the flags of the named preset which are supported by the given Java version. When the
+/// Java version doesn't support the preset at all, its fallback preset is used instead. When the
+/// version is unknown all of the preset's flags are returned.
+pub fn preset_flags(name: &str, java_version: Option<u32>) -> Option<Vec<String>> {
+    let mut preset = PRESETS.iter().find(|p| p.name == name)?;
+
+    if let Some(version) = java_version {
+        while version < preset.min_java {
+            let fallback = preset.fallback?;
+            eprintln!(
+                "Warning: Java {} does not support the {} preset, using {} instead",
+                version, preset.name, fallback
+            );
+            preset = PRESETS.iter().find(|p| p.name == fallback)?;
+        }
+    }
+
+    return Some(
+        preset
+            .flags
+            .iter()
+            .filter(|f| java_version.map_or(true, |v| v <= f.max))
+            .map(|f| f.arg.to_string())
+            .collect(),
+    );
+}
@@ -0,0 +1,146 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+
+pub const PROPERTIES_FILE_NAME: &'static str = "server.properties";
+
+/// Reads the server.properties file in `working_dir`. The server writes it with Java's
+/// `Properties`, so this only needs to understand what that writes: one `key=value` per line, `#`
+/// comments and backslash escapes.
+pub fn read_properties(working_dir: &Path) -> Option<BTreeMap<String, String>> {
+    let text = fs::read_to_string(working_dir.join(PROPERTIES_FILE_NAME)).ok()?;
+
+    let mut properties = BTreeMap::new();
+    for line in text.lines() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+            continue;
+        }
+        if let Some((key, value)) = split_property(line) {
+            properties.insert(unescape(key), unescape(value.trim_start()));
+        }
+    }
+    return Some(properties);
+}
+
+/// Sets properties in the server.properties file in `working_dir`, creating it if needed. Lines
+/// of properties which are already set are replaced in place, everything else in the file is kept
+/// as it is.
+pub fn set_properties(working_dir: &Path, values: &[(&str, &str)]) -> io::Result<()> {
+    let path = working_dir.join(PROPERTIES_FILE_NAME);
+    let text = match fs::read_to_string(&path) {
+        Ok(t) => t,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut remaining: Vec<&(&str, &str)> = values.iter().collect();
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        let key = split_property(trimmed)
+            .filter(|_| !trimmed.starts_with('#') && !trimmed.starts_with('!'))
+            .map(|(key, _)| unescape(key));
+        match remaining
+            .iter()
+            .position(|(k, _)| Some(k.to_string()) == key)
+        {
+            Some(i) => {
+                let (key, value) = remaining.remove(i);
+                lines.push(format!("{}={}", key, escape(value)));
+            }
+            None => lines.push(line.to_string()),
+        }
+    }
+    for (key, value) in remaining {
+        lines.push(format!("{}={}", key, escape(value)));
+    }
+
+    let mut text = lines.join("\n");
+    text.push('\n');
+    return fs::write(&path, text);
+}
+
+/// Splits a line at the first `=` or `:` which isn't escaped.
+fn split_property(line: &str) -> Option<(&str, &str)> {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '=' | ':' => return Some((line[..i].trim_end(), &line[i + 1..])),
+            _ => {}
+        }
+    }
+    return None;
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+                if let Some(c) = u32::from_str_radix(&code, 16)
+                    .ok()
+                    .and_then(std::char::from_u32)
+                {
+                    result.push(c);
+                }
+            }
+            Some(c) => result.push(c),
+            None => {}
+        }
+    }
+    return result;
+}
+
+/// Escapes a value the way Java's `Properties` does, so the server reads it back unchanged.
+fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for (i, c) in text.chars().enumerate() {
+        match c {
+            ' ' if i == 0 => result.push_str("\\ "),
+            '\\' | '=' | ':' | '#' | '!' => {
+                result.push('\\');
+                result.push(c);
+            }
+            '\t' => result.push_str("\\t"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            c if !(' '..='~').contains(&c) => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    result.push_str(&format!("\\u{:04X}", unit));
+                }
+            }
+            c => result.push(c),
+        }
+    }
+    return result;
+}
@@ -14,14 +14,66 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use crate::messaging::MessageSocket;
-use crate::util::{ExitError, ExitValue};
+use crate::util::{get_sock_from_file_direct, ExitError, ExitValue};
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io::Read;
 use std::path::Path;
+use std::time::{Duration, Instant};
 use zip::ZipArchive;
 
-const PROTOCOL_VERSION: i64 = 1;
+/// The oldest and newest protocol versions this paperd speaks.
+pub const MIN_PROTOCOL_VERSION: i64 = 1;
+pub const MAX_PROTOCOL_VERSION: i64 = 1;
+
+// What a server can do, each command checks the server has the capabilities it needs
+pub const CAPABILITY_STOP: &'static str = "stop";
+pub const CAPABILITY_RESTART: &'static str = "restart";
+pub const CAPABILITY_STATUS: &'static str = "status";
+pub const CAPABILITY_SEND: &'static str = "send";
+pub const CAPABILITY_TIMINGS: &'static str = "timings";
+pub const CAPABILITY_CONSOLE: &'static str = "console";
+
+/// The capabilities of servers which answer with only their protocol version. They were all
+/// released before capabilities were, and speak protocol version 1.
+const LEGACY_CAPABILITIES: &[&str] = &[
+    CAPABILITY_STOP,
+    CAPABILITY_RESTART,
+    CAPABILITY_STATUS,
+    CAPABILITY_SEND,
+    CAPABILITY_TIMINGS,
+    CAPABILITY_CONSOLE,
+];
+
+/// What the server answered to the protocol version message.
+pub struct ServerProtocol {
+    /// The version the server speaks with paperd
+    pub version: i64,
+    pub min_version: i64,
+    pub max_version: i64,
+    pub capabilities: Vec<String>,
+}
+
+impl ServerProtocol {
+    /// Whether paperd speaks the version the server chose.
+    pub fn is_compatible(&self) -> bool {
+        return self.version >= MIN_PROTOCOL_VERSION && self.version <= MAX_PROTOCOL_VERSION;
+    }
+
+    /// The capabilities out of `required` the server doesn't have.
+    pub fn missing<'a>(&self, required: &[&'a str]) -> Vec<&'a str> {
+        return required
+            .iter()
+            .filter(|c| !self.capabilities.iter().any(|s| s == *c))
+            .copied()
+            .collect();
+    }
+
+    /// The versions the server speaks, like `1` or `1-3`.
+    pub fn versions_text(&self) -> String {
+        return versions_text(self.min_version, self.max_version);
+    }
+}
 
 pub fn check_jar_protocol<P: AsRef<Path>>(path: P) -> Result<(), ExitValue> {
     let jar_path = path.as_ref();
@@ -62,23 +114,32 @@ pub fn check_jar_protocol<P: AsRef<Path>>(path: P) -> Result<(), ExitValue> {
         return Err(ExitValue::Code(1));
     }
 
-    return match buffer.trim().parse::<i64>() {
-        Ok(protocol) => {
-            if protocol != PROTOCOL_VERSION {
+    // Either a single version, or the oldest and newest versions the server speaks, like 1-3
+    let text = buffer.trim();
+    let versions = match text.find('-') {
+        Some(i) => (
+            text[..i].trim().parse::<i64>(),
+            text[i + 1..].trim().parse::<i64>(),
+        ),
+        None => (text.parse::<i64>(), text.parse::<i64>()),
+    };
+    return match versions {
+        (Ok(min), Ok(max)) => {
+            if min > MAX_PROTOCOL_VERSION || max < MIN_PROTOCOL_VERSION {
                 eprintln!(
                     "Protocol versions of paperd and jar file({}) do not match. paperd \
-                     protocol version: {}; jar protocol version: {}. Please use a version \
+                     protocol versions: {}; jar protocol versions: {}. Please use a version \
                      of paperd compatible with this build of Paper.",
                     jar_path.to_string_lossy(),
-                    PROTOCOL_VERSION,
-                    protocol
+                    versions_text(MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION),
+                    versions_text(min, max)
                 );
                 Err(ExitValue::Code(1))
             } else {
                 Ok(())
             }
         }
-        Err(e) => {
+        (Err(e), _) | (_, Err(e)) => {
             eprintln!(
                 "Failed to read protocol version file in jar {}: {}",
                 jar_path.to_string_lossy(),
@@ -89,32 +150,117 @@ pub fn check_jar_protocol<P: AsRef<Path>>(path: P) -> Result<(), ExitValue> {
     };
 }
 
-pub fn check_protocol(sock: &MessageSocket) -> Result<(), ExitValue> {
-    let message = ProtocolVersionMessage {};
-    sock.send_message(&message)?;
+/// Agrees on a protocol version with the server and checks it has all of the `required`
+/// capabilities. Returns what the server answered, so it can be shown.
+pub fn check_protocol(
+    sock: &MessageSocket,
+    required: &[&str],
+) -> Result<ServerProtocol, ExitValue> {
+    sock.send_message(&ProtocolVersionMessage::new())?;
 
-    let res = sock.receive_message::<ProtocolVersionMessageResponse>()?;
+    let protocol = sock
+        .receive_message::<ProtocolVersionMessageResponse>()?
+        .into_protocol();
 
-    if res.protocol_version != PROTOCOL_VERSION {
+    if !protocol.is_compatible() {
         eprintln!(
             "The protocol versions of paperd and the specified server do not match. \
-             paperd protocol version: {}; server protocol version: {}. Please use a version \
-             of paperd compatible with this build of Paper.",
-            PROTOCOL_VERSION, res.protocol_version
+             paperd protocol versions: {}; server protocol version: {} (server speaks {}). \
+             Please use a version of paperd compatible with this build of Paper.",
+            versions_text(MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION),
+            protocol.version,
+            protocol.versions_text()
+        );
+        return Err(ExitValue::Code(1));
+    }
+
+    let missing = protocol.missing(required);
+    if !missing.is_empty() {
+        eprintln!(
+            "The server does not support this command, it is missing the {} capability of the \
+             paperd protocol. Please update Paper to use it.",
+            missing.join(", ")
         );
         return Err(ExitValue::Code(1));
     }
 
-    return Ok(());
+    return Ok(protocol);
+}
+
+/// Checks whether the server answers a protocol version request within `timeout`, without printing
+/// any errors.
+pub fn ping_server<P: AsRef<Path>>(sock_file: P, timeout: Duration) -> bool {
+    return server_protocol(sock_file, timeout).is_some();
+}
+
+/// Asks the server for its protocol versions and capabilities, giving up after `timeout`, without
+/// printing any errors.
+pub fn server_protocol<P: AsRef<Path>>(sock_file: P, timeout: Duration) -> Option<ServerProtocol> {
+    let mut sock = get_sock_from_file_direct(sock_file).ok()?;
+    sock.print_err = false;
+    return request_protocol(&sock, Instant::now() + timeout);
+}
+
+/// Asks the server connected to `sock` for its protocol versions and capabilities, giving up at
+/// `deadline`.
+pub fn request_protocol(sock: &MessageSocket, deadline: Instant) -> Option<ServerProtocol> {
+    sock.send_message(&ProtocolVersionMessage::new()).ok()?;
+    return sock
+        .receive_loop::<ProtocolVersionMessageResponse, _>(|| Instant::now() < deadline)
+        .ok()
+        .map(|res| res.into_protocol());
+}
+
+fn versions_text(min: i64, max: i64) -> String {
+    if min == max {
+        return min.to_string();
+    }
+    return format!("{}-{}", min, max);
 }
 
 // Request
+// Servers from before version ranges ignore the fields, the message type has to stay the same
 #[derive(Serialize)]
-pub struct ProtocolVersionMessage {}
+pub struct ProtocolVersionMessage {
+    #[serde(rename = "minProtocolVersion")]
+    min_protocol_version: i64,
+    #[serde(rename = "maxProtocolVersion")]
+    max_protocol_version: i64,
+}
+
+impl ProtocolVersionMessage {
+    fn new() -> Self {
+        return ProtocolVersionMessage {
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            max_protocol_version: MAX_PROTOCOL_VERSION,
+        };
+    }
+}
 
 // Response
 #[derive(Serialize, Deserialize)]
 struct ProtocolVersionMessageResponse {
     #[serde(rename = "protocolVersion")]
     protocol_version: i64,
+    #[serde(rename = "minProtocolVersion")]
+    min_protocol_version: Option<i64>,
+    #[serde(rename = "maxProtocolVersion")]
+    max_protocol_version: Option<i64>,
+    #[serde(rename = "capabilities")]
+    capabilities: Option<Vec<String>>,
+}
+
+impl ProtocolVersionMessageResponse {
+    fn into_protocol(self) -> ServerProtocol {
+        let version = self.protocol_version;
+        return ServerProtocol {
+            version,
+            min_version: self.min_protocol_version.unwrap_or(version),
+            max_version: self.max_protocol_version.unwrap_or(version),
+            capabilities: match self.capabilities {
+                Some(c) => c,
+                None => LEGACY_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            },
+        };
+    }
 }
@@ -0,0 +1,263 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::expand_text;
+use crate::pid::{is_locked, read_pid_file, uptime, PidStatus};
+use crate::protocol::server_protocol;
+use crate::runner::{PID_FILE_NAME, SOCK_FILE_NAME};
+use crate::util::{format_duration, print_table, ExitError, ExitValue};
+use clap::ArgMatches;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const REGISTRY_FILE_NAME: &'static str = "instances.toml";
+const SYSTEM_REGISTRY_DIR: &'static str = "/etc/paperd";
+
+/// How long `paperd list` waits for each server to answer
+const LIST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A server registered by name, so commands can find it with `-n` from any directory.
+pub struct Instance {
+    pub name: String,
+    pub working_dir: PathBuf,
+    pub config_file: Option<PathBuf>,
+    /// Groups of instances commands can be sent to at once
+    pub tags: Vec<String>,
+}
+
+impl Instance {
+    pub fn sock_file(&self) -> PathBuf {
+        return self.working_dir.join(SOCK_FILE_NAME);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct InstanceEntry {
+    #[serde(rename = "workingDir")]
+    working_dir: String,
+    #[serde(rename = "configFile", skip_serializing_if = "Option::is_none")]
+    config_file: Option<String>,
+    #[serde(rename = "tags", skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+}
+
+pub fn list(_: &ArgMatches) -> Result<(), ExitValue> {
+    let instances = load_registry()?;
+    if instances.is_empty() {
+        println!(
+            "No instances registered. Register them by name in {}.",
+            registry_files_text()
+        );
+        return Ok(());
+    }
+
+    let rows: Vec<Vec<String>> = instances.values().map(instance_row).collect();
+    print_table(
+        &["NAME", "STATE", "PID", "UPTIME", "PROTOCOL", "DIRECTORY"],
+        &rows,
+    );
+    return Ok(());
+}
+
+fn instance_row(instance: &Instance) -> Vec<String> {
+    let sock_file = instance.sock_file();
+    let protocol = if sock_file.exists() {
+        server_protocol(&sock_file, LIST_TIMEOUT)
+    } else {
+        None
+    };
+
+    let (state, pid) = instance_state(instance, protocol.is_some());
+
+    let protocol = match protocol {
+        Some(protocol) if protocol.is_compatible() => "compatible".to_string(),
+        Some(protocol) => format!("incompatible ({})", protocol.versions_text()),
+        None if sock_file.exists() => "no answer".to_string(),
+        None => "-".to_string(),
+    };
+
+    return vec![
+        instance.name.clone(),
+        state.to_string(),
+        pid.map_or_else(|| "-".to_string(), |p| p.to_string()),
+        pid.and_then(uptime)
+            .map_or_else(|| "-".to_string(), format_duration),
+        protocol,
+        instance.working_dir.to_string_lossy().into_owned(),
+    ];
+}
+
+/// Tells whether the instance is running from its PID and lock files, along with the PID of its
+/// supervisor. `answered` is whether the server answered on its socket.
+pub fn instance_state(instance: &Instance, answered: bool) -> (&'static str, Option<Pid>) {
+    let pid_status =
+        read_pid_file(&instance.working_dir.join(PID_FILE_NAME)).unwrap_or(PidStatus::Missing);
+    return match pid_status {
+        PidStatus::Running(pid) => ("running", Some(pid)),
+        // Servers started with the run command don't have a PID file
+        _ if answered || is_locked(&instance.working_dir) => ("running", None),
+        PidStatus::Stale(_) => ("stale", None),
+        PidStatus::Missing => ("stopped", None),
+    };
+}
+
+/// The instance chosen with `-n`, if any.
+pub fn selected_instance(sub_m: &ArgMatches) -> Result<Option<Instance>, ExitValue> {
+    return match sub_m.value_of("INSTANCE") {
+        Some(name) => find_instance(name).map(Some),
+        None => Ok(None),
+    };
+}
+
+pub fn find_instance(name: &str) -> Result<Instance, ExitValue> {
+    return match load_registry()?.remove(name) {
+        Some(instance) => Ok(instance),
+        None => {
+            eprintln!(
+                "No instance named {} is registered in {}",
+                name,
+                registry_files_text()
+            );
+            Err(ExitValue::Code(1))
+        }
+    };
+}
+
+/// Reads all registered instances. The system-wide registry is read first, so instances in the
+/// user's own registry replace ones with the same name.
+pub fn load_registry() -> Result<BTreeMap<String, Instance>, ExitValue> {
+    let mut instances = BTreeMap::new();
+    for file in registry_files() {
+        if !file.is_file() {
+            continue;
+        }
+        let text = fs::read_to_string(&file).conv(format!(
+            "Failed to read instance registry {}",
+            file.to_string_lossy()
+        ))?;
+        let entries = match toml::from_str::<BTreeMap<String, InstanceEntry>>(&text) {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!(
+                    "Failed to parse instance registry {}: {}",
+                    file.to_string_lossy(),
+                    e
+                );
+                return Err(ExitValue::Code(1));
+            }
+        };
+
+        // Paths are relative to the registry they're in
+        let dir = file.parent().unwrap_or_else(|| Path::new("/"));
+        for (name, entry) in entries {
+            let working_dir = dir.join(expand_text(&entry.working_dir)?);
+            let config_file = match entry.config_file {
+                Some(f) => Some(dir.join(expand_text(&f)?)),
+                None => None,
+            };
+            instances.insert(
+                name.clone(),
+                Instance {
+                    name,
+                    working_dir,
+                    config_file,
+                    tags: entry.tags.unwrap_or_default(),
+                },
+            );
+        }
+    }
+    return Ok(instances);
+}
+
+/// Adds an instance to the user's own registry, creating it if needed. Returns the registry file.
+pub fn register(
+    name: &str,
+    working_dir: &Path,
+    config_file: Option<&Path>,
+) -> Result<PathBuf, ExitValue> {
+    let file = match user_registry_file() {
+        Some(f) => f,
+        None => {
+            eprintln!("Failed to find the registry, neither XDG_CONFIG_HOME nor HOME are set");
+            return Err(ExitValue::Code(1));
+        }
+    };
+
+    let mut entries = BTreeMap::new();
+    entries.insert(
+        name.to_string(),
+        InstanceEntry {
+            working_dir: working_dir.to_string_lossy().into_owned(),
+            config_file: config_file.map(|f| f.to_string_lossy().into_owned()),
+            tags: None,
+        },
+    );
+    let entry = match toml::to_string(&entries) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Failed to write instance {}: {}", name, e);
+            return Err(ExitValue::Code(1));
+        }
+    };
+
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir).conv(format!(
+            "Failed to create directory {}",
+            dir.to_string_lossy()
+        ))?;
+    }
+    // Append the entry, so comments and formatting in the file are kept
+    let mut text = fs::read_to_string(&file).unwrap_or_default();
+    if !text.is_empty() {
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push('\n');
+    }
+    text.push_str(&entry);
+    fs::write(&file, text).conv(format!(
+        "Failed to write instance registry {}",
+        file.to_string_lossy()
+    ))?;
+    return Ok(file);
+}
+
+/// The system-wide registry followed by the user's own.
+fn registry_files() -> Vec<PathBuf> {
+    let mut files = vec![Path::new(SYSTEM_REGISTRY_DIR).join(REGISTRY_FILE_NAME)];
+    files.extend(user_registry_file());
+    return files;
+}
+
+fn user_registry_file() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+    return Some(config_dir.join("paperd").join(REGISTRY_FILE_NAME));
+}
+
+pub fn registry_files_text() -> String {
+    return registry_files()
+        .iter()
+        .map(|f| f.to_string_lossy().into_owned())
+        .collect::<Vec<String>>()
+        .join(" or ");
+}
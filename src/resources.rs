@@ -0,0 +1,280 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::util::{parse_size, ExitValue};
+use nix::libc;
+use nix::sched::{sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
+use std::cmp::max;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+const IOPRIO_CLASS_SHIFT: i32 = 13;
+const IOPRIO_WHO_PROCESS: i32 = 1;
+
+/// Scheduling classes for the server's disk I/O, see ioprio_set(2).
+#[derive(Clone, Copy)]
+pub enum IoClass {
+    Realtime(u8),
+    BestEffort(u8),
+    Idle,
+}
+
+impl IoClass {
+    fn ioprio(self) -> i32 {
+        return match self {
+            IoClass::Realtime(level) => 1 << IOPRIO_CLASS_SHIFT | level as i32,
+            IoClass::BestEffort(level) => 2 << IOPRIO_CLASS_SHIFT | level as i32,
+            IoClass::Idle => 3 << IOPRIO_CLASS_SHIFT,
+        };
+    }
+}
+
+/// Limits and scheduling settings for the server process. They are applied to the server process
+/// after it is forked and before the JVM is executed, so they never affect paperd itself.
+#[derive(Clone, Copy, Default)]
+pub struct ResourceControls {
+    /// RLIMIT_NOFILE, the number of files the server may have open
+    pub open_files: Option<libc::rlim_t>,
+    /// RLIMIT_CORE, the largest core dump the server may write
+    pub core_size: Option<libc::rlim_t>,
+    pub nice: Option<i32>,
+    pub io_class: Option<IoClass>,
+    pub cpus: Option<CpuSet>,
+}
+
+impl ResourceControls {
+    /// Raises the limits of paperd's own process where the server needs more, before paperd
+    /// switches from root to the user it runs the server as. That user isn't allowed to raise them
+    /// any more, but may still lower them for the server.
+    pub fn prepare(&self) -> Result<(), ExitValue> {
+        if let Some(open_files) = self.open_files {
+            raise_limit(libc::RLIMIT_NOFILE as i32, open_files, false, "open files")?;
+        }
+        if let Some(core_size) = self.core_size {
+            raise_limit(libc::RLIMIT_CORE as i32, core_size, false, "core dump size")?;
+        }
+        // Unprivileged processes may only lower their nice value down to 20 - RLIMIT_NICE
+        if let Some(nice) = self.nice.filter(|n| *n < 0) {
+            raise_limit(
+                libc::RLIMIT_NICE as i32,
+                (20 - nice) as libc::rlim_t,
+                true,
+                "nice",
+            )?;
+        }
+        return Ok(());
+    }
+
+    pub fn apply(&self, command: &mut Command) {
+        let controls = *self;
+        // Only async-signal-safe calls are allowed between fork and exec, which all of these are
+        unsafe {
+            command.pre_exec(move || controls.apply_to_self());
+        }
+    }
+
+    fn apply_to_self(&self) -> io::Result<()> {
+        if let Some(open_files) = self.open_files {
+            set_limit(libc::RLIMIT_NOFILE as i32, open_files)?;
+        }
+        if let Some(core_size) = self.core_size {
+            set_limit(libc::RLIMIT_CORE as i32, core_size)?;
+        }
+        if let Some(nice) = self.nice {
+            check(unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) })?;
+        }
+        if let Some(io_class) = self.io_class {
+            check(unsafe {
+                libc::syscall(
+                    libc::SYS_ioprio_set,
+                    IOPRIO_WHO_PROCESS,
+                    0,
+                    io_class.ioprio(),
+                ) as i32
+            })?;
+        }
+        if let Some(cpus) = &self.cpus {
+            if let Err(e) = sched_setaffinity(Pid::from_raw(0), cpus) {
+                return Err(io::Error::from_raw_os_error(
+                    e.as_errno().map_or(libc::EINVAL, |e| e as i32),
+                ));
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn set_limit(resource: i32, value: libc::rlim_t) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: value,
+        rlim_max: value,
+    };
+    return check(unsafe { libc::setrlimit(resource as _, &limit) });
+}
+
+/// Raises the hard limit of `resource` to `value` if it is lower, and the soft limit too if `soft`
+/// is set.
+fn raise_limit(
+    resource: i32,
+    value: libc::rlim_t,
+    soft: bool,
+    name: &str,
+) -> Result<(), ExitValue> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(resource as _, &mut limit) } != 0 {
+        return Ok(());
+    }
+    if limit.rlim_max >= value && (!soft || limit.rlim_cur >= value) {
+        return Ok(());
+    }
+
+    limit.rlim_max = max(limit.rlim_max, value);
+    if soft {
+        limit.rlim_cur = max(limit.rlim_cur, value);
+    }
+    if let Err(e) = check(unsafe { libc::setrlimit(resource as _, &limit) }) {
+        eprintln!(
+            "Failed to raise the {} limit to {}: {}. Raising limits requires starting paperd as \
+             root.",
+            name,
+            format_limit(value),
+            e
+        );
+        return Err(ExitValue::Code(1));
+    }
+    return Ok(());
+}
+
+fn check(result: i32) -> io::Result<()> {
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(());
+}
+
+fn format_limit(value: libc::rlim_t) -> String {
+    if value == libc::RLIM_INFINITY {
+        return "unlimited".to_string();
+    }
+    return value.to_string();
+}
+
+/// Parses a core dump size like 512m or 1G, or `unlimited`.
+pub fn parse_core_size(text: &str) -> Option<libc::rlim_t> {
+    if text == "unlimited" || text == "infinity" {
+        return Some(libc::RLIM_INFINITY);
+    }
+    return parse_size(text).map(|s| s as libc::rlim_t);
+}
+
+pub fn parse_nice(text: &str) -> Option<i32> {
+    return text.parse::<i32>().ok().filter(|n| *n >= -20 && *n <= 19);
+}
+
+/// Parses an I/O class like `idle`, `best-effort` or `realtime`, optionally followed by the
+/// priority within the class from 0 (highest) to 7, like `best-effort:2`.
+pub fn parse_io_class(text: &str) -> Option<IoClass> {
+    let mut parts = text.splitn(2, ':');
+    let class = parts.next()?;
+    let level = match parts.next() {
+        Some(level) => level.parse::<u8>().ok().filter(|l| *l <= 7)?,
+        None => 4,
+    };
+    return match class {
+        "realtime" | "rt" => Some(IoClass::Realtime(level)),
+        "best-effort" | "be" => Some(IoClass::BestEffort(level)),
+        "idle" => Some(IoClass::Idle),
+        _ => None,
+    };
+}
+
+/// Parses a list of CPUs like `0-3,6,8-9`.
+pub fn parse_cpu_list(text: &str) -> Option<CpuSet> {
+    let mut cpus = CpuSet::new();
+    for part in text.split(',') {
+        let part = part.trim();
+        let (first, last) = match part.find('-') {
+            Some(i) => (
+                part[..i].parse::<usize>().ok()?,
+                part[i + 1..].parse::<usize>().ok()?,
+            ),
+            None => {
+                let cpu = part.parse::<usize>().ok()?;
+                (cpu, cpu)
+            }
+        };
+        if first > last {
+            return None;
+        }
+        for cpu in first..=last {
+            cpus.set(cpu).ok()?;
+        }
+    }
+    return Some(cpus);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cpu_lists() {
+        let cpus = parse_cpu_list("0-3,6").unwrap();
+        for cpu in 0..8 {
+            let expected = cpu <= 3 || cpu == 6;
+            assert_eq!(cpus.is_set(cpu).unwrap(), expected, "cpu {}", cpu);
+        }
+        assert!(parse_cpu_list(" 1 , 2 ").unwrap().is_set(2).unwrap());
+    }
+
+    #[test]
+    fn rejects_invalid_cpu_lists() {
+        assert!(parse_cpu_list("").is_none());
+        assert!(parse_cpu_list("3-1").is_none());
+        assert!(parse_cpu_list("0,,1").is_none());
+        assert!(parse_cpu_list("a-b").is_none());
+        assert!(parse_cpu_list("100000").is_none());
+    }
+
+    #[test]
+    fn parses_io_classes() {
+        assert!(matches!(
+            parse_io_class("realtime"),
+            Some(IoClass::Realtime(4))
+        ));
+        assert!(matches!(parse_io_class("rt:0"), Some(IoClass::Realtime(0))));
+        assert!(matches!(
+            parse_io_class("be:7"),
+            Some(IoClass::BestEffort(7))
+        ));
+        assert!(matches!(
+            parse_io_class("best-effort"),
+            Some(IoClass::BestEffort(4))
+        ));
+        assert!(matches!(parse_io_class("idle"), Some(IoClass::Idle)));
+    }
+
+    #[test]
+    fn rejects_invalid_io_classes() {
+        assert!(parse_io_class("be:8").is_none());
+        assert!(parse_io_class("be:").is_none());
+        assert!(parse_io_class("fast").is_none());
+    }
+}
@@ -13,21 +13,31 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::broadcast::{broadcast, find_targets};
 use crate::log::{find_log_file, tail};
-use crate::protocol::check_protocol;
-use crate::util::{get_sock, ExitValue};
+use crate::protocol::{check_protocol, CAPABILITY_RESTART};
+use crate::util::{check_sock_file, get_sock_from_file, ExitValue};
+use crate::watchdog::request_stop;
 use clap::ArgMatches;
 use serde::Serialize;
+use std::path::Path;
 
 pub fn restart(sub_m: &ArgMatches) -> Result<(), ExitValue> {
-    let (sock, sock_file) = get_sock(sub_m)?;
-    check_protocol(&sock)?;
+    let targets = find_targets(sub_m)?;
+    if targets.len() > 1 {
+        let serial = sub_m.is_present("SERIAL");
+        return broadcast(targets, serial, "restarting", |target| {
+            return send_restart(check_sock_file(target.sock_file.clone())?);
+        });
+    }
 
-    let message = RestartMessage {};
+    let sock_file = match targets.into_iter().next() {
+        Some(target) => check_sock_file(target.sock_file)?,
+        None => return Err(ExitValue::Code(1)),
+    };
 
     println!("Sending restart request...");
-
-    sock.send_message(&message)?;
+    send_restart(&sock_file)?;
 
     if sub_m.is_present("TAIL") {
         let log_file = find_log_file(&sock_file)?;
@@ -37,6 +47,18 @@ pub fn restart(sub_m: &ArgMatches) -> Result<(), ExitValue> {
     return Ok(());
 }
 
+/// Asks the server to shut down and be restarted by paperd.
+pub fn send_restart<P: AsRef<Path>>(sock_file: P) -> Result<(), ExitValue> {
+    let sock = get_sock_from_file(&sock_file)?;
+    check_protocol(&sock, &[CAPABILITY_RESTART])?;
+
+    let message = RestartMessage {};
+    sock.send_message(&message)?;
+    request_stop(&sock_file);
+
+    return Ok(());
+}
+
 // Request
 #[derive(Serialize)]
 pub struct RestartMessage {}
@@ -13,45 +13,77 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::cgroup::{parse_cpu_max, Cgroup, CgroupConfig};
+use crate::config::{load_config, RunnerConfig};
 use crate::daemon::{run_daemon, Status};
+use crate::environment::{is_valid_name, read_env_file, ChildEnv};
+use crate::events::{Event, EventJournal, EVENTS_FILE_NAME};
+use crate::hooks::{Hook, HookContext, Hooks};
+use crate::identity::{check_not_root, Identity};
+use crate::java::{find_installs, java_install, java_version, prefer_java, required_java_version};
+use crate::keep_alive::{CrashTracker, Decision, RestartPolicy};
 use crate::log::{find_log_file, tail};
+use crate::memory::{format_heap, parse_heap_arg, percent_heap, HeapArg, MemoryInfo, MB, MIN_HEAP};
+use crate::output::{
+    capture, RotatingFile, StdoutLogConfig, DEFAULT_STDOUT_LOG_COUNT, DEFAULT_STDOUT_LOG_SIZE,
+};
+use crate::pid::{read_pid_file, write_pid_file, InstanceLock, PidStatus, LOCK_FILE_NAME};
+use crate::preflight::preflight;
+use crate::presets::{preset_flags, DEFAULT_PRESET, PRESET_NAMES};
 use crate::protocol::check_jar_protocol;
-use crate::util::{find_program, ExitError, ExitValue};
+use crate::registry::selected_instance;
+use crate::resources::{
+    parse_core_size, parse_cpu_list, parse_io_class, parse_nice, ResourceControls,
+};
+use crate::stop::stop_gracefully;
+use crate::systemd::{Monitor, Notifier};
+use crate::upgrade::{apply_staged, rollback, DEFAULT_ROLLBACK_WINDOW};
+use crate::util::{find_program, parse_duration_arg, parse_size, ExitError, ExitValue};
+use crate::watchdog::{Watchdog, WatchdogConfig, DEFAULT_WATCHDOG_FAILURES};
 use clap::ArgMatches;
-use nix::errno::Errno::ESRCH;
 use nix::sys::signal;
-use nix::sys::signal::kill;
 use nix::unistd::Pid;
-use nix::Error;
-use serde::Deserialize;
 use signal_hook::iterator::Signals;
 use signal_hook::{SIGABRT, SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGTRAP};
-use std::borrow::Cow;
-use std::cmp::{max, min};
+use std::cmp::max;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
-use std::fs::{canonicalize, File};
+use std::fs::canonicalize;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
-use std::process::{Child, Command};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::{env, fs, process, thread};
-use sys_info::mem_info;
 
 static JNI_LIB: &'static [u8] = include_bytes!(env!("PAPERD_JNI_LIB"));
 
 pub const SOCK_FILE_NAME: &'static str = "paper.sock";
 pub const PID_FILE_NAME: &'static str = "paper.pid";
+pub const EULA_FILE_NAME: &'static str = "eula.txt";
+pub const STDOUT_LOG_FILE_NAME: &'static str = "paperd-stdout.log";
 
-const STOP_EXIT_CODE: i32 = 13;
-const RESTART_EXIT_CODE: i32 = 27;
+const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub const STOP_EXIT_CODE: i32 = 13;
+pub const RESTART_EXIT_CODE: i32 = 27;
 
 pub fn start(sub_m: &ArgMatches) -> Result<(), ExitValue> {
-    let env = setup_java_env(sub_m)?;
+    let mut env = setup_java_env(sub_m)?;
+    // Held from here on, the daemon inherits it when forking
+    let lock = InstanceLock::acquire(&env.working_dir)?;
+    check_not_running(&env.working_dir, &lock)?;
+    prepare_identity(&env)?;
 
     check_jar_protocol(&env.jar_file)?;
+    if !sub_m.is_present("SKIP_PREFLIGHT") {
+        run_preflight(&env)?;
+    }
 
     if !check_eula(&env)? {
-        return run_server_foreground(&env);
+        return run_server_foreground(&mut env);
     }
 
     let mut lib_file = std::env::temp_dir();
@@ -69,9 +101,32 @@ pub fn start(sub_m: &ArgMatches) -> Result<(), ExitValue> {
             eprintln!("Failed to write JNI library to temp directory: {}", e);
             return Err(ExitValue::Code(1));
         }
+        // The server replaces the contents of this file, so it needs to be able to write to it
+        if let Some(identity) = &env.identity {
+            identity.give(&lib_file)?;
+        }
     }
 
-    match run_daemon() {
+    let cgroup = match &env.cgroup {
+        Some(config) => Some(config.create(&env.working_dir, env.identity.as_ref())?),
+        None => None,
+    };
+
+    // In the foreground paperd is usually run by a service manager, which keeps track of it instead
+    let foreground = sub_m.is_present("FOREGROUND");
+    let notifier = if foreground {
+        Notifier::from_env()
+    } else {
+        None
+    };
+
+    let daemon_status = if foreground {
+        Ok(Status::CONTINUE)
+    } else {
+        run_daemon()
+    };
+
+    match daemon_status {
         Ok(Status::QUIT(pid)) => {
             println!("Server starting in background, waiting for server to start...");
 
@@ -101,7 +156,10 @@ pub fn start(sub_m: &ArgMatches) -> Result<(), ExitValue> {
         Err(err) => return Err(err),
     }
 
-    let mut env = env;
+    if let Some(identity) = &env.identity {
+        identity.switch()?;
+    }
+
     env.args
         .push("-Dio.papermc.daemon.enabled=true".to_string());
     env.args.push(format!(
@@ -109,41 +167,201 @@ pub fn start(sub_m: &ArgMatches) -> Result<(), ExitValue> {
         lib_file.to_string_lossy()
     ));
 
+    // stdout and stderr were pointed at /dev/null when daemonizing, so the output of the server,
+    // the hooks and paperd itself goes to the stdout log instead. They all share one handle, so
+    // only one of them rotates it.
+    let log = if foreground {
+        None
+    } else {
+        Some(open_stdout_log(&env)?)
+    };
+
+    let keep_alive = sub_m.is_present("KEEP_ALIVE");
+    let mut crashes = CrashTracker::new(env.restart_policy.clone());
+    let mut restarts: u32 = 0;
+    let mut last_exit: Option<i32> = None;
+
     let mut result: i32;
     loop {
-        let child = start_process(&env)?;
-
         let pid = process::id();
 
         // Write pid file
         let pid_file = env.working_dir.join(PID_FILE_NAME);
         let pid_file = pid_file.as_path();
-        if let Err(_) = fs::write(pid_file, pid.to_string()) {
+        if let Err(_) = write_pid_file(pid_file, pid) {
+            result = 1;
+            break;
+        }
+
+        let upgraded = upgrade_jar(&mut env);
+
+        let context = HookContext {
+            restart_count: restarts,
+            exit_code: last_exit,
+            pid: None,
+        };
+        if !run_hook(&env, Hook::PreStart, &context, log.as_ref()) {
+            let _ = fs::remove_file(pid_file);
             result = 1;
             break;
         }
 
-        let signals = forward_signals(pid)?;
+        let oom_kills = cgroup.as_ref().map_or(0, |c| c.oom_kills());
+
+        let child = match start_process(&env, log.as_ref(), cgroup.as_ref()) {
+            Ok(c) => c,
+            Err(err) => {
+                let _ = fs::remove_file(pid_file);
+                return Err(err);
+            }
+        };
+        let server_pid = child.id();
+        let started = Instant::now();
+
+        let stopping = Arc::new(AtomicBool::new(false));
+        let signals = forward_signals(server_pid, &env, true, &stopping)?;
+
+        let context = HookContext {
+            restart_count: restarts,
+            exit_code: None,
+            pid: Some(server_pid),
+        };
+        run_hook(&env, Hook::PostStart, &context, log.as_ref());
+
+        let watchdog = match (keep_alive, &env.watchdog) {
+            (true, Some(config)) => Some(Watchdog::start(
+                config.clone(),
+                env.working_dir.join(SOCK_FILE_NAME),
+                server_pid,
+                stopping.clone(),
+            )),
+            _ => None,
+        };
+
+        let monitor = notifier.as_ref().map(|n| {
+            Monitor::start(
+                n.clone(),
+                env.working_dir.join(SOCK_FILE_NAME),
+                stopping.clone(),
+            )
+        });
 
-        result = wait_for_child(child);
+        let status = wait_for_child(child);
+        result = exit_code(&status);
+        record_exit(&env.events, server_pid, &status);
+        let oom = check_oom(&env, cgroup.as_ref(), oom_kills, server_pid);
 
         signals.close();
 
-        let _ = fs::remove_file(pid_file);
+        if let Some(monitor) = monitor {
+            monitor.finish();
+        }
 
-        // Check to see if we should restart from error
-        if sub_m.is_present("KEEP_ALIVE") {
-            if result == STOP_EXIT_CODE {
-                break;
-            } else {
-                // We need to restart, it looks like the server has crashed
-                continue;
+        let mut hung = false;
+        if let Some(watchdog) = watchdog {
+            if watchdog.finish() {
+                hung = true;
+                eprintln!("Server stopped responding, killed it after taking a thread dump");
             }
         }
 
-        if result != RESTART_EXIT_CODE {
+        if upgraded && !stopping.load(Ordering::SeqCst) {
+            let cause = if hung {
+                "hung and was killed"
+            } else if oom {
+                "ran out of memory and was killed"
+            } else {
+                "exited"
+            };
+            check_upgrade(&env, started, result, cause);
+        }
+
+        let context = HookContext {
+            restart_count: restarts,
+            exit_code: Some(result),
+            pid: Some(server_pid),
+        };
+        run_hook(&env, Hook::PostStop, &context, log.as_ref());
+
+        let _ = fs::remove_file(pid_file);
+
+        // paperd itself was asked to stop, so don't bring the server back up
+        if stopping.load(Ordering::SeqCst) {
             break;
         }
+
+        // Check to see if we should restart from error
+        let delay = if result == RESTART_EXIT_CODE {
+            // Requested restart, not a crash
+            env.events.record(Event::Restart {
+                reason: "requested",
+                delay_ms: 0,
+            });
+            Duration::from_secs(0)
+        } else if !keep_alive {
+            break;
+        } else if result == STOP_EXIT_CODE {
+            env.events.record(Event::KeepAlive {
+                decision: "stop",
+                exit_code: result,
+                recent_crashes: 0,
+            });
+            break;
+        } else {
+            // We need to restart, it looks like the server has crashed
+            match crashes.record_crash() {
+                Decision::Restart(delay) => {
+                    env.events.record(Event::KeepAlive {
+                        decision: "restart",
+                        exit_code: result,
+                        recent_crashes: crashes.recent_crashes(),
+                    });
+                    env.events.record(Event::Restart {
+                        reason: if hung {
+                            "unresponsive"
+                        } else if oom {
+                            "oom"
+                        } else {
+                            "crash"
+                        },
+                        delay_ms: delay.as_millis() as u64,
+                    });
+                    eprintln!(
+                        "Server exited with code {}, restarting in {} ms",
+                        result,
+                        delay.as_millis()
+                    );
+                    delay
+                }
+                Decision::GiveUp(count) => {
+                    env.events.record(Event::KeepAlive {
+                        decision: "giveUp",
+                        exit_code: result,
+                        recent_crashes: count,
+                    });
+                    eprintln!(
+                        "Server crashed {} times within {} seconds, giving up",
+                        count,
+                        env.restart_policy.crash_window.as_secs()
+                    );
+                    break;
+                }
+            }
+        };
+
+        run_hook(&env, Hook::PreRestart, &context, log.as_ref());
+        thread::sleep(delay);
+
+        restarts += 1;
+        last_exit = Some(result);
+    }
+
+    if let Some(notifier) = &notifier {
+        notifier.stopping();
+    }
+
+    if let Some(cgroup) = cgroup {
+        cgroup.remove();
     }
 
     if result == STOP_EXIT_CODE {
@@ -180,7 +398,7 @@ fn check_eula(env: &JavaEnv) -> Result<bool, ExitValue> {
         }
     }
 
-    let eula_path = env.working_dir.join("eula.txt");
+    let eula_path = env.working_dir.join(EULA_FILE_NAME);
     if !eula_path.exists() {
         println!("eula.txt file not found, running server in foreground instead.");
         return Ok(false);
@@ -200,136 +418,386 @@ fn check_eula(env: &JavaEnv) -> Result<bool, ExitValue> {
 }
 
 pub fn run_cmd(sub_m: &ArgMatches) -> Result<(), ExitValue> {
-    let env = setup_java_env(sub_m)?;
-    return run_server_foreground(&env);
+    let mut env = setup_java_env(sub_m)?;
+    let lock = InstanceLock::acquire(&env.working_dir)?;
+    check_not_running(&env.working_dir, &lock)?;
+    prepare_identity(&env)?;
+    if !sub_m.is_present("SKIP_PREFLIGHT") {
+        run_preflight(&env)?;
+    }
+    return run_server_foreground(&mut env);
+}
+
+fn run_preflight(env: &JavaEnv) -> Result<(), ExitValue> {
+    return preflight(
+        &env.working_dir,
+        &env.args,
+        env.identity.as_ref(),
+        env.cgroup.as_ref().and_then(|c| c.memory_max),
+    );
+}
+
+/// Makes sure the server won't be run as root by accident, that paperd's limits leave room for the
+/// ones the server is given, and that the user it is run as can write to the files paperd and the
+/// server keep in the working directory.
+fn prepare_identity(env: &JavaEnv) -> Result<(), ExitValue> {
+    check_not_root(env.identity.as_ref(), env.allow_root)?;
+    // The limits are raised whether or not paperd switches users, every restart of the server
+    // inherits them from paperd
+    env.resources.prepare()?;
+
+    let identity = match &env.identity {
+        Some(i) => i,
+        None => return Ok(()),
+    };
+    identity.check_switch()?;
+    for name in &[
+        LOCK_FILE_NAME,
+        PID_FILE_NAME,
+        SOCK_FILE_NAME,
+        EVENTS_FILE_NAME,
+    ] {
+        identity.give(&env.working_dir.join(name))?;
+    }
+    return identity.give(&env.stdout_log.path);
 }
 
-fn run_server_foreground(env: &JavaEnv) -> Result<(), ExitValue> {
-    let child = start_process(env)?;
+fn run_server_foreground(env: &mut JavaEnv) -> Result<(), ExitValue> {
+    let cgroup = match &env.cgroup {
+        Some(config) => Some(config.create(&env.working_dir, env.identity.as_ref())?),
+        None => None,
+    };
+
+    if let Some(identity) = &env.identity {
+        identity.switch()?;
+    }
+
+    let upgraded = upgrade_jar(env);
+
+    if !run_hook(env, Hook::PreStart, &HookContext::default(), None) {
+        return Err(ExitValue::Code(1));
+    }
+
+    let oom_kills = cgroup.as_ref().map_or(0, |c| c.oom_kills());
+    let child = start_process(env, None, cgroup.as_ref())?;
 
     let pid = child.id();
+    let started = Instant::now();
+
+    let stopping = Arc::new(AtomicBool::new(false));
+    let signals = forward_signals(pid, env, false, &stopping)?;
 
-    let signals = forward_signals(pid)?;
+    let context = HookContext {
+        pid: Some(pid),
+        ..HookContext::default()
+    };
+    run_hook(env, Hook::PostStart, &context, None);
 
-    let result = wait_for_child(child);
+    let status = wait_for_child(child);
+    record_exit(&env.events, pid, &status);
+    let oom = check_oom(env, cgroup.as_ref(), oom_kills, pid);
 
     signals.close();
 
-    return Err(ExitValue::Code(result));
+    if upgraded && !stopping.load(Ordering::SeqCst) {
+        let cause = if oom {
+            "ran out of memory and was killed"
+        } else {
+            "exited"
+        };
+        check_upgrade(env, started, exit_code(&status), cause);
+    }
+
+    let context = HookContext {
+        exit_code: Some(exit_code(&status)),
+        pid: Some(pid),
+        ..HookContext::default()
+    };
+    run_hook(env, Hook::PostStop, &context, None);
+
+    if let Some(cgroup) = cgroup {
+        cgroup.remove();
+    }
+
+    return Err(ExitValue::Code(exit_code(&status)));
+}
+
+/// Swaps in a jar staged with the upgrade command, rolling it back right away when none of the
+/// installed JDKs can run it. Returns whether the server is about to run a newly swapped in jar.
+fn upgrade_jar(env: &mut JavaEnv) -> bool {
+    if !apply_staged(&env.working_dir, &env.jar_file, &env.events) {
+        return false;
+    }
+    if !reselect_java(env) {
+        let reason = "no installed JDK can run the new server jar";
+        rollback(&env.working_dir, &env.jar_file, &env.events, reason);
+        return false;
+    }
+    return true;
+}
+
+/// Rolls back a jar which was swapped in before the server was started at `started`, when the
+/// server crashed within the rollback window. A new jar which crashes that soon is considered
+/// broken.
+fn check_upgrade(env: &JavaEnv, started: Instant, result: i32, cause: &str) {
+    if result == STOP_EXIT_CODE || result == RESTART_EXIT_CODE {
+        return;
+    }
+    if started.elapsed() >= env.rollback_window {
+        return;
+    }
+
+    let reason = format!(
+        "server {} with code {} {} s after the upgrade",
+        cause,
+        result,
+        started.elapsed().as_secs()
+    );
+    rollback(&env.working_dir, &env.jar_file, &env.events, &reason);
+}
+
+/// Runs the commands configured for `hook`, with their output going to the stdout log when paperd
+/// is capturing the server's output.
+fn run_hook(
+    env: &JavaEnv,
+    hook: Hook,
+    context: &HookContext,
+    log: Option<&Arc<Mutex<RotatingFile>>>,
+) -> bool {
+    return env
+        .hooks
+        .run(hook, &env.working_dir, context, &env.events, log);
+}
+
+pub struct JavaEnv {
+    pub java_file: PathBuf,
+    pub jar_file: PathBuf,
+    pub working_dir: PathBuf,
+    pub args: Vec<String>,
+    pub cmd_args: Vec<String>,
+    pub sources: Sources,
+    restart_policy: RestartPolicy,
+    stdout_log: StdoutLogConfig,
+    watchdog: Option<WatchdogConfig>,
+    events: EventJournal,
+    graceful_stop: Option<Duration>,
+    hooks: Hooks,
+    rollback_window: Duration,
+    child_env: ChildEnv,
+    identity: Option<Identity>,
+    allow_root: bool,
+    resources: ResourceControls,
+    cgroup: Option<CgroupConfig>,
+    auto_java: Option<AutoJava>,
+}
+
+/// How the JVM was picked when it wasn't given, so it can be picked again for a new server jar.
+struct AutoJava {
+    java_homes: Vec<String>,
+    /// What the JVM arguments were made from, when they weren't given either
+    preset: Option<PresetArgs>,
+    /// How many of the JVM arguments, from the start, were made from the preset
+    preset_len: usize,
+}
+
+/// Where a setting of the server's command line came from.
+pub enum Source {
+    /// The command line argument it was given with
+    CommandLine(&'static str),
+    /// The key in the config file
+    ConfigFile(&'static str),
+    /// The environment variable it was found with
+    Environment(&'static str),
+    /// What was taken from the instance chosen with -n
+    Instance(&'static str),
+    /// How the default was chosen
+    Default(&'static str),
+}
+
+pub struct Sources {
+    pub java: Source,
+    pub jar: Source,
+    pub working_dir: Source,
+    pub jvm_args: Source,
+    pub server_args: Source,
+    config_file: Option<String>,
+    config_origins: BTreeMap<String, String>,
+    instance: Option<String>,
+}
+
+impl Sources {
+    pub fn describe(&self, source: &Source) -> String {
+        return match source {
+            Source::CommandLine(arg) => format!("command line ({})", arg),
+            Source::ConfigFile(key) => {
+                let file = self.config_file.as_ref().map_or("", |f| f.as_str());
+                match self.config_origins.get(*key) {
+                    Some(origin) if origin.starts_with("profile ") => {
+                        format!("{} in config file {} ({})", origin, file, key)
+                    }
+                    Some(origin) => format!("config file {} ({})", origin, key),
+                    None => format!("config file {} ({})", file, key),
+                }
+            }
+            Source::Environment(var) => format!("environment ({})", var),
+            Source::Instance(text) => {
+                let name = self.instance.as_ref().map_or("", |n| n.as_str());
+                format!("instance {} ({})", name, text)
+            }
+            Source::Default(text) => format!("default ({})", text),
+        };
+    }
 }
 
-struct JavaEnv {
-    java_file: PathBuf,
-    jar_file: PathBuf,
-    working_dir: PathBuf,
-    args: Vec<String>,
-    cmd_args: Vec<String>,
+fn open_stdout_log(env: &JavaEnv) -> Result<Arc<Mutex<RotatingFile>>, ExitValue> {
+    let mut file = RotatingFile::open(env.stdout_log.clone()).conv(format!(
+        "Failed to open {}",
+        env.stdout_log.path.to_string_lossy()
+    ))?;
+    file.redirect_stdio()
+        .conv("Failed to redirect stdout and stderr to the stdout log")?;
+    return Ok(Arc::new(Mutex::new(file)));
 }
 
-fn start_process(env: &JavaEnv) -> Result<Child, ExitValue> {
-    let result = Command::new(&env.java_file)
+/// Starts the server, with its output going to `log` when it is set.
+fn start_process(
+    env: &JavaEnv,
+    log: Option<&Arc<Mutex<RotatingFile>>>,
+    cgroup: Option<&Cgroup>,
+) -> Result<Child, ExitValue> {
+    let mut command = Command::new(&env.java_file);
+    command
         .args(&env.args)
         .arg("-jar")
         .arg(&env.jar_file)
         .args(&env.cmd_args)
-        .current_dir(&env.working_dir)
-        .spawn();
+        .current_dir(&env.working_dir);
+    env.child_env.apply(&mut command);
+    env.resources.apply(&mut command);
+    if let Some(cgroup) = cgroup {
+        cgroup.apply(&mut command);
+    }
+    command
+        // These are meant for paperd only
+        .env_remove("NOTIFY_SOCKET")
+        .env_remove("WATCHDOG_USEC")
+        .env_remove("WATCHDOG_PID");
+
+    if log.is_some() {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
 
-    return match result {
-        Ok(c) => Ok(c),
+    let mut child = match command.spawn() {
+        Ok(c) => c,
         Err(err) => {
             eprintln!("Failed to start server: {}", err);
-            Err(ExitValue::Code(1))
+            return Err(ExitValue::Code(1));
         }
     };
-}
-
-fn shell_context(s: &str) -> Result<Option<Cow<'static, str>>, env::VarError> {
-    match env::var(s) {
-        Ok(value) => Ok(Some(value.into())),
-        Err(env::VarError::NotPresent) => Ok(Some("".into())),
-        Err(e) => Err(e),
-    }
-}
-
-fn setup_java_env(sub_m: &ArgMatches) -> Result<JavaEnv, ExitValue> {
-    let mut config: Option<RunnerConfig> = match sub_m.value_of("CONFIG_FILE") {
-        Some(config_path_text) => {
-            let config_path = PathBuf::from(config_path_text);
-            if !config_path.exists() {
-                eprintln!("No file found at {}", config_path_text);
-                return Err(ExitValue::Code(1));
-            }
 
-            let config_file = match File::open(config_path) {
-                Ok(f) => f,
-                Err(e) => {
-                    eprintln!("Failed to open config file {}: {}", config_path_text, e);
-                    return Err(ExitValue::Code(1));
-                }
-            };
+    let mut argv = vec![env.java_file.to_string_lossy().into_owned()];
+    argv.extend(env.args.iter().cloned());
+    argv.push("-jar".to_string());
+    argv.push(env.jar_file.to_string_lossy().into_owned());
+    argv.extend(env.cmd_args.iter().cloned());
+    env.events.record(Event::Spawn {
+        pid: child.id(),
+        argv,
+    });
 
-            match serde_json::from_reader(config_file) {
-                Ok(c) => c,
-                Err(e) => {
-                    eprintln!("Failed to parse config file {}: {}", config_path_text, e);
-                    return Err(ExitValue::Code(1));
-                }
-            }
+    if let Some(log) = log {
+        if let Some(stdout) = child.stdout.take() {
+            capture(stdout, log.clone());
         }
-        None => None,
-    };
+        if let Some(stderr) = child.stderr.take() {
+            capture(stderr, log.clone());
+        }
+    }
 
-    // Replace shell variables in config file values if they are present
-    if let Some(config) = config.as_mut() {
-        let map_func = |text: String| {
-            shellexpand::env_with_context(text.as_str(), shell_context)
-                .unwrap()
-                .to_string()
-        };
+    return Ok(child);
+}
 
-        config.jar_file = config.jar_file.clone().map(map_func);
-        config.jvm = config.jvm.clone().map(map_func);
-        config.working_dir = config.working_dir.clone().map(map_func);
-        config.jvm_args = config
-            .jvm_args
-            .clone()
-            .map(|args| args.into_iter().map(map_func).collect());
-        config.server_args = config
-            .server_args
-            .clone()
-            .map(|args| args.into_iter().map(map_func).collect());
+/// Makes sure there isn't a server running in the working directory already, cleaning up after
+/// one which didn't exit cleanly.
+fn check_not_running(working_dir: &Path, lock: &InstanceLock) -> Result<(), ExitValue> {
+    if let PidStatus::Running(pid) = read_pid_file(&working_dir.join(PID_FILE_NAME))? {
+        eprintln!(
+            "Found server already running in this directory with PID {}, will not continue",
+            pid
+        );
+        return Err(ExitValue::Code(1));
     }
 
-    let config = config.as_ref();
+    if lock.clean_stale()? {
+        println!("Found stale PID or socket file, removing");
+    }
 
-    // Find Java executable
-    let java_path = config
-        .and_then(|c| c.jvm.as_ref().map(|s| s.as_str()))
-        .or(sub_m.value_of("JVM"))
-        .map(PathBuf::from)
-        .or_else(find_java);
+    // A server is still listening on the socket, without paperd supervising it
+    let sock_file = working_dir.join(SOCK_FILE_NAME);
+    if sock_file.exists() {
+        eprintln!(
+            "Found server already listening on {}, will not continue",
+            sock_file.to_string_lossy()
+        );
+        return Err(ExitValue::Code(1));
+    }
 
-    let java_path = match java_path {
-        Some(path) => path,
+    return Ok(());
+}
+
+/// Resolves everything needed to run the server from the command line and config file, without
+/// starting anything.
+pub fn setup_java_env(sub_m: &ArgMatches) -> Result<JavaEnv, ExitValue> {
+    let instance = selected_instance(sub_m)?;
+    // The instance's config file is used unless another one is given
+    let config_file = sub_m
+        .value_of("CONFIG_FILE")
+        .map(|s| s.to_string())
+        .or_else(|| {
+            instance
+                .as_ref()
+                .and_then(|i| i.config_file.as_ref())
+                .map(|f| f.to_string_lossy().into_owned())
+        });
+    let loaded = match &config_file {
+        Some(path) => Some(load_config(path, sub_m.value_of("PROFILE"))?),
         None => {
-            eprintln!(
-                "Could not find a JVM executable. Either make sure it's present on the PATH, or \
-                 there's a valid JAVA_HOME, or specify it with -j. See --help for more details."
-            );
-            return Err(ExitValue::Code(1));
+            if sub_m.is_present("PROFILE") {
+                eprintln!("--profile requires a config file, from --config-file or the instance");
+                return Err(ExitValue::Code(1));
+            }
+            None
         }
     };
+    let config = loaded.as_ref().map(|l| &l.config);
 
     // Find target jar file
+    let instance_jar = instance
+        .as_ref()
+        .filter(|_| sub_m.occurrences_of("JAR") == 0)
+        .map(|i| i.working_dir.join("paperclip.jar"));
+    let jar_source = if config.and_then(|c| c.jar_file.as_ref()).is_some() {
+        Source::ConfigFile("jarFile")
+    } else if sub_m.occurrences_of("JAR") > 0 {
+        Source::CommandLine("--jar")
+    } else if instance_jar.is_some() {
+        Source::Instance("paperclip.jar in its working directory")
+    } else {
+        Source::Default("paperclip.jar in the current directory")
+    };
     let jar_path = match config
-        .and_then(|c| c.jar_file.as_ref().map(|s| s.as_str()))
-        .or(sub_m.value_of("JAR"))
+        .and_then(|c| c.jar_file.as_ref().map(PathBuf::from))
+        .or(instance_jar)
+        .or(sub_m.value_of("JAR").map(PathBuf::from))
     {
-        Some(path) => match canonicalize(PathBuf::from(path)) {
+        Some(path) => match canonicalize(&path) {
             Ok(canonical) => canonical,
             Err(e) => {
-                eprintln!("Failed to get full path to jar {}: {}", path, e);
+                eprintln!(
+                    "Failed to get full path to jar {}: {}",
+                    path.to_string_lossy(),
+                    e
+                );
                 return Err(ExitValue::Code(1));
             }
         },
@@ -343,11 +811,53 @@ fn setup_java_env(sub_m: &ArgMatches) -> Result<JavaEnv, ExitValue> {
         return Err(ExitValue::Code(1));
     }
 
+    // Find Java executable
+    let java_path = config
+        .and_then(|c| c.jvm.as_ref())
+        .map(|s| (PathBuf::from(s), Source::ConfigFile("jvm")))
+        .or_else(|| {
+            sub_m
+                .value_of("JVM")
+                .map(|s| (PathBuf::from(s), Source::CommandLine("--jvm")))
+        });
+    let java_homes = config
+        .and_then(|c| c.java_homes.clone())
+        .unwrap_or_default();
+    let auto = java_path.is_none();
+    let java_path = match java_path {
+        Some(path) => Some(path),
+        None => match required_java_version(&jar_path) {
+            Some(required) => Some(select_jdk(&java_homes, &jar_path, required)?),
+            None => find_java(),
+        },
+    };
+
+    let (java_path, java_source) = match java_path {
+        Some(path) => path,
+        None => {
+            eprintln!(
+                "Could not find a JVM executable. Either make sure it's present on the PATH, or \
+                 there's a valid JAVA_HOME, or specify it with -j. See --help for more details."
+            );
+            return Err(ExitValue::Code(1));
+        }
+    };
+
     // Get the jar's parent directory
+    let working_dir_source = if config.and_then(|c| c.working_dir.as_ref()).is_some() {
+        Source::ConfigFile("workingDir")
+    } else if sub_m.is_present("CWD") {
+        Source::CommandLine("--working-dir")
+    } else if instance.is_some() {
+        Source::Instance("workingDir in the registry")
+    } else {
+        Source::Default("directory of the jar")
+    };
     let parent_path = config
         .and_then(|c| c.working_dir.as_ref().map(|s| s.as_str()))
         .or(sub_m.value_of("CWD"))
         .map(|s| PathBuf::from(s))
+        .or_else(|| instance.as_ref().map(|i| i.working_dir.clone()))
         .or_else(|| jar_path.parent().map(|p| p.to_path_buf()));
 
     let parent_path = match parent_path {
@@ -361,52 +871,409 @@ fn setup_java_env(sub_m: &ArgMatches) -> Result<JavaEnv, ExitValue> {
         }
     };
 
-    let pid_file = parent_path.join(PID_FILE_NAME);
-    if pid_file.is_file() {
-        let pid = fs::read_to_string(&pid_file).conv("Failed to read PID file")?;
-        let pid = Pid::from_raw(pid.parse::<i32>().conv("Failed to parse PID file")?);
+    let (jvm_args, jvm_args_source, preset) = get_jvm_args(&config, sub_m, &java_path)?;
+    let auto_java = if auto {
+        Some(AutoJava {
+            java_homes,
+            preset_len: preset.as_ref().map_or(0, |_| jvm_args.len()),
+            preset,
+        })
+    } else {
+        None
+    };
+    let (server_args, server_args_source) = match sub_m.values_of("SERVER_ARGS") {
+        Some(values) => (
+            values.map(|s| s.to_string()).collect(),
+            Source::CommandLine("--server-arg"),
+        ),
+        None => match config.and_then(|c| c.server_args.as_ref()) {
+            Some(args) => (args.clone(), Source::ConfigFile("serverArgs")),
+            None => (Vec::new(), Source::Default("none")),
+        },
+    };
 
-        match kill(pid, None) {
-            Ok(()) => {
+    let restart_policy = get_restart_policy(&config, sub_m)?;
+    let stdout_log = get_stdout_log_config(&config, sub_m, &parent_path)?;
+    let watchdog = get_watchdog_config(&config, sub_m)?;
+    let events = EventJournal::new(&parent_path);
+    let graceful_stop = get_graceful_stop(&config, sub_m)?;
+    let hooks = get_hooks(&config);
+    let child_env = get_child_env(&config)?;
+    let identity = get_identity(&config, sub_m)?;
+    let allow_root = sub_m.is_present("ALLOW_ROOT");
+    let resources = get_resources(&config, sub_m)?;
+    let cgroup = get_cgroup_config(&config)?;
+    let rollback_window = match sub_m
+        .value_of("ROLLBACK_WINDOW")
+        .or(config.and_then(|c| c.rollback_window.as_deref()))
+    {
+        Some(text) => parse_duration_arg("rollback window", text)?,
+        None => DEFAULT_ROLLBACK_WINDOW,
+    };
+
+    let sources = Sources {
+        java: java_source,
+        jar: jar_source,
+        working_dir: working_dir_source,
+        jvm_args: jvm_args_source,
+        server_args: server_args_source,
+        config_file,
+        config_origins: loaded.map(|l| l.origins).unwrap_or_default(),
+        instance: instance.map(|i| i.name),
+    };
+
+    return Ok(JavaEnv {
+        java_file: java_path,
+        jar_file: jar_path,
+        working_dir: parent_path,
+        args: jvm_args,
+        cmd_args: server_args,
+        sources,
+        restart_policy,
+        stdout_log,
+        watchdog,
+        events,
+        graceful_stop,
+        hooks,
+        rollback_window,
+        child_env,
+        identity,
+        allow_root,
+        resources,
+        cgroup,
+        auto_java,
+    });
+}
+
+fn get_cgroup_config(config: &Option<&RunnerConfig>) -> Result<Option<CgroupConfig>, ExitValue> {
+    let config = match config {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+    let parent = match &config.cgroup {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let limits = [
+                ("memoryMax", config.memory_max.is_some()),
+                ("memoryHigh", config.memory_high.is_some()),
+                ("cpuMax", config.cpu_max.is_some()),
+                ("pidsMax", config.pids_max.is_some()),
+            ];
+            if let Some((key, _)) = limits.iter().find(|(_, set)| *set) {
                 eprintln!(
-                    "Found server already running in this directory with PID {}, will not continue",
-                    pid
+                    "{} can only be used together with cgroup in the config file",
+                    key
                 );
                 return Err(ExitValue::Code(1));
             }
-            Err(Error::Sys(e)) => {
-                if e == ESRCH {
-                    println!("Found stale PID file, removing");
-                    fs::remove_file(&pid_file).conv("Failed to delete PID file")?;
-                } else {
-                    println!("Unknown error occurred (start): {}", e);
-                    return Err(ExitValue::Code(1));
-                }
+            return Ok(None);
+        }
+    };
+
+    let parse_memory = |name: &str, text: &Option<String>| match text {
+        Some(text) => match parse_size(text) {
+            Some(size) => Ok(Some(size)),
+            None => {
+                eprintln!(
+                    "Invalid size for {}: {}. Should be something like 512m or 4G.",
+                    name, text
+                );
+                Err(ExitValue::Code(1))
+            }
+        },
+        None => Ok(None),
+    };
+    let memory_max = parse_memory("memoryMax", &config.memory_max)?;
+    let memory_high = parse_memory("memoryHigh", &config.memory_high)?;
+
+    let cpu_max = match &config.cpu_max {
+        Some(text) => match parse_cpu_max(text) {
+            Some(value) => Some(value),
+            None => {
+                eprintln!(
+                    "Invalid value for cpuMax: {}. Should be a percentage of one CPU like 150%, \
+                     or a quota and period in microseconds like \"150000 100000\".",
+                    text
+                );
+                return Err(ExitValue::Code(1));
+            }
+        },
+        None => None,
+    };
+
+    return Ok(Some(CgroupConfig {
+        parent,
+        memory_max,
+        memory_high,
+        cpu_max,
+        pids_max: config.pids_max,
+    }));
+}
+
+fn get_resources(
+    config: &Option<&RunnerConfig>,
+    sub_m: &ArgMatches,
+) -> Result<ResourceControls, ExitValue> {
+    let mut resources = ResourceControls::default();
+
+    match sub_m.value_of("LIMIT_NOFILE") {
+        Some(text) => match text.parse::<u64>() {
+            Ok(count) => resources.open_files = Some(count),
+            Err(_) => {
+                eprintln!("Invalid open files limit: {}. Should be a number.", text);
+                return Err(ExitValue::Code(1));
             }
-            _ => {}
+        },
+        None => resources.open_files = config.and_then(|c| c.limit_nofile),
+    }
+
+    let text = sub_m
+        .value_of("LIMIT_CORE")
+        .or(config.and_then(|c| c.limit_core.as_deref()));
+    if let Some(text) = text {
+        resources.core_size = parse_core_size(text);
+        if resources.core_size.is_none() {
+            eprintln!(
+                "Invalid core dump size limit: {}. Should be something like 0, 1G or unlimited.",
+                text
+            );
+            return Err(ExitValue::Code(1));
         }
     }
 
-    let jvm_args = get_jvm_args(&config, sub_m)?;
-    let server_args = sub_m
-        .values_of("SERVER_ARGS")
-        .map(|values| values.map(|s| s.to_string()).collect())
-        .unwrap_or_else(|| {
-            config
-                .and_then(|c| c.server_args.as_ref().map(|a| a.clone()))
-                .unwrap_or_else(|| Vec::new())
-        });
+    let text = sub_m
+        .value_of("NICE")
+        .map(|s| s.to_string())
+        .or(config.and_then(|c| c.nice.map(|n| n.to_string())));
+    if let Some(text) = text {
+        resources.nice = parse_nice(&text);
+        if resources.nice.is_none() {
+            eprintln!(
+                "Invalid nice level: {}. Should be between -20 and 19.",
+                text
+            );
+            return Err(ExitValue::Code(1));
+        }
+    }
 
-    return Ok(JavaEnv {
-        java_file: java_path,
-        jar_file: jar_path,
-        working_dir: parent_path,
-        args: jvm_args,
-        cmd_args: server_args,
+    let text = sub_m
+        .value_of("IO_CLASS")
+        .or(config.and_then(|c| c.io_class.as_deref()));
+    if let Some(text) = text {
+        resources.io_class = parse_io_class(text);
+        if resources.io_class.is_none() {
+            eprintln!(
+                "Invalid I/O class: {}. Should be idle, best-effort or realtime, optionally \
+                 followed by a priority from 0 to 7, like best-effort:2.",
+                text
+            );
+            return Err(ExitValue::Code(1));
+        }
+    }
+
+    let text = sub_m
+        .value_of("CPU_AFFINITY")
+        .or(config.and_then(|c| c.cpu_affinity.as_deref()));
+    if let Some(text) = text {
+        resources.cpus = parse_cpu_list(text);
+        if resources.cpus.is_none() {
+            eprintln!(
+                "Invalid CPU list: {}. Should be something like 0-3,6.",
+                text
+            );
+            return Err(ExitValue::Code(1));
+        }
+    }
+
+    return Ok(resources);
+}
+
+fn get_identity(
+    config: &Option<&RunnerConfig>,
+    sub_m: &ArgMatches,
+) -> Result<Option<Identity>, ExitValue> {
+    let user = sub_m
+        .value_of("RUN_USER")
+        .or(config.and_then(|c| c.user.as_deref()));
+    let group = sub_m
+        .value_of("RUN_GROUP")
+        .or(config.and_then(|c| c.group.as_deref()));
+    let groups: Vec<String> = match sub_m.values_of("RUN_GROUPS") {
+        Some(values) => values.map(|s| s.to_string()).collect(),
+        None => config.and_then(|c| c.groups.clone()).unwrap_or_default(),
+    };
+    return Identity::resolve(user, group, &groups);
+}
+
+fn get_child_env(config: &Option<&RunnerConfig>) -> Result<ChildEnv, ExitValue> {
+    let config = match config {
+        Some(c) => c,
+        None => return Ok(ChildEnv::default()),
+    };
+
+    let mut vars = Vec::new();
+    for path in config.env_file.iter().flatten() {
+        vars.extend(read_env_file(path)?);
+    }
+    for (name, value) in config.env.iter().flatten() {
+        if !is_valid_name(name) {
+            eprintln!("Invalid environment variable name in config file: {}", name);
+            return Err(ExitValue::Code(1));
+        }
+        vars.push((name.clone(), value.clone()));
+    }
+
+    return Ok(ChildEnv {
+        keep: config.clear_env.clone(),
+        vars,
     });
 }
 
-fn forward_signals(pid: u32) -> Result<Signals, ExitValue> {
+fn get_hooks(config: &Option<&RunnerConfig>) -> Hooks {
+    let commands = |f: fn(&RunnerConfig) -> &Option<Vec<String>>| {
+        config.and_then(|c| f(c).clone()).unwrap_or_default()
+    };
+    return Hooks {
+        pre_start: commands(|c| &c.pre_start),
+        post_start: commands(|c| &c.post_start),
+        pre_restart: commands(|c| &c.pre_restart),
+        post_stop: commands(|c| &c.post_stop),
+    };
+}
+
+fn get_graceful_stop(
+    config: &Option<&RunnerConfig>,
+    sub_m: &ArgMatches,
+) -> Result<Option<Duration>, ExitValue> {
+    let enabled =
+        sub_m.is_present("GRACEFUL_STOP") || config.and_then(|c| c.graceful_stop).unwrap_or(false);
+    if !enabled {
+        return Ok(None);
+    }
+
+    let timeout = sub_m
+        .value_of("STOP_TIMEOUT")
+        .or(config.and_then(|c| c.stop_timeout.as_deref()));
+    return match timeout {
+        Some(text) => parse_duration_arg("stop timeout", text).map(Some),
+        None => Ok(Some(DEFAULT_STOP_TIMEOUT)),
+    };
+}
+
+fn get_watchdog_config(
+    config: &Option<&RunnerConfig>,
+    sub_m: &ArgMatches,
+) -> Result<Option<WatchdogConfig>, ExitValue> {
+    let interval = sub_m
+        .value_of("WATCHDOG_INTERVAL")
+        .or(config.and_then(|c| c.watchdog_interval.as_deref()));
+    let interval = match interval {
+        Some(text) => parse_duration_arg("watchdog interval", text)?,
+        None => return Ok(None),
+    };
+
+    let max_failures = match sub_m.value_of("WATCHDOG_FAILURES") {
+        Some(text) => text
+            .parse::<u32>()
+            .conv("Failed to parse command line argument")?,
+        None => config
+            .and_then(|c| c.watchdog_failures)
+            .unwrap_or(DEFAULT_WATCHDOG_FAILURES),
+    };
+
+    return Ok(Some(WatchdogConfig {
+        interval,
+        max_failures,
+    }));
+}
+
+fn get_stdout_log_config(
+    config: &Option<&RunnerConfig>,
+    sub_m: &ArgMatches,
+    working_dir: &Path,
+) -> Result<StdoutLogConfig, ExitValue> {
+    let max_size = sub_m
+        .value_of("STDOUT_LOG_SIZE")
+        .or(config.and_then(|c| c.stdout_log_size.as_deref()));
+    let max_size = match max_size {
+        Some(text) => match parse_size(text) {
+            Some(size) => size,
+            None => {
+                eprintln!(
+                    "Invalid size for stdout log: {}. Should be something like 512k or 10M.",
+                    text
+                );
+                return Err(ExitValue::Code(1));
+            }
+        },
+        None => DEFAULT_STDOUT_LOG_SIZE,
+    };
+
+    let retention = match sub_m.value_of("STDOUT_LOG_COUNT") {
+        Some(text) => text
+            .parse::<u32>()
+            .conv("Failed to parse command line argument")?,
+        None => config
+            .and_then(|c| c.stdout_log_count)
+            .unwrap_or(DEFAULT_STDOUT_LOG_COUNT),
+    };
+
+    return Ok(StdoutLogConfig {
+        path: working_dir.join(STDOUT_LOG_FILE_NAME),
+        max_size,
+        retention,
+    });
+}
+
+fn get_restart_policy(
+    config: &Option<&RunnerConfig>,
+    sub_m: &ArgMatches,
+) -> Result<RestartPolicy, ExitValue> {
+    let mut policy = RestartPolicy::default();
+
+    let restart_delay = sub_m
+        .value_of("RESTART_DELAY")
+        .or(config.and_then(|c| c.restart_delay.as_deref()));
+    if let Some(text) = restart_delay {
+        policy.restart_delay = parse_duration_arg("restart delay", text)?;
+    }
+
+    let max_restart_delay = sub_m
+        .value_of("MAX_RESTART_DELAY")
+        .or(config.and_then(|c| c.max_restart_delay.as_deref()));
+    if let Some(text) = max_restart_delay {
+        policy.max_restart_delay = parse_duration_arg("max restart delay", text)?;
+    }
+
+    let crash_window = sub_m
+        .value_of("CRASH_WINDOW")
+        .or(config.and_then(|c| c.crash_window.as_deref()));
+    if let Some(text) = crash_window {
+        policy.crash_window = parse_duration_arg("crash window", text)?;
+    }
+
+    if let Some(text) = sub_m.value_of("MAX_CRASHES") {
+        policy.max_crashes = text
+            .parse::<u32>()
+            .conv("Failed to parse command line argument")?;
+    } else if let Some(max_crashes) = config.and_then(|c| c.max_crashes) {
+        policy.max_crashes = max_crashes;
+    }
+
+    return Ok(policy);
+}
+
+/// Forwards signals sent to paperd to the server. When `supervisor` is set and a graceful stop
+/// timeout is configured, SIGTERM and SIGINT instead ask the server to stop through its socket,
+/// escalating to signals only if it doesn't exit in time, and further SIGTERMs and SIGINTs are
+/// ignored while it does. Either way `stopping` is set when paperd is asked to terminate, so the
+/// server won't be restarted afterwards.
+fn forward_signals(
+    pid: u32,
+    env: &JavaEnv,
+    supervisor: bool,
+    stopping: &Arc<AtomicBool>,
+) -> Result<Signals, ExitValue> {
     // While the server is running we'll redirect some signals to it
     let signals = Signals::new(&[SIGHUP, SIGINT, SIGQUIT, SIGTRAP, SIGABRT, SIGTERM]);
     let signals = match signals {
@@ -418,10 +1285,41 @@ fn forward_signals(pid: u32) -> Result<Signals, ExitValue> {
     };
 
     let signals_bg = signals.clone();
+    let events = env.events.clone();
+    let sock_file = env.working_dir.join(SOCK_FILE_NAME);
+    let graceful_stop = if supervisor { env.graceful_stop } else { None };
+    let stopping = stopping.clone();
     thread::spawn(move || {
         for sig_int in signals_bg.forever() {
             if let Ok(sig) = signal::Signal::try_from(sig_int) {
-                let _ = signal::kill(Pid::from_raw(pid as i32), sig);
+                events.record(Event::Signal {
+                    signal: sig.as_str(),
+                    pid,
+                });
+
+                let terminate = sig_int == SIGTERM || sig_int == SIGINT;
+                // Only the first of these starts a graceful stop, which escalates on its own
+                let already_stopping = terminate && stopping.swap(true, Ordering::SeqCst);
+
+                match graceful_stop {
+                    Some(_) if already_stopping => {}
+                    Some(timeout) if terminate => {
+                        let sock_file = sock_file.clone();
+                        let events = events.clone();
+                        // Stopping may take a while, keep handling other signals in the meantime
+                        thread::spawn(move || {
+                            stop_gracefully(
+                                &sock_file,
+                                Pid::from_raw(pid as i32),
+                                timeout,
+                                |method| events.record(Event::Stop { method, pid }),
+                            );
+                        });
+                    }
+                    _ => {
+                        let _ = signal::kill(Pid::from_raw(pid as i32), sig);
+                    }
+                }
             }
         }
     });
@@ -429,109 +1327,286 @@ fn forward_signals(pid: u32) -> Result<Signals, ExitValue> {
     return Ok(signals);
 }
 
-fn wait_for_child(mut child: Child) -> i32 {
-    return match child.wait().map(|status| status.code().unwrap_or(1)) {
-        Ok(status) => status,
+fn wait_for_child(mut child: Child) -> Option<ExitStatus> {
+    return match child.wait() {
+        Ok(status) => Some(status),
         Err(err) => {
             eprintln!("Error while running server: {}", err);
-            1
+            None
         }
     };
 }
 
+fn exit_code(status: &Option<ExitStatus>) -> i32 {
+    return status.and_then(|s| s.code()).unwrap_or(1);
+}
+
+/// Tells whether the kernel killed the server for going over the memory limit of its cgroup,
+/// which looks just like any other crash from its exit status.
+fn check_oom(env: &JavaEnv, cgroup: Option<&Cgroup>, oom_kills: u64, pid: u32) -> bool {
+    let cgroup = match cgroup {
+        Some(c) if c.oom_kills() > oom_kills => c,
+        _ => return false,
+    };
+    env.events.record(Event::OomKill {
+        pid,
+        cgroup: &cgroup.path.to_string_lossy(),
+    });
+    eprintln!(
+        "Server was killed for running out of memory, it went over memory.max of cgroup {}",
+        cgroup.path.to_string_lossy()
+    );
+    return true;
+}
+
+fn record_exit(events: &EventJournal, pid: u32, status: &Option<ExitStatus>) {
+    events.record(Event::Exit {
+        pid,
+        code: status.and_then(|s| s.code()),
+        signal: status.and_then(|s| s.signal()),
+    });
+}
+
+/// Picks the java on the PATH or in JAVA_HOME when it can run the Java version the jar needs, and
+/// the installed JDK closest to that version otherwise, failing with a list of the JDKs which were
+/// found when none of them is new enough.
+fn select_jdk(homes: &[String], jar: &Path, required: u32) -> Result<(PathBuf, Source), ExitValue> {
+    let default = find_java();
+    let default_install = default.as_ref().and_then(|(java, _)| java_install(java));
+    let installs = match prefer_java(default_install, required, || find_installs(homes)) {
+        Ok(install) => {
+            return Ok(match default {
+                Some((java, source)) if java == install.java => (java, source),
+                _ => (
+                    install.java,
+                    Source::Default("installed JDK closest to the Java version the jar requires"),
+                ),
+            });
+        }
+        Err(installs) => installs,
+    };
+
+    eprintln!(
+        "{} requires Java {} or newer, but no suitable JDK was found.",
+        jar.to_string_lossy(),
+        required
+    );
+    if installs.is_empty() {
+        eprintln!("No JDKs were found.");
+    } else {
+        eprintln!("Found JDKs:");
+        for install in &installs {
+            eprintln!(
+                "  Java {}: {}",
+                install.version,
+                install.java.to_string_lossy()
+            );
+        }
+    }
+    eprintln!(
+        "Install Java {} or newer, add its location to javaHomes in the config file, or specify \
+         the java executable with --jvm.",
+        required
+    );
+    return Err(ExitValue::Code(1));
+}
+
+/// Picks the JVM again for a server jar which was just swapped in, as it may need a newer Java than
+/// the jar before it, and remakes the preset flags for that JVM. Returns false when none of the
+/// installed JDKs can run the new jar.
+fn reselect_java(env: &mut JavaEnv) -> bool {
+    let auto = match env.auto_java.as_mut() {
+        Some(a) => a,
+        None => return true,
+    };
+    let required = match required_java_version(&env.jar_file) {
+        Some(r) => r,
+        None => return true,
+    };
+    let java = match select_jdk(&auto.java_homes, &env.jar_file, required) {
+        Ok((java, _)) => java,
+        Err(_) => return false,
+    };
+    if java == env.java_file {
+        return true;
+    }
+
+    if let Some(preset) = &auto.preset {
+        let args = match preset_args(preset, &java) {
+            Ok(a) => a,
+            Err(_) => return false,
+        };
+        let len = args.len();
+        env.args.splice(..auto.preset_len, args);
+        auto.preset_len = len;
+    }
+    eprintln!(
+        "The new server jar requires Java {}, using {}",
+        required,
+        java.to_string_lossy()
+    );
+    env.java_file = java;
+    return true;
+}
+
 /// Searches the PATH for java. If that fails, JAVA_HOME is searched as well.
-fn find_java() -> Option<PathBuf> {
-    return find_program(&[("PATH", "java"), ("JAVA_HOME", "bin/java")]);
+fn find_java() -> Option<(PathBuf, Source)> {
+    return find_program(&[("PATH", "java")])
+        .map(|p| (p, Source::Environment("PATH")))
+        .or_else(|| {
+            find_program(&[("JAVA_HOME", "bin/java")])
+                .map(|p| (p, Source::Environment("JAVA_HOME")))
+        });
 }
 
 fn get_jvm_args(
     config: &Option<&RunnerConfig>,
     sub_m: &ArgMatches,
-) -> Result<Vec<String>, ExitValue> {
+    java: &Path,
+) -> Result<(Vec<String>, Source, Option<PresetArgs>), ExitValue> {
     if let Some(vals) = sub_m.values_of("CUSTOM_ARGS") {
-        return Ok(vals.map(|s| s.to_string()).collect());
+        return Ok((
+            vals.map(|s| s.to_string()).collect(),
+            Source::CommandLine("JVM arguments after --"),
+            None,
+        ));
     }
     if let Some(args) = config.and_then(|c| c.jvm_args.as_ref().map(|a| a.clone())) {
-        return Ok(args);
+        return Ok((args, Source::ConfigFile("jvmArgs"), None));
     }
 
     // When all else fails, use 500m
     // This should hopefully be small enough to not cause problems for anyone
     let mut heap: String = "500m".to_string();
+    let mut source = Source::Default("flags of the preset with a 500m heap");
+
+    let mut memory = MemoryInfo::detect();
+    // The server is placed in a cgroup of its own when one is configured, with its own limit
+    let memory_max = config
+        .filter(|c| c.cgroup.is_some())
+        .and_then(|c| c.memory_max.as_ref())
+        .and_then(|m| parse_size(m));
+    if let Some(memory_max) = memory_max {
+        memory.cap(memory_max);
+    }
+    let headroom = match sub_m
+        .value_of("HEAP_HEADROOM")
+        .or(config.and_then(|c| c.heap_headroom.as_deref()))
+    {
+        Some(text) => match parse_size(text) {
+            Some(size) => size,
+            None => {
+                eprintln!(
+                    "Invalid size for heap headroom: {}. Should be something like 512m or 1G.",
+                    text
+                );
+                return Err(ExitValue::Code(1));
+            }
+        },
+        None => memory.default_headroom(),
+    };
 
     if let Some(value) = sub_m.value_of("DEFAULT_ARGS") {
-        const ERROR_MSG: &str =
-            "Invalid format for JVM heap size. Should be something like 500m or 2G.";
-        if value.is_empty() {
-            eprintln!("{}", ERROR_MSG);
-            return Err(ExitValue::Code(1));
-        }
-
-        if value[..value.len() - 1]
-            .chars()
-            .any(|c| !c.is_ascii_digit())
-            && value.chars().last().map_or(true, |c| c != 'm' && c != 'G')
-        {
-            eprintln!("{}", ERROR_MSG);
-            return Err(ExitValue::Code(1));
+        match parse_heap_arg(value) {
+            Some(HeapArg::Size(text, bytes)) => {
+                if bytes < MIN_HEAP {
+                    eprintln!(
+                        "JVM heap size {} is too small, it must be at least 2m.",
+                        text
+                    );
+                    return Err(ExitValue::Code(1));
+                }
+                if let (Some(limit), Some(max_heap)) =
+                    (memory.cgroup_limit, memory.max_heap(headroom))
+                {
+                    if bytes > max_heap {
+                        eprintln!(
+                            "Warning: A heap of {} leaves less than {} MB free under the memory \
+                             limit of {} MB, the server may be killed when it runs out of memory.",
+                            text,
+                            headroom / MB,
+                            limit / MB
+                        );
+                    }
+                }
+                heap = text;
+            }
+            Some(HeapArg::Percent(percent)) => {
+                heap = percent_heap(&memory, percent, headroom)?.0;
+            }
+            None => {
+                eprintln!(
+                    "Invalid format for JVM heap size: {}. Should be an amount of memory like \
+                     500m, 2G or 1048576k, or a percentage of the available memory like 75%.",
+                    value
+                );
+                return Err(ExitValue::Code(1));
+            }
         }
-
-        heap = value.to_string();
-    } else {
+        source = Source::CommandLine("--default-args, with the flags of the preset");
+    } else if let Some(bytes) = memory.auto_heap(headroom) {
         // If no arguments are provided, use 1/2 of the current available memory with default flags
-        if let Ok(info) = mem_info() {
-            // info.avail should always be greater than free, but it seems there may be a bug
-            // for macOS. Assuming most users are using linux this doesn't really affect much
-            let mem = max(info.avail, info.free);
-            // mem is in kb, so convert to mb by dividing by 1000
-            // Then we take half of it
-            // Cap the amount we automatically choose at 10G
-            let mut mb = min((mem / 1000) / 2, 10000).to_string();
-
-            println!(
-                "Warning: No memory argument provided, automatically determining to use {} MB \
-                 instead. This is not recommended, please specify an amount of memory with -d or \
-                 --default-args",
-                mb
-            );
-
-            mb.push_str("m");
-            heap = mb;
-        }
+        // Cap the amount we automatically choose at 10G, and stay under any memory limit
+        let (mb, _) = format_heap(max(bytes, MIN_HEAP));
+
+        eprintln!(
+            "Warning: No memory argument provided, automatically determining to use {} MB \
+             instead. This is not recommended, please specify an amount of memory with -d or \
+             --default-args",
+            &mb[..mb.len() - 1]
+        );
+
+        heap = mb;
+        source = Source::Default("flags of the preset with half of the available memory as heap");
     }
 
+    let preset = sub_m
+        .value_of("PRESET")
+        .or(config.and_then(|c| c.preset.as_deref()))
+        .unwrap_or(DEFAULT_PRESET);
+    let preset = PresetArgs {
+        heap,
+        preset: preset.to_string(),
+    };
+    let args = preset_args(&preset, java)?;
+    return Ok((args, source, Some(preset)));
+}
+
+/// The heap and the preset of flags which the JVM arguments are made from when none are given.
+struct PresetArgs {
+    heap: String,
+    preset: String,
+}
+
+fn preset_args(preset: &PresetArgs, java: &Path) -> Result<Vec<String>, ExitValue> {
     let mut xms = "-Xms".to_string();
     let mut xmx = "-Xmx".to_string();
-    xms.push_str(heap.as_str());
-    xmx.push_str(heap.as_str());
-
-    return Ok(vec![
-        xms,
-        xmx,
-        "-XX:+UseG1GC".to_string(),
-        "-XX:+UnlockExperimentalVMOptions".to_string(),
-        "-XX:MaxGCPauseMillis=100".to_string(),
-        "-XX:+DisableExplicitGC".to_string(),
-        "-XX:TargetSurvivorRatio=90".to_string(),
-        "-XX:G1NewSizePercent=50".to_string(),
-        "-XX:G1MaxNewSizePercent=80".to_string(),
-        "-XX:G1MixedGCLiveThresholdPercent=35".to_string(),
-        "-XX:+AlwaysPreTouch".to_string(),
-        "-XX:+ParallelRefProcEnabled".to_string(),
-        "-Dusing.aikars.flags=mcflags.emc.gs".to_string(),
-    ]);
-}
-
-#[derive(Deserialize)]
-struct RunnerConfig {
-    #[serde(rename = "jvm")]
-    jvm: Option<String>,
-    #[serde(rename = "jarFile")]
-    jar_file: Option<String>,
-    #[serde(rename = "workingDir")]
-    working_dir: Option<String>,
-    #[serde(rename = "jvmArgs")]
-    jvm_args: Option<Vec<String>>,
-    #[serde(rename = "serverArgs")]
-    server_args: Option<Vec<String>>,
+    xms.push_str(preset.heap.as_str());
+    xmx.push_str(preset.heap.as_str());
+
+    let preset = preset.preset.as_str();
+    // Flags are picked to match the JVM, so it doesn't refuse to start over ones it doesn't know
+    let java_version = java_version(java);
+    if java_version.is_none() {
+        eprintln!(
+            "Warning: Failed to find the version of {}, using all flags of the {} preset",
+            java.to_string_lossy(),
+            preset
+        );
+    }
+    let flags = match preset_flags(preset, java_version) {
+        Some(f) => f,
+        None => {
+            eprintln!(
+                "Unknown JVM flag preset {}, should be one of {}",
+                preset,
+                PRESET_NAMES.join(", ")
+            );
+            return Err(ExitValue::Code(1));
+        }
+    };
+
+    let mut args = vec![xms, xmx];
+    args.extend(flags);
+    return Ok(args);
 }
@@ -13,10 +13,11 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::broadcast::{broadcast, find_targets};
 use crate::log::{find_log_file, tail};
 use crate::messaging::MessageSocket;
-use crate::protocol::check_protocol;
-use crate::util::{get_sock, ExitValue};
+use crate::protocol::{check_protocol, CAPABILITY_SEND};
+use crate::util::{check_sock_file, get_sock_from_file, ExitValue};
 use clap::ArgMatches;
 use serde::Serialize;
 use std::sync::{Arc, Mutex};
@@ -24,9 +25,6 @@ use std::thread::{sleep, spawn};
 use std::time::Duration;
 
 pub fn send(sub_m: &ArgMatches) -> Result<(), ExitValue> {
-    let (sock, sock_file) = get_sock(sub_m)?;
-    check_protocol(&sock)?;
-
     let command: String = match sub_m.values_of("COMMAND") {
         Some(s) => s.map(|a| a.to_string()).collect::<Vec<String>>().join(" "),
         None => {
@@ -35,6 +33,23 @@ pub fn send(sub_m: &ArgMatches) -> Result<(), ExitValue> {
         }
     };
 
+    let targets = find_targets(sub_m)?;
+    if targets.len() > 1 {
+        let serial = sub_m.is_present("SERIAL");
+        return broadcast(targets, serial, "received the command", move |target| {
+            let sock = get_sock_from_file(check_sock_file(target.sock_file.clone())?)?;
+            check_protocol(&sock, &[CAPABILITY_SEND])?;
+            return send_command(&sock, command.as_str());
+        });
+    }
+
+    let sock_file = match targets.into_iter().next() {
+        Some(target) => check_sock_file(target.sock_file)?,
+        None => return Err(ExitValue::Code(1)),
+    };
+    let sock = get_sock_from_file(&sock_file)?;
+    check_protocol(&sock, &[CAPABILITY_SEND])?;
+
     return if sub_m.is_present("TAIL") {
         let lock: Arc<Mutex<Option<ExitValue>>> = Arc::new(Mutex::new(None));
         let thread_lock = lock.clone();
@@ -67,7 +82,7 @@ pub fn send(sub_m: &ArgMatches) -> Result<(), ExitValue> {
             Ok(())
         }
     } else {
-        Ok(())
+        send_command(&sock, command.as_str())
     };
 }
 
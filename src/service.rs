@@ -0,0 +1,358 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::registry::selected_instance;
+use crate::runner::{setup_java_env, RESTART_EXIT_CODE, SOCK_FILE_NAME, STOP_EXIT_CODE};
+use crate::util::{ExitError, ExitValue};
+use clap::ArgMatches;
+use nix::unistd::{Uid, User};
+use std::env;
+use std::fs;
+use std::fs::{canonicalize, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+pub fn install_service(sub_m: &ArgMatches) -> Result<(), ExitValue> {
+    let instance = selected_instance(sub_m)?;
+    let name = sub_m
+        .value_of("NAME")
+        .or(instance.as_ref().map(|i| i.name.as_str()))
+        .unwrap_or("paper");
+    let init = sub_m.value_of("INIT").unwrap_or("systemd");
+
+    let paperd = env::current_exe().conv("Failed to find the path to paperd")?;
+    let env = setup_java_env(sub_m)?;
+    let working_dir =
+        canonicalize(&env.working_dir).conv("Failed to get full path to working directory")?;
+
+    let user = match sub_m.value_of("USER") {
+        Some(u) => u.to_string(),
+        None => owner_of(&working_dir)?,
+    };
+
+    let mut args = start_args(sub_m, &env.jar_file, &working_dir)?;
+    // paperd refuses to run the server as root unless told to
+    if user == "root" || user == "0" {
+        args.insert(0, "--allow-root".to_string());
+    }
+
+    let text = match init {
+        "openrc" => openrc_script(name, &paperd, &working_dir, &user, &args),
+        "sysvinit" => sysvinit_script(name, &paperd, &working_dir, &user, &args),
+        _ => systemd_unit(name, &paperd, &working_dir, &user, &args),
+    };
+
+    if sub_m.is_present("PRINT") {
+        print!("{}", text);
+        return Ok(());
+    }
+
+    let output = match init {
+        "openrc" | "sysvinit" => PathBuf::from("/etc/init.d").join(name),
+        _ => PathBuf::from("/etc/systemd/system").join(format!("{}.service", name)),
+    };
+    if output.exists() && !sub_m.is_present("FORCE") {
+        eprintln!(
+            "{} already exists, use --force to overwrite it",
+            output.to_string_lossy()
+        );
+        return Err(ExitValue::Code(1));
+    }
+
+    let mode = if init == "systemd" { 0o644 } else { 0o755 };
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&output)
+        .conv(format!("Failed to open {}", output.to_string_lossy()))?;
+    file.write_all(text.as_bytes())
+        .conv(format!("Failed to write {}", output.to_string_lossy()))?;
+
+    println!("Wrote {}", output.to_string_lossy());
+    match init {
+        "openrc" => println!("Enable it with: rc-update add {} default", name),
+        "sysvinit" => println!("Enable it with: update-rc.d {} defaults", name),
+        _ => println!(
+            "Enable it with: systemctl daemon-reload && systemctl enable --now {}",
+            name
+        ),
+    }
+
+    return Ok(());
+}
+
+fn owner_of(path: &Path) -> Result<String, ExitValue> {
+    let metadata = fs::metadata(path).conv("Failed to read working directory")?;
+    return match User::from_uid(Uid::from_raw(metadata.uid())) {
+        Ok(Some(user)) => Ok(user.name),
+        _ => {
+            eprintln!(
+                "Failed to find the owner of {}, specify the user with --user",
+                path.to_string_lossy()
+            );
+            Err(ExitValue::Code(1))
+        }
+    };
+}
+
+/// Rebuilds the arguments for the start command from the ones given to this command. Paths are
+/// made absolute, since the service won't run from the current directory.
+fn start_args(
+    sub_m: &ArgMatches,
+    jar_file: &Path,
+    working_dir: &Path,
+) -> Result<Vec<String>, ExitValue> {
+    let mut args = Vec::<String>::new();
+
+    // The service may not run as the user whose registry holds the instance, so pass on what it
+    // resolves to instead of its name
+    if let Some(instance) = selected_instance(sub_m)? {
+        if !sub_m.is_present("CWD") {
+            args.push("--working-dir".to_string());
+            args.push(working_dir.to_string_lossy().into_owned());
+        }
+        if sub_m.occurrences_of("JAR") == 0 {
+            args.push("--jar".to_string());
+            args.push(jar_file.to_string_lossy().into_owned());
+        }
+        if let (Some(config_file), false) = (instance.config_file, sub_m.is_present("CONFIG_FILE"))
+        {
+            let path = canonicalize(&config_file).conv(format!(
+                "Failed to get full path to {}",
+                config_file.to_string_lossy()
+            ))?;
+            args.push("--config-file".to_string());
+            args.push(path.to_string_lossy().into_owned());
+        }
+    }
+
+    for (name, flag) in &[
+        ("JVM", "--jvm"),
+        ("JAR", "--jar"),
+        ("CWD", "--working-dir"),
+        ("CONFIG_FILE", "--config-file"),
+    ] {
+        // The jar has a default value, only pass it on when it was given explicitly
+        if sub_m.occurrences_of(name) == 0 {
+            continue;
+        }
+        if let Some(value) = sub_m.value_of(name) {
+            let path = canonicalize(value).conv(format!("Failed to get full path to {}", value))?;
+            args.push(flag.to_string());
+            args.push(path.to_string_lossy().into_owned());
+        }
+    }
+
+    if let Some(profile) = sub_m.value_of("PROFILE") {
+        args.push("--profile".to_string());
+        args.push(profile.to_string());
+    }
+    if let Some(memory) = sub_m.value_of("DEFAULT_ARGS") {
+        args.push("--default-args".to_string());
+        args.push(memory.to_string());
+    }
+    if let Some(preset) = sub_m.value_of("PRESET") {
+        args.push("--preset".to_string());
+        args.push(preset.to_string());
+    }
+    if let Some(headroom) = sub_m.value_of("HEAP_HEADROOM") {
+        args.push("--heap-headroom".to_string());
+        args.push(headroom.to_string());
+    }
+    if let Some(values) = sub_m.values_of("SERVER_ARGS") {
+        // Server args often look like flags themselves, so keep them attached to the option
+        for value in values {
+            args.push(format!("--server-arg={}", value));
+        }
+    }
+    if sub_m.is_present("KEEP_ALIVE") {
+        args.push("--keep-alive".to_string());
+    }
+    if let Some(values) = sub_m.values_of("CUSTOM_ARGS") {
+        args.push("--".to_string());
+        args.extend(values.map(|s| s.to_string()));
+    }
+
+    return Ok(args);
+}
+
+fn systemd_unit(name: &str, paperd: &Path, dir: &Path, user: &str, args: &[String]) -> String {
+    let mut exec_start = vec![
+        paperd.to_string_lossy().into_owned(),
+        "start".to_string(),
+        "--foreground".to_string(),
+        "--graceful-stop".to_string(),
+    ];
+    exec_start.extend(args.iter().cloned());
+    let exec_start: Vec<String> = exec_start.iter().map(|a| systemd_quote(a)).collect();
+
+    return format!(
+        r#"[Unit]
+Description=PaperMC server ({name})
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+User={user}
+WorkingDirectory={dir}
+ExecStart={exec_start}
+SuccessExitStatus={stop} {restart}
+Restart=on-failure
+RestartSec=10
+TimeoutStopSec=60
+
+[Install]
+WantedBy=multi-user.target
+"#,
+        name = name,
+        user = user,
+        dir = systemd_quote(&dir.to_string_lossy()),
+        exec_start = exec_start.join(" "),
+        stop = STOP_EXIT_CODE,
+        restart = RESTART_EXIT_CODE,
+    );
+}
+
+fn openrc_script(name: &str, paperd: &Path, dir: &Path, user: &str, args: &[String]) -> String {
+    let mut command_args = vec![
+        "start".to_string(),
+        "--foreground".to_string(),
+        "--graceful-stop".to_string(),
+    ];
+    command_args.extend(args.iter().cloned());
+    let command_args: Vec<String> = command_args.iter().map(|a| shell_quote(a)).collect();
+
+    return format!(
+        r#"#!/sbin/openrc-run
+
+name="{name}"
+description="PaperMC server ({name})"
+command={paperd}
+command_args="{command_args}"
+command_user={user}
+command_background=true
+directory={dir}
+pidfile="/run/${{RC_SVCNAME}}.pid"
+retry="SIGTERM/60/SIGKILL/5"
+
+depend() {{
+    need net
+}}
+"#,
+        name = name,
+        paperd = shell_quote(&paperd.to_string_lossy()),
+        command_args = command_args
+            .join(" ")
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('$', "\\$")
+            .replace('`', "\\`"),
+        user = shell_quote(user),
+        dir = shell_quote(&dir.to_string_lossy()),
+    );
+}
+
+fn sysvinit_script(name: &str, paperd: &Path, dir: &Path, user: &str, args: &[String]) -> String {
+    let paperd = shell_quote(&paperd.to_string_lossy());
+    let sock = shell_quote(&dir.join(SOCK_FILE_NAME).to_string_lossy());
+    let args: Vec<String> = args.iter().map(|a| shell_quote(a)).collect();
+
+    let start = format!(
+        "cd {} && {} start {}",
+        shell_quote(&dir.to_string_lossy()),
+        paperd,
+        args.join(" ")
+    );
+    let stop = format!("{} stop --force --sock {}", paperd, sock);
+    let status = format!("{} status --sock {}", paperd, sock);
+
+    return format!(
+        r#"#!/bin/sh
+### BEGIN INIT INFO
+# Provides:          {name}
+# Required-Start:    $remote_fs $network
+# Required-Stop:     $remote_fs $network
+# Default-Start:     2 3 4 5
+# Default-Stop:      0 1 6
+# Short-Description: PaperMC server ({name})
+### END INIT INFO
+
+USER={user}
+
+run() {{
+    if [ "$(id -un)" = "$USER" ]; then
+        sh -c "$1"
+    else
+        su -s /bin/sh -c "$1" "$USER"
+    fi
+}}
+
+case "$1" in
+    start)
+        run {start}
+        ;;
+    stop)
+        run {stop}
+        ;;
+    restart)
+        "$0" stop
+        "$0" start
+        ;;
+    status)
+        run {status}
+        ;;
+    *)
+        echo "Usage: $0 {{start|stop|restart|status}}"
+        exit 2
+        ;;
+esac
+"#,
+        name = name,
+        user = shell_quote(user),
+        start = shell_quote(&start),
+        stop = shell_quote(&stop),
+        status = shell_quote(&status),
+    );
+}
+
+/// Quotes an argument for a systemd command line, see systemd.service(5).
+fn systemd_quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    if !escaped.is_empty() && !escaped.contains(|c: char| c.is_whitespace() || c == '\'') {
+        return escaped;
+    }
+    return format!("\"{}\"", escaped);
+}
+
+/// Quotes an argument for a POSIX shell.
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:@,+".contains(c))
+    {
+        return arg.to_string();
+    }
+    return format!("'{}'", arg.replace('\'', "'\\''"));
+}
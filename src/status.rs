@@ -13,15 +13,28 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::protocol::check_protocol;
+use crate::pid::uptime;
+use crate::properties::read_properties;
+use crate::protocol::{check_protocol, request_protocol, ServerProtocol, CAPABILITY_STATUS};
+use crate::registry::{instance_state, load_registry, registry_files_text, Instance};
 use crate::util;
-use crate::util::{get_sock, ExitValue};
+use crate::util::{format_duration, get_sock, get_sock_from_file_direct, print_table, ExitValue};
 use clap::ArgMatches;
 use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long `status --all` waits for each server to answer
+const FLEET_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub fn status(sub_m: &ArgMatches) -> Result<(), ExitValue> {
+    if sub_m.is_present("ALL") {
+        return fleet_status();
+    }
+
     let (sock, _) = get_sock(sub_m)?;
-    check_protocol(&sock)?;
+    let protocol = check_protocol(&sock, &[CAPABILITY_STATUS])?;
 
     let message = StatusMessage {};
 
@@ -29,13 +42,140 @@ pub fn status(sub_m: &ArgMatches) -> Result<(), ExitValue> {
 
     let res = sock.receive_message::<StatusMessageResponse>()?;
 
-    output_status(&res);
+    output_status(&res, &protocol);
+
+    return Ok(());
+}
+
+/// Queries all registered instances at once and prints a line for each of them.
+fn fleet_status() -> Result<(), ExitValue> {
+    let instances = load_registry()?;
+    if instances.is_empty() {
+        println!(
+            "No instances registered. Register them by name in {}.",
+            registry_files_text()
+        );
+        return Ok(());
+    }
+
+    let handles: Vec<_> = instances
+        .into_iter()
+        .map(|(_, instance)| {
+            let name = instance.name.clone();
+            (name, thread::spawn(move || fleet_row(&instance)))
+        })
+        .collect();
+    // An instance whose query panicked still gets its row, so it doesn't go missing from the table
+    let rows: Vec<Vec<String>> = handles
+        .into_iter()
+        .map(|(name, handle)| {
+            handle.join().unwrap_or_else(|_| {
+                fleet_row_without_status(&name, "error".to_string(), "-".to_string())
+            })
+        })
+        .collect();
 
+    print_table(
+        &[
+            "NAME", "STATE", "TPS", "PLAYERS", "MEMORY", "VERSION", "UPTIME",
+        ],
+        &rows,
+    );
     return Ok(());
 }
 
+enum FleetQuery {
+    /// Nothing is listening on the socket
+    NoServer,
+    NoAnswer,
+    /// The server answered, but can't be asked for its status. Has the state to show instead.
+    Unsupported(String),
+    Status(Box<StatusMessageResponse>),
+}
+
+fn fleet_row(instance: &Instance) -> Vec<String> {
+    let query = query_status(&instance.sock_file(), FLEET_TIMEOUT);
+    let (state, pid) = match query {
+        FleetQuery::NoServer | FleetQuery::NoAnswer => instance_state(instance, false),
+        _ => instance_state(instance, true),
+    };
+    let uptime = pid
+        .and_then(uptime)
+        .map_or_else(|| "-".to_string(), format_duration);
+
+    let status = match query {
+        FleetQuery::Status(status) => status,
+        FleetQuery::Unsupported(state) => {
+            return fleet_row_without_status(&instance.name, state, uptime);
+        }
+        // The server is still starting or has hung
+        FleetQuery::NoAnswer => {
+            return fleet_row_without_status(&instance.name, "no answer".to_string(), uptime);
+        }
+        _ => return fleet_row_without_status(&instance.name, state.to_string(), uptime),
+    };
+
+    let max_players = read_properties(&instance.working_dir)
+        .and_then(|p| p.get("max-players").cloned())
+        .map_or_else(String::new, |max| format!("/{}", max));
+    return vec![
+        instance.name.clone(),
+        state.to_string(),
+        format!(
+            "{:.1} {:.1} {:.1}",
+            util::tps_cap(status.tps.one_min),
+            util::tps_cap(status.tps.five_min),
+            util::tps_cap(status.tps.fifteen_min)
+        ),
+        format!("{}{}", status.players.len(), max_players),
+        format!(
+            "{} / {}",
+            status.memory_usage.used_memory, status.memory_usage.max_memory
+        ),
+        status.server_version,
+        uptime,
+    ];
+}
+
+fn fleet_row_without_status(name: &str, state: String, uptime: String) -> Vec<String> {
+    let mut row = vec![name.to_string(), state];
+    row.extend(vec!["-".to_string(); 4]);
+    row.push(uptime);
+    return row;
+}
+
+/// Asks the server for its status without printing any errors, giving up after `timeout`.
+fn query_status(sock_file: &Path, timeout: Duration) -> FleetQuery {
+    let mut sock = match get_sock_from_file_direct(sock_file) {
+        Ok(s) => s,
+        Err(_) => return FleetQuery::NoServer,
+    };
+    sock.print_err = false;
+
+    let deadline = Instant::now() + timeout;
+    match request_protocol(&sock, deadline) {
+        Some(protocol) if !protocol.is_compatible() => {
+            let state = format!("incompatible ({})", protocol.versions_text());
+            return FleetQuery::Unsupported(state);
+        }
+        Some(protocol) if !protocol.missing(&[CAPABILITY_STATUS]).is_empty() => {
+            return FleetQuery::Unsupported("no status support".to_string());
+        }
+        Some(_) => {}
+        None => return FleetQuery::NoAnswer,
+    }
+
+    if sock.send_message(&StatusMessage {}).is_err() {
+        return FleetQuery::NoAnswer;
+    }
+    return match sock.receive_loop::<StatusMessageResponse, _>(|| Instant::now() < deadline) {
+        Ok(status) => FleetQuery::Status(Box::new(status)),
+        Err(_) => FleetQuery::NoAnswer,
+    };
+}
+
 #[cfg_attr(rustfmt, rustfmt_skip)]
-fn output_status(status: &StatusMessageResponse) {
+fn output_status(status: &StatusMessageResponse, protocol: &ServerProtocol) {
     let line_length = 60;
 
     println!("======================= Server Info =======================");
@@ -46,6 +186,8 @@ fn output_status(status: &StatusMessageResponse) {
     println!("------------------------- Version --------------------------");
     println!("  PaperMC Server Version | {}", status.server_version);
     println!("  Bukkit API Version     | {}", status.api_version);
+    println!("  Protocol Version       | {} (server speaks {})", protocol.version, protocol.versions_text());
+    println!("  Capabilities           | {}", protocol.capabilities.join(", "));
     println!();
     println!("------------------------  Worlds  --------------------------");
 
@@ -125,11 +267,11 @@ pub(crate) struct StatusMessageResponse {
     #[serde(rename = "apiVersion")]
     api_version: String,
     #[serde(rename = "players")]
-    players: Vec<String>,
+    pub(crate) players: Vec<String>,
     #[serde(rename = "worlds")]
     worlds: Vec<WorldStatus>,
     #[serde(rename = "tps")]
-    tps: TpsStatus,
+    pub(crate) tps: TpsStatus,
     #[serde(rename = "memoryUsage")]
     memory_usage: MemoryStatus,
 }
@@ -151,13 +293,13 @@ struct WorldStatus {
 }
 
 #[derive(Deserialize)]
-struct TpsStatus {
+pub(crate) struct TpsStatus {
     #[serde(rename = "oneMin")]
-    one_min: f64,
+    pub(crate) one_min: f64,
     #[serde(rename = "fiveMin")]
-    five_min: f64,
+    pub(crate) five_min: f64,
     #[serde(rename = "fifteenMin")]
-    fifteen_min: f64,
+    pub(crate) fifteen_min: f64,
 }
 
 #[derive(Deserialize)]
@@ -13,54 +13,90 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::protocol::check_protocol;
-use crate::util::{find_sock_file, get_pid, get_sock_from_file, ExitValue};
+use crate::broadcast::{broadcast, find_targets};
+use crate::protocol::{check_protocol, CAPABILITY_STOP};
+use crate::util::{check_sock_file, get_pid, get_sock_from_file, ExitValue};
+use crate::watchdog::request_stop;
 use clap::ArgMatches;
 use nix::errno::Errno::ESRCH;
-use nix::sys::signal::{kill, SIGKILL};
+use nix::sys::signal::{kill, SIGKILL, SIGTERM};
 use nix::unistd::Pid;
 use nix::Error;
 use serde::Serialize;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::thread::sleep;
 use std::time::Duration;
 use std::{fs, io};
 
+const TERM_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub fn stop(sub_m: &ArgMatches) -> Result<(), ExitValue> {
-    let sock_file = find_sock_file(sub_m)?;
+    let force = sub_m.is_present("FORCE");
+    let kill_now = sub_m.is_present("KILL");
+
+    let targets = find_targets(sub_m)?;
+    if targets.len() > 1 {
+        return broadcast(
+            targets,
+            sub_m.is_present("SERIAL"),
+            "stopped",
+            move |target| {
+                return stop_server(target.sock_file.clone(), force, kill_now, false);
+            },
+        );
+    }
+
+    return match targets.into_iter().next() {
+        Some(target) => stop_server(target.sock_file, force, kill_now, true),
+        None => Err(ExitValue::Code(1)),
+    };
+}
+
+/// Stops the server listening on `sock_file`. Progress is only printed with `progress`, as it
+/// can't be told apart when several servers are stopped at once.
+fn stop_server(
+    sock_file: PathBuf,
+    force: bool,
+    kill_now: bool,
+    progress: bool,
+) -> Result<(), ExitValue> {
+    let sock_file = check_sock_file(sock_file)?;
     let (pid_file, pid) = get_pid(&sock_file)?;
 
-    if sub_m.is_present("KILL") {
+    if kill_now {
         force_kill(&sock_file, &pid_file, pid);
-        println!("Server killed");
+        if progress {
+            println!("Server killed");
+        }
         return Ok(());
     }
 
-    let sock = get_sock_from_file(&sock_file)?;
-    check_protocol(&sock)?;
-
-    let message = StopMessage {};
-
-    println!("Sending stop command to the server..");
-    sock.send_message(&message)?;
+    if progress {
+        println!("Sending stop command to the server..");
+    }
+    send_stop(&sock_file)?;
 
-    print!("Waiting for server to exit.");
-    let _ = io::stdout().flush();
+    if progress {
+        print!("Waiting for server to exit.");
+        let _ = io::stdout().flush();
+    }
     // If -f is set then we need to wait to see if it fails
-    for _ in 0..30 {
-        if let Err(_) = kill(pid, None) {
-            break;
+    wait_for_exit(pid, Duration::from_secs(15), || {
+        if progress {
+            print!(".");
+            let _ = io::stdout().flush();
         }
-        sleep(Duration::from_millis(500));
-        print!(".");
-        let _ = io::stdout().flush();
+    });
+    if progress {
+        println!();
     }
-    println!();
 
     if let Err(Error::Sys(e)) = kill(pid, None) {
         return if e == ESRCH {
-            println!("Server exited successfully");
+            if progress {
+                println!("Server exited successfully");
+            }
             Ok(())
         } else {
             println!("Unknown error occurred (stop): {}", e);
@@ -68,18 +104,74 @@ pub fn stop(sub_m: &ArgMatches) -> Result<(), ExitValue> {
         };
     }
 
-    if !sub_m.is_present("FORCE") {
+    if !force {
         println!("Server failed to exit cleanly");
         return Err(ExitValue::Code(1));
     }
 
-    println!("Server failed to exit cleanly, killing now");
+    if progress {
+        println!("Server failed to exit cleanly, killing now");
+    }
     force_kill(&sock_file, &pid_file, pid);
-    println!("Server killed");
+    if progress {
+        println!("Server killed");
+    }
 
     return Ok(());
 }
 
+/// Asks the server to shut down through its socket.
+pub fn send_stop<P: AsRef<Path>>(sock_file: P) -> Result<(), ExitValue> {
+    let sock = get_sock_from_file(&sock_file)?;
+    check_protocol(&sock, &[CAPABILITY_STOP])?;
+
+    let message = StopMessage {};
+    sock.send_message(&message)?;
+    request_stop(&sock_file);
+
+    return Ok(());
+}
+
+/// Waits up to `timeout` for the process to exit, returning `true` if it did. `on_tick` is called
+/// every time the process is checked and found still running.
+pub fn wait_for_exit<F: Fn()>(pid: Pid, timeout: Duration, on_tick: F) -> bool {
+    let interval = Duration::from_millis(500);
+    let mut waited = Duration::from_secs(0);
+    while waited < timeout {
+        if let Err(_) = kill(pid, None) {
+            return true;
+        }
+        sleep(interval);
+        waited += interval;
+        on_tick();
+    }
+
+    return kill(pid, None).is_err();
+}
+
+/// Stops the server the same way `paperd stop --force` would, but with signals as the fallback:
+/// the server is asked to stop through its socket, and if it hasn't exited after `timeout` it's sent
+/// SIGTERM, and then SIGKILL. The `escalate` callback is given the name of each step as it's taken.
+pub fn stop_gracefully<P, F>(sock_file: P, pid: Pid, timeout: Duration, escalate: F)
+where
+    P: AsRef<Path>,
+    F: Fn(&str),
+{
+    escalate("socket");
+    if send_stop(sock_file).is_ok() && wait_for_exit(pid, timeout, || {}) {
+        return;
+    }
+
+    escalate("SIGTERM");
+    let _ = kill(pid, SIGTERM);
+    if wait_for_exit(pid, TERM_TIMEOUT, || {}) {
+        return;
+    }
+
+    escalate("SIGKILL");
+    let _ = kill(pid, SIGKILL);
+}
+
 fn force_kill<P: AsRef<Path>>(sock_file: P, pid_file: P, pid: Pid) {
     let _ = kill(pid, SIGKILL);
     let _ = fs::remove_file(&sock_file);
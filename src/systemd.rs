@@ -0,0 +1,370 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::protocol::ping_server;
+use crate::status::{StatusMessage, StatusMessageResponse};
+use crate::util;
+use crate::util::get_sock_from_file_direct;
+use nix::libc;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant};
+use std::{env, process, thread};
+
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const TICK: Duration = Duration::from_secs(1);
+
+/// Sends state notifications to systemd through the datagram socket named by `NOTIFY_SOCKET`,
+/// see sd_notify(3). Names starting with `@` refer to the abstract socket namespace.
+pub struct Notifier {
+    sock: UnixDatagram,
+    addr: NotifyAddr,
+    watchdog: Option<Duration>,
+    ready: AtomicBool,
+    stopping: AtomicBool,
+    /// Whether the server answers on its socket, or isn't expected to, as there is none running
+    server_responsive: AtomicBool,
+}
+
+impl Notifier {
+    /// Returns `None` when paperd isn't running under a `Type=notify` service.
+    pub fn from_env() -> Option<Arc<Notifier>> {
+        let path = env::var_os("NOTIFY_SOCKET")?;
+        return Notifier::new(&path.to_string_lossy(), watchdog_interval());
+    }
+
+    /// Creates a notifier for the socket at `path`, which pings the watchdog every `watchdog` for
+    /// as long as paperd runs, as long as the server is responsive.
+    fn new(path: &str, watchdog: Option<Duration>) -> Option<Arc<Notifier>> {
+        // The abstract name follows a leading nul byte in the address
+        let (addr, len) = if path.starts_with('@') {
+            (
+                NotifyAddr::Abstract(path.as_bytes()[1..].to_vec()),
+                path.len(),
+            )
+        } else {
+            (NotifyAddr::Path(PathBuf::from(path)), path.len() + 1)
+        };
+        if path.is_empty() || path == "@" || len > sun_path_len() {
+            eprintln!("Invalid NOTIFY_SOCKET {}", path);
+            return None;
+        }
+
+        // Sockets from std are closed on exec, so the server doesn't inherit this one
+        let sock = match UnixDatagram::unbound() {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Failed to create socket for NOTIFY_SOCKET: {}", e);
+                return None;
+            }
+        };
+
+        let notifier = Arc::new(Notifier {
+            sock,
+            addr,
+            watchdog,
+            ready: AtomicBool::new(false),
+            stopping: AtomicBool::new(false),
+            server_responsive: AtomicBool::new(true),
+        });
+
+        // paperd has to keep pinging while no server runs as well, such as in hooks and while
+        // waiting to restart a crashed server, or systemd kills it in the middle of a restart
+        if let Some(interval) = watchdog {
+            let pinger = notifier.clone();
+            thread::spawn(move || loop {
+                if pinger.server_responsive.load(Ordering::SeqCst) {
+                    pinger.notify("WATCHDOG=1");
+                }
+                sleep(interval);
+            });
+        }
+
+        return Some(notifier);
+    }
+
+    pub fn notify(&self, state: &str) {
+        match &self.addr {
+            NotifyAddr::Path(path) => {
+                let _ = self.sock.send_to(state.as_bytes(), path);
+            }
+            NotifyAddr::Abstract(name) => {
+                let (addr, len) = abstract_addr(name);
+                unsafe {
+                    libc::sendto(
+                        self.sock.as_raw_fd(),
+                        state.as_ptr() as *const libc::c_void,
+                        state.len(),
+                        0,
+                        &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                        len,
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn ready(&self) {
+        if !self.ready.swap(true, Ordering::SeqCst) {
+            self.notify("READY=1");
+        }
+    }
+
+    pub fn stopping(&self) {
+        if !self.stopping.swap(true, Ordering::SeqCst) {
+            self.notify("STOPPING=1");
+        }
+    }
+
+    /// Stops the watchdog pings while the server is hung, so systemd notices.
+    fn set_server_responsive(&self, responsive: bool) {
+        self.server_responsive.store(responsive, Ordering::SeqCst);
+    }
+}
+
+enum NotifyAddr {
+    Path(PathBuf),
+    /// A name in the abstract socket namespace, without the leading `@`
+    Abstract(Vec<u8>),
+}
+
+/// std can only send to sockets with a path, so the address of an abstract socket is built by hand.
+/// `name` has to fit in `sun_path` after the leading nul byte.
+fn abstract_addr(name: &[u8]) -> (libc::sockaddr_un, libc::socklen_t) {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dest, byte) in addr.sun_path[1..].iter_mut().zip(name) {
+        *dest = *byte as libc::c_char;
+    }
+    // Abstract names aren't nul terminated, the length of the address says where they end
+    let len = mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+    return (addr, len as libc::socklen_t);
+}
+
+fn sun_path_len() -> usize {
+    let addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    return addr.sun_path.len();
+}
+
+/// systemd asks for `WATCHDOG=1` at least every `WATCHDOG_USEC` microseconds, as long as
+/// `WATCHDOG_PID` is unset or refers to us. We notify twice as often to leave some headroom.
+fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        if pid.to_string_lossy() != process::id().to_string() {
+            return None;
+        }
+    }
+
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    return Some(Duration::from_micros(usec / 2));
+}
+
+/// Keeps systemd updated about a single run of the server: `READY=1` once the server first answers
+/// on its socket, periodic `STATUS=` lines and `STOPPING=1` once paperd has been asked to stop. It
+/// also tells the notifier whether the server is responsive, which holds back watchdog pings.
+pub struct Monitor {
+    notifier: Arc<Notifier>,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Monitor {
+    pub fn start(notifier: Arc<Notifier>, sock_file: PathBuf, stopping: Arc<AtomicBool>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_stop = stop.clone();
+        let thread_notifier = notifier.clone();
+        let handle = thread::spawn(move || {
+            let notifier = thread_notifier;
+            let mut last_status: Option<Instant> = None;
+            let mut last_watchdog: Option<Instant> = None;
+            // Until the server is up in this run there's nothing to ask
+            let mut answered = false;
+
+            while !thread_stop.load(Ordering::SeqCst) {
+                if stopping.load(Ordering::SeqCst) {
+                    notifier.stopping();
+                }
+
+                if !answered && ping_server(&sock_file, QUERY_TIMEOUT) {
+                    answered = true;
+                    notifier.ready();
+                }
+
+                if answered && is_due(last_status, STATUS_INTERVAL) {
+                    last_status = Some(Instant::now());
+                    if let Some(status) = status_line(&sock_file) {
+                        notifier.notify(&format!("STATUS={}", status));
+                    }
+                }
+
+                if let Some(interval) = notifier.watchdog {
+                    if is_due(last_watchdog, interval) {
+                        last_watchdog = Some(Instant::now());
+                        let responsive = !answered || ping_server(&sock_file, QUERY_TIMEOUT);
+                        notifier.set_server_responsive(responsive);
+                    }
+                }
+
+                sleep(TICK);
+            }
+        });
+
+        return Monitor {
+            notifier,
+            stop,
+            handle,
+        };
+    }
+
+    pub fn finish(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.handle.join();
+        // The server is gone, paperd itself is what's alive now
+        self.notifier.set_server_responsive(true);
+    }
+}
+
+fn is_due(last: Option<Instant>, interval: Duration) -> bool {
+    return last.map_or(true, |t| t.elapsed() >= interval);
+}
+
+fn status_line(sock_file: &Path) -> Option<String> {
+    let mut sock = get_sock_from_file_direct(sock_file).ok()?;
+    sock.print_err = false;
+
+    sock.send_message(&StatusMessage {}).ok()?;
+    let deadline = Instant::now() + QUERY_TIMEOUT;
+    let status = sock
+        .receive_loop::<StatusMessageResponse, _>(|| Instant::now() < deadline)
+        .ok()?;
+
+    return Some(format!(
+        "TPS: {:.2} {:.2} {:.2}, {} players online",
+        util::tps_cap(status.tps.one_min),
+        util::tps_cap(status.tps.five_min),
+        util::tps_cap(status.tps.fifteen_min),
+        status.players.len()
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::net::UnixDatagram;
+
+    fn receive(sock: &UnixDatagram) -> Option<String> {
+        let mut buf = [0; 256];
+        let len = sock.recv(&mut buf).ok()?;
+        return Some(String::from_utf8_lossy(&buf[..len]).into_owned());
+    }
+
+    #[test]
+    fn notifies_systemd() {
+        let path = env::temp_dir().join(format!("paperd-notify-test-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let sock = UnixDatagram::bind(&path).unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let notifier = Notifier::new(&path.to_string_lossy(), Some(Duration::from_millis(50)))
+            .expect("failed to create notifier");
+        notifier.ready();
+        notifier.ready();
+        notifier.notify("STATUS=TPS: 20.00 20.00 20.00, 0 players online");
+        notifier.stopping();
+        notifier.stopping();
+
+        let mut messages = Vec::new();
+        while messages.iter().filter(|m| *m == "WATCHDOG=1").count() < 2 {
+            messages.push(receive(&sock).expect("no watchdog ping"));
+        }
+        let _ = fs::remove_file(&path);
+
+        let others: Vec<&String> = messages.iter().filter(|m| *m != "WATCHDOG=1").collect();
+        assert_eq!(
+            others,
+            vec![
+                "READY=1",
+                "STATUS=TPS: 20.00 20.00 20.00, 0 players online",
+                "STOPPING=1"
+            ]
+        );
+    }
+
+    #[test]
+    fn holds_back_watchdog_while_server_is_hung() {
+        let path = env::temp_dir().join(format!("paperd-watchdog-test-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let sock = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Notifier::new(&path.to_string_lossy(), Some(Duration::from_millis(20)))
+            .expect("failed to create notifier");
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(receive(&sock).as_deref(), Some("WATCHDOG=1"));
+
+        notifier.set_server_responsive(false);
+        // A ping may already have been on its way
+        sleep(Duration::from_millis(100));
+        sock.set_nonblocking(true).unwrap();
+        while receive(&sock).is_some() {}
+        sock.set_nonblocking(false).unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert_eq!(receive(&sock), None);
+
+        notifier.set_server_responsive(true);
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(receive(&sock).as_deref(), Some("WATCHDOG=1"));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn notifies_abstract_socket() {
+        let name = format!("paperd-notify-test-{}", process::id());
+        let sock = UnixDatagram::unbound().unwrap();
+        let (addr, len) = abstract_addr(name.as_bytes());
+        let result = unsafe {
+            libc::bind(
+                sock.as_raw_fd(),
+                &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                len,
+            )
+        };
+        assert_eq!(result, 0);
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let notifier =
+            Notifier::new(&format!("@{}", name), None).expect("failed to create notifier");
+        notifier.ready();
+        assert_eq!(receive(&sock).as_deref(), Some("READY=1"));
+    }
+
+    #[test]
+    fn rejects_invalid_socket_names() {
+        assert!(Notifier::new("@", None).is_none());
+        assert!(Notifier::new(&"a".repeat(200), None).is_none());
+        assert!(Notifier::new(&format!("@{}", "a".repeat(200)), None).is_none());
+    }
+}
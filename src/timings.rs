@@ -15,14 +15,14 @@
 
 #[cfg(feature = "console")]
 use crate::console::ansi;
-use crate::protocol::check_protocol;
+use crate::protocol::{check_protocol, CAPABILITY_TIMINGS};
 use crate::util::{get_sock, ExitValue};
 use clap::ArgMatches;
 use serde::{Deserialize, Serialize};
 
 pub fn timings(sub_m: &ArgMatches) -> Result<(), ExitValue> {
     let (sock, _) = get_sock(sub_m)?;
-    check_protocol(&sock)?;
+    check_protocol(&sock, &[CAPABILITY_TIMINGS])?;
 
     let message = TimingsMessage {};
 
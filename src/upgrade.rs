@@ -0,0 +1,135 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::events::{Event, EventJournal};
+use crate::protocol::check_jar_protocol;
+use crate::restart::restart;
+use crate::util::{find_sock_path, ExitError, ExitValue};
+use clap::ArgMatches;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const STAGED_JAR_FILE_NAME: &'static str = "paperd-staged.jar";
+pub const ROLLBACK_JAR_FILE_NAME: &'static str = "paperd-rollback.jar";
+
+pub const DEFAULT_ROLLBACK_WINDOW: Duration = Duration::from_secs(300);
+
+pub fn upgrade(sub_m: &ArgMatches) -> Result<(), ExitValue> {
+    let jar_path = PathBuf::from(sub_m.value_of("JAR").unwrap());
+    if !jar_path.is_file() {
+        eprintln!("Could not find jar {}", jar_path.to_string_lossy());
+        return Err(ExitValue::Code(1));
+    }
+    check_jar_protocol(&jar_path)?;
+
+    let sock_path = find_sock_path(sub_m)?;
+    let working_dir = match sock_path.parent() {
+        Some(dir) => dir.to_path_buf(),
+        None => {
+            eprintln!(
+                "Failed to find server directory for socket {}",
+                sock_path.to_string_lossy()
+            );
+            return Err(ExitValue::Code(1));
+        }
+    };
+
+    let staged = working_dir.join(STAGED_JAR_FILE_NAME);
+    replace_file(&jar_path, &staged).conv("Failed to stage jar")?;
+    EventJournal::new(&working_dir).record(Event::Upgrade {
+        action: "stage",
+        reason: None,
+    });
+
+    println!(
+        "Staged {}, it will replace the server jar the next time paperd starts or restarts the server.",
+        jar_path.to_string_lossy()
+    );
+
+    if sub_m.is_present("RESTART") {
+        return restart(sub_m);
+    }
+
+    return Ok(());
+}
+
+/// Swaps a jar staged with the upgrade command in place of `jar_file`, keeping the current jar
+/// around so it can be rolled back to. Returns whether a new jar was swapped in.
+pub fn apply_staged(working_dir: &Path, jar_file: &Path, events: &EventJournal) -> bool {
+    let staged = working_dir.join(STAGED_JAR_FILE_NAME);
+    if !staged.is_file() {
+        return false;
+    }
+
+    let result = replace_file(jar_file, &working_dir.join(ROLLBACK_JAR_FILE_NAME))
+        .and_then(|_| replace_file(&staged, jar_file));
+    if let Err(e) = result {
+        let reason = format!("Failed to swap in staged jar: {}", e);
+        eprintln!("{}", reason);
+        events.record(Event::Upgrade {
+            action: "fail",
+            reason: Some(&reason),
+        });
+        return false;
+    }
+
+    let _ = fs::remove_file(&staged);
+    events.record(Event::Upgrade {
+        action: "swap",
+        reason: None,
+    });
+    return true;
+}
+
+/// Puts the jar which was replaced by the last upgrade back in place of `jar_file`.
+pub fn rollback(working_dir: &Path, jar_file: &Path, events: &EventJournal, reason: &str) {
+    let previous = working_dir.join(ROLLBACK_JAR_FILE_NAME);
+    match replace_file(&previous, jar_file) {
+        Ok(()) => {
+            eprintln!("Rolled back to the previous server jar: {}", reason);
+            events.record(Event::Upgrade {
+                action: "rollback",
+                reason: Some(reason),
+            });
+        }
+        Err(e) => {
+            let reason = format!("{}, but failed to roll back: {}", reason, e);
+            eprintln!("{}", reason);
+            events.record(Event::Upgrade {
+                action: "fail",
+                reason: Some(&reason),
+            });
+        }
+    }
+}
+
+/// Copies `from` over `to` through a temporary file next to `to`, so `to` is never left half
+/// written. The jar may be on another file system, so it can't simply be renamed.
+fn replace_file(from: &Path, to: &Path) -> io::Result<()> {
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(to.file_name().unwrap_or_default());
+    tmp_name.push(".tmp");
+    let tmp = to.with_file_name(tmp_name);
+
+    fs::copy(from, &tmp)?;
+    if let Err(e) = fs::rename(&tmp, to) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    return Ok(());
+}
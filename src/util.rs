@@ -14,6 +14,8 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use crate::messaging::MessageSocket;
+use crate::pid::{clean_stale, is_locked, read_pid_file, PidStatus};
+use crate::registry::selected_instance;
 use crate::runner;
 use crate::runner::PID_FILE_NAME;
 use clap::ArgMatches;
@@ -21,7 +23,8 @@ use nix::unistd::Pid;
 use paperd_lib::{connect_socket, Error};
 use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
-use std::{env, fs, io};
+use std::time::Duration;
+use std::{env, io};
 
 pub fn get_sock(sub_m: &ArgMatches) -> Result<(MessageSocket, PathBuf), ExitValue> {
     let sock_file = find_sock_file(sub_m)?;
@@ -31,11 +34,16 @@ pub fn get_sock(sub_m: &ArgMatches) -> Result<(MessageSocket, PathBuf), ExitValu
 }
 
 pub fn find_sock_file(sub_m: &ArgMatches) -> Result<PathBuf, ExitValue> {
-    let sock_file = sub_m
-        .value_of("SOCK")
-        .map(PathBuf::from)
-        .or_else(|| env::var_os("PAPERD_SOCK").map(PathBuf::from))
-        .unwrap_or_else(|| PathBuf::from(runner::SOCK_FILE_NAME));
+    return check_sock_file(find_sock_path(sub_m)?);
+}
+
+/// Makes sure there's a socket file to send commands to, after cleaning up one left behind by a
+/// server which isn't running anymore.
+pub fn check_sock_file(sock_file: PathBuf) -> Result<PathBuf, ExitValue> {
+    // Don't try to talk to a server which is gone
+    if let Some(dir) = sock_file.parent() {
+        clean_stale(dir)?;
+    }
 
     if !sock_file.exists() {
         eprintln!("No socket file found to send commands to");
@@ -45,6 +53,19 @@ pub fn find_sock_file(sub_m: &ArgMatches) -> Result<PathBuf, ExitValue> {
     return Ok(sock_file);
 }
 
+/// Returns where the socket file is expected to be, without checking whether it exists.
+pub fn find_sock_path(sub_m: &ArgMatches) -> Result<PathBuf, ExitValue> {
+    if let Some(sock) = sub_m.value_of("SOCK") {
+        return Ok(PathBuf::from(sock));
+    }
+    if let Some(instance) = selected_instance(sub_m)? {
+        return Ok(instance.sock_file());
+    }
+    return Ok(env::var_os("PAPERD_SOCK")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(runner::SOCK_FILE_NAME)));
+}
+
 pub fn get_sock_from_file_direct<P: AsRef<Path>>(sock_file: P) -> Result<MessageSocket, Error> {
     let sock = connect_socket(sock_file.as_ref())?;
 
@@ -80,32 +101,136 @@ pub fn find_program(searches: &[(&str, &str)]) -> Option<PathBuf> {
 }
 
 pub fn get_pid<P: AsRef<Path>>(sock_file: P) -> Result<(PathBuf, Pid), ExitValue> {
-    let pid_file = match sock_file.as_ref().parent().map(|p| p.join(PID_FILE_NAME)) {
-        Some(path) => path,
+    let dir = match sock_file.as_ref().parent() {
+        Some(dir) => dir.to_path_buf(),
         None => {
             eprintln!("Failed to find PID file {}", PID_FILE_NAME);
             return Err(ExitValue::Code(1));
         }
     };
+    let pid_file = dir.join(PID_FILE_NAME);
 
-    let pid_text = fs::read_to_string(&pid_file).conv("Failed to read PID file")?;
-    let pid = Pid::from_raw(match pid_text.parse::<i32>() {
-        Ok(p) => p,
-        Err(e) => {
-            eprintln!("Failed to parse PID file: {}", e);
-            fs::remove_file(&pid_file).conv("Failed to delete PID file")?;
+    return match read_pid_file(&pid_file)? {
+        PidStatus::Running(pid) => Ok((pid_file, pid)),
+        PidStatus::Stale(_) | PidStatus::Missing if is_locked(&dir) => {
+            eprintln!(
+                "The server is running, but its PID file {} is missing or doesn't belong to it",
+                pid_file.to_string_lossy()
+            );
+            Err(ExitValue::Code(1))
+        }
+        PidStatus::Stale(_) => {
+            clean_stale(&dir)?;
             eprintln!("No server found to send commands to");
-            return Err(ExitValue::Code(1));
+            Err(ExitValue::Code(1))
         }
-    });
-
-    return Ok((pid_file, pid));
+        PidStatus::Missing => {
+            eprintln!("Failed to read PID file {}", pid_file.to_string_lossy());
+            Err(ExitValue::Code(1))
+        }
+    };
 }
 
 pub fn tps_cap(tps: f64) -> f64 {
     return tps.min(20.0);
 }
 
+/// Parses durations such as `500ms`, `30s`, `10m` or `1h`. A number without a unit is treated as
+/// seconds.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    if split == 0 {
+        return None;
+    }
+
+    let value = text[..split].parse::<u64>().ok()?;
+    return match &text[split..] {
+        "ms" => Some(Duration::from_millis(value)),
+        "" | "s" => Some(Duration::from_secs(value)),
+        "m" => value.checked_mul(60).map(Duration::from_secs),
+        "h" => value.checked_mul(60 * 60).map(Duration::from_secs),
+        "d" => value.checked_mul(60 * 60 * 24).map(Duration::from_secs),
+        _ => None,
+    };
+}
+
+/// Parses sizes such as `512k`, `10M`, `1G` or `1T` into a number of bytes. A number without a unit
+/// is treated as bytes.
+pub fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    if split == 0 {
+        return None;
+    }
+
+    let value = text[..split].parse::<u64>().ok()?;
+    return match &text[split..] {
+        "" => Some(value),
+        "k" | "K" => value.checked_mul(1024),
+        "m" | "M" => value.checked_mul(1024 * 1024),
+        "g" | "G" => value.checked_mul(1024 * 1024 * 1024),
+        "t" | "T" => value.checked_mul(1024 * 1024 * 1024 * 1024),
+        _ => None,
+    };
+}
+
+pub fn parse_duration_arg(name: &str, text: &str) -> Result<Duration, ExitValue> {
+    return match parse_duration(text) {
+        Some(d) => Ok(d),
+        None => {
+            eprintln!(
+                "Invalid duration for {}: {}. Should be something like 500ms, 30s, 10m or 1h.",
+                name, text
+            );
+            Err(ExitValue::Code(1))
+        }
+    };
+}
+
+/// Formats a duration in its two largest units, like `3d 4h`, `2h 15m` or `40s`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    return if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, secs % 60)
+    } else {
+        format!("{}s", secs)
+    };
+}
+
+/// Prints rows of text as a table, with every column as wide as its widest cell.
+pub fn print_table<S: AsRef<str>>(header: &[&str], rows: &[Vec<S>]) {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.as_ref().chars().count());
+        }
+    }
+
+    let print_row = |cells: Vec<&str>| {
+        let line = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:1$}", cell, width))
+            .collect::<Vec<String>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+    print_row(header.to_vec());
+    for row in rows {
+        print_row(row.iter().map(|c| c.as_ref()).collect());
+    }
+}
+
 #[derive(Clone)]
 pub enum ExitValue {
     Code(i32),
@@ -145,3 +270,47 @@ impl<T> ExitError<T> for Result<T, paperd_lib::Error> {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration(" 30s "), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("7d"), Some(Duration::from_secs(7 * 86400)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("-1s"), None);
+        assert_eq!(parse_duration("1.5s"), None);
+        assert_eq!(parse_duration("10 m"), None);
+        assert_eq!(parse_duration("1w"), None);
+        assert_eq!(parse_duration("18446744073709551615d"), None);
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("512k"), Some(512 * 1024));
+        assert_eq!(parse_size("10M"), Some(10 * 1024 * 1024));
+        assert_eq!(parse_size("1g"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("1T"), Some(1024 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("1GB"), None);
+        assert_eq!(parse_size("1.5G"), None);
+        assert_eq!(parse_size("99999999999T"), None);
+    }
+}
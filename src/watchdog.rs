@@ -0,0 +1,259 @@
+// This file is part of paperd, the PaperMC server daemon
+// Copyright (C) 2019 Kyle Wood (DemonWav)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 only.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::protocol::ping_server;
+use nix::sys::signal::{kill, SIGKILL, SIGQUIT};
+use nix::unistd::Pid;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_WATCHDOG_FAILURES: u32 = 3;
+/// Created next to the socket when a stop or restart is sent to the server
+pub const STOP_REQUEST_FILE_NAME: &'static str = "paperd.stopping";
+
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+const THREAD_DUMP_WAIT: Duration = Duration::from_secs(3);
+const TICK: Duration = Duration::from_millis(100);
+
+#[derive(Clone)]
+pub struct WatchdogConfig {
+    pub interval: Duration,
+    /// Number of pings in a row which have to fail before the server is considered hung
+    pub max_failures: u32,
+}
+
+pub struct Watchdog {
+    stop: Arc<AtomicBool>,
+    hung: Arc<AtomicBool>,
+    stop_request: PathBuf,
+    handle: JoinHandle<()>,
+}
+
+impl Watchdog {
+    /// Starts pinging the server on `sock_file` in the background. Failed pings only count once
+    /// the server has answered at least once, so a slow startup won't trigger a restart. When the
+    /// server stops responding a thread dump is requested with SIGQUIT, which the JVM writes to its
+    /// stdout, before the server is killed.
+    ///
+    /// The watchdog stands down once the server is shutting down, either because `stopping` was
+    /// set by paperd or because a stop or restart was sent to the server through its socket (see
+    /// `request_stop`), so a slow but orderly shutdown isn't cut short.
+    pub fn start(
+        config: WatchdogConfig,
+        sock_file: PathBuf,
+        server_pid: u32,
+        stopping: Arc<AtomicBool>,
+    ) -> Self {
+        let stop_request = stop_request_file(&sock_file);
+        return Watchdog::start_with(config, stop_request, server_pid, stopping, move || {
+            return ping_server(&sock_file, PING_TIMEOUT);
+        });
+    }
+
+    fn start_with<F>(
+        config: WatchdogConfig,
+        stop_request: PathBuf,
+        server_pid: u32,
+        stopping: Arc<AtomicBool>,
+        ping: F,
+    ) -> Self
+    where
+        F: Fn() -> bool + Send + 'static,
+    {
+        // Left over from an earlier run of the server, which is gone now
+        let _ = fs::remove_file(&stop_request);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let hung = Arc::new(AtomicBool::new(false));
+
+        let thread_stop = stop.clone();
+        let thread_hung = hung.clone();
+        let thread_stop_request = stop_request.clone();
+        let handle = thread::spawn(move || {
+            let pid = Pid::from_raw(server_pid as i32);
+            let standing_down = || {
+                return thread_stop.load(Ordering::SeqCst)
+                    || stopping.load(Ordering::SeqCst)
+                    || thread_stop_request.exists();
+            };
+            let mut answered = false;
+            let mut failures = 0;
+
+            loop {
+                let next = Instant::now() + config.interval;
+                while Instant::now() < next {
+                    if standing_down() {
+                        return;
+                    }
+                    sleep(TICK);
+                }
+
+                if ping() {
+                    answered = true;
+                    failures = 0;
+                    continue;
+                }
+                if !answered {
+                    continue;
+                }
+
+                failures += 1;
+                if failures < config.max_failures {
+                    continue;
+                }
+
+                if standing_down() {
+                    return;
+                }
+                thread_hung.store(true, Ordering::SeqCst);
+                let _ = kill(pid, SIGQUIT);
+                sleep(THREAD_DUMP_WAIT);
+                let _ = kill(pid, SIGKILL);
+                return;
+            }
+        });
+
+        return Watchdog {
+            stop,
+            hung,
+            stop_request,
+            handle,
+        };
+    }
+
+    /// Stops the watchdog, returning `true` if it killed the server for not responding.
+    pub fn finish(self) -> bool {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.handle.join();
+        let _ = fs::remove_file(&self.stop_request);
+        return self.hung.load(Ordering::SeqCst);
+    }
+}
+
+/// Lets the watchdog of the server on `sock_file` know that the server was asked to stop or
+/// restart, so it doesn't kill the server while it shuts down. Servers without a watchdog simply
+/// ignore this.
+pub fn request_stop<P: AsRef<Path>>(sock_file: P) {
+    let _ = File::create(stop_request_file(sock_file.as_ref()));
+}
+
+fn stop_request_file(sock_file: &Path) -> PathBuf {
+    return sock_file.with_file_name(STOP_REQUEST_FILE_NAME);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Child, Command};
+    use std::sync::atomic::AtomicU32;
+
+    fn config() -> WatchdogConfig {
+        return WatchdogConfig {
+            interval: Duration::from_millis(200),
+            max_failures: 2,
+        };
+    }
+
+    fn server() -> Child {
+        return Command::new("sleep").arg("30").spawn().unwrap();
+    }
+
+    /// Answers the first ping only, like a server which hangs or starts shutting down after it
+    /// came up.
+    fn answers_once() -> impl Fn() -> bool + Send + 'static {
+        let pings = AtomicU32::new(0);
+        return move || pings.fetch_add(1, Ordering::SeqCst) == 0;
+    }
+
+    fn stop_request(dir: &Path) -> PathBuf {
+        let _ = fs::create_dir_all(dir);
+        return stop_request_file(&dir.join("paper.sock"));
+    }
+
+    #[test]
+    fn kills_a_hung_server() {
+        let mut server = server();
+        let dir = std::env::temp_dir().join(format!("paperd-watchdog-hung-{}", server.id()));
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let watchdog = Watchdog::start_with(
+            config(),
+            stop_request(&dir),
+            server.id(),
+            stopping,
+            answers_once(),
+        );
+        let status = server.wait().unwrap();
+
+        assert!(watchdog.finish());
+        assert!(!status.success());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn stands_down_while_paperd_stops_the_server() {
+        let mut server = server();
+        let dir = std::env::temp_dir().join(format!("paperd-watchdog-stopping-{}", server.id()));
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let watchdog = Watchdog::start_with(
+            config(),
+            stop_request(&dir),
+            server.id(),
+            stopping.clone(),
+            answers_once(),
+        );
+        sleep(Duration::from_millis(250));
+        stopping.store(true, Ordering::SeqCst);
+        sleep(Duration::from_millis(500));
+
+        assert!(server.try_wait().unwrap().is_none());
+        assert!(!watchdog.finish());
+        let _ = server.kill();
+        let _ = server.wait();
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn stands_down_once_a_stop_was_sent_to_the_server() {
+        let mut server = server();
+        let dir = std::env::temp_dir().join(format!("paperd-watchdog-request-{}", server.id()));
+        let sock_file = dir.join("paper.sock");
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let watchdog = Watchdog::start_with(
+            config(),
+            stop_request(&dir),
+            server.id(),
+            stopping,
+            answers_once(),
+        );
+        sleep(Duration::from_millis(250));
+        request_stop(&sock_file);
+        sleep(Duration::from_millis(500));
+
+        assert!(server.try_wait().unwrap().is_none());
+        assert!(!watchdog.finish());
+        assert!(!stop_request_file(&sock_file).exists());
+        let _ = server.kill();
+        let _ = server.wait();
+        let _ = fs::remove_dir_all(dir);
+    }
+}